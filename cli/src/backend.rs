@@ -1,7 +1,7 @@
 use std::{
     any::Any,
     io::{Cursor, Read},
-    path::Path,
+    path::{Path, PathBuf},
     time::SystemTime,
 };
 
@@ -10,8 +10,9 @@ use futures::stream::{self, BoxStream};
 use jj_lib::{
     backend::{
         make_root_commit, Backend, BackendError, BackendInitError, BackendResult, ChangeId, Commit,
-        CommitId, Conflict, ConflictId, CopyRecord, FileId, MergedTreeId, MillisSinceEpoch,
-        SecureSig, Signature, SigningFn, SymlinkId, Timestamp, Tree, TreeId, TreeValue,
+        CommitId, Conflict, ConflictId, ConflictTerm, CopyRecord, FileId, MergedTreeId,
+        MillisSinceEpoch, SecureSig, Signature, SigningFn, SymlinkId, Timestamp, Tree, TreeId,
+        TreeValue,
     },
     index::Index,
     merge::MergeBuilder,
@@ -20,6 +21,7 @@ use jj_lib::{
     settings::UserSettings,
 };
 use prost::Message;
+use tonic::{metadata::MetadataValue, Code, Status};
 
 use crate::blocking_client::BlockingJujutsuInterfaceClient;
 
@@ -29,6 +31,8 @@ const CHANGE_ID_LENGTH: usize = 16;
 #[derive(Debug)]
 pub struct YakBackend {
     client: BlockingJujutsuInterfaceClient,
+    /// Where the repo was initialized, which is how the daemon knows it
+    repo_path: PathBuf,
     root_commit_id: CommitId,
     root_change_id: ChangeId,
     empty_tree_id: TreeId,
@@ -39,25 +43,59 @@ impl YakBackend {
         "yak"
     }
 
-    pub fn new(settings: &UserSettings, _store_path: &Path) -> Result<Self, BackendInitError> {
+    pub fn new(settings: &UserSettings, store_path: &Path) -> Result<Self, BackendInitError> {
         let root_commit_id = CommitId::from_bytes(&[0; COMMIT_ID_LENGTH]);
         let root_change_id = ChangeId::from_bytes(&[0; CHANGE_ID_LENGTH]);
-        let grpc_port = settings.get::<usize>("grpc_port").unwrap();
-
-        let client = crate::blocking_client::BlockingJujutsuInterfaceClient::connect(format!(
-            "http://[::1]:{grpc_port}"
-        ))
-        .unwrap();
-        let empty_tree_id =
-            TreeId::from_bytes(&client.get_empty_tree_id().unwrap().into_inner().tree_id);
+        let grpc_port = settings
+            .get::<usize>("grpc_port")
+            .map_err(|err| BackendInitError(err.into()))?;
+
+        let client = BlockingJujutsuInterfaceClient::connect_to_daemon(grpc_port)
+            .map_err(|err| BackendInitError(err.into()))?;
+        let empty_tree_id = client
+            .get_empty_tree_id()
+            .map_err(|status| BackendInitError(status.into()))?;
+        let empty_tree_id = TreeId::from_bytes(&empty_tree_id.into_inner().tree_id);
+        // The store is in `<repo>/.jj/repo/store`, also for workspaces added to the repo later.
+        let repo_path = store_path
+            .canonicalize()
+            .map_err(|err| BackendInitError(err.into()))?
+            .ancestors()
+            .nth(3)
+            .ok_or_else(|| {
+                BackendInitError(
+                    format!("Store at {} is not in a repo", store_path.display()).into(),
+                )
+            })?
+            .to_path_buf();
 
         Ok(YakBackend {
             client,
+            repo_path,
             root_commit_id,
             root_change_id,
             empty_tree_id,
         })
     }
+
+    /// The connection to the daemon, shared with the working copy.
+    pub fn client(&self) -> &BlockingJujutsuInterfaceClient {
+        &self.client
+    }
+
+    pub fn repo_path(&self) -> &Path {
+        &self.repo_path
+    }
+
+    /// Names the repo a store request is for, so the daemon uses the repo's store.
+    fn request<T>(&self, message: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        request.metadata_mut().insert_bin(
+            proto::REPO_PATH_METADATA,
+            MetadataValue::from_bytes(self.repo_path.as_os_str().as_encoded_bytes()),
+        );
+        request
+    }
 }
 
 #[async_trait]
@@ -97,10 +135,14 @@ impl Backend for YakBackend {
     async fn read_file(&self, _path: &RepoPath, id: &FileId) -> BackendResult<Box<dyn Read>> {
         let proto = self
             .client
-            .read_file(file_id_to_proto(id))
-            .unwrap()
+            .read_file(self.request(file_id_to_proto(id)))
+            .map_err(|status| read_error("file", id.hex(), status))?
             .into_inner();
-        Ok(file_from_proto(proto))
+        file_from_proto(proto).map_err(|err| BackendError::ReadObject {
+            object_type: "file".to_string(),
+            hash: id.hex(),
+            source: err.into(),
+        })
     }
 
     async fn write_file(
@@ -108,8 +150,14 @@ impl Backend for YakBackend {
         _path: &RepoPath,
         contents: &mut (dyn Read + Send),
     ) -> BackendResult<FileId> {
-        let proto = file_to_proto(contents);
-        let id = self.client.write_file(proto).unwrap();
+        let proto = file_to_proto(contents).map_err(|err| BackendError::WriteObject {
+            object_type: "file",
+            source: err.into(),
+        })?;
+        let id = self
+            .client
+            .write_file(self.request(proto))
+            .map_err(|status| write_error("file", status))?;
         let id = id.into_inner();
         Ok(FileId::new(id.file_id))
     }
@@ -117,15 +165,18 @@ impl Backend for YakBackend {
     async fn read_symlink(&self, _path: &RepoPath, id: &SymlinkId) -> BackendResult<String> {
         let proto = self
             .client
-            .read_symlink(symlink_id_to_proto(id))
-            .unwrap()
+            .read_symlink(self.request(symlink_id_to_proto(id)))
+            .map_err(|status| read_error("symlink", id.hex(), status))?
             .into_inner();
         Ok(symlink_from_proto(proto))
     }
 
     async fn write_symlink(&self, _path: &RepoPath, target: &str) -> BackendResult<SymlinkId> {
         let proto = symlink_to_proto(target);
-        let id = self.client.write_symlink(proto).unwrap();
+        let id = self
+            .client
+            .write_symlink(self.request(proto))
+            .map_err(|status| write_error("symlink", status))?;
         let id = id.into_inner();
         Ok(SymlinkId::new(id.symlink_id))
     }
@@ -134,26 +185,48 @@ impl Backend for YakBackend {
     async fn read_tree(&self, _path: &RepoPath, id: &TreeId) -> BackendResult<Tree> {
         let proto = self
             .client
-            .read_tree(tree_id_to_proto(id))
-            .unwrap()
+            .read_tree(self.request(tree_id_to_proto(id)))
+            .map_err(|status| read_error("tree", id.hex(), status))?
             .into_inner();
-        Ok(tree_from_proto(proto))
+        tree_from_proto(proto).map_err(|err| BackendError::ReadObject {
+            object_type: "tree".to_string(),
+            hash: id.hex(),
+            source: err.into(),
+        })
     }
 
     #[tracing::instrument]
     async fn write_tree(&self, _path: &RepoPath, tree: &Tree) -> BackendResult<TreeId> {
         let proto = tree_to_proto(tree);
-        let id = self.client.write_tree(proto).unwrap();
+        let id = self
+            .client
+            .write_tree(self.request(proto))
+            .map_err(|status| write_error("tree", status))?;
         let id = id.into_inner();
         Ok(TreeId::new(id.tree_id))
     }
 
-    fn read_conflict(&self, _path: &RepoPath, _id: &ConflictId) -> BackendResult<Conflict> {
-        todo!("Support conflict")
+    fn read_conflict(&self, _path: &RepoPath, id: &ConflictId) -> BackendResult<Conflict> {
+        let proto = self
+            .client
+            .read_conflict(self.request(conflict_id_to_proto(id)))
+            .map_err(|status| read_error("conflict", id.hex(), status))?
+            .into_inner();
+        conflict_from_proto(proto).map_err(|err| BackendError::ReadObject {
+            object_type: "conflict".to_string(),
+            hash: id.hex(),
+            source: err.into(),
+        })
     }
 
-    fn write_conflict(&self, _path: &RepoPath, _contents: &Conflict) -> BackendResult<ConflictId> {
-        todo!("Support conflict")
+    fn write_conflict(&self, _path: &RepoPath, contents: &Conflict) -> BackendResult<ConflictId> {
+        let proto = conflict_to_proto(contents);
+        let id = self
+            .client
+            .write_conflict(self.request(proto))
+            .map_err(|status| write_error("conflict", status))?;
+        let id = id.into_inner();
+        Ok(ConflictId::new(id.conflict_id))
     }
 
     #[tracing::instrument]
@@ -166,28 +239,37 @@ impl Backend for YakBackend {
         }
         let proto = self
             .client
-            .read_commit(commit_id_to_proto(id))
-            .unwrap()
+            .read_commit(self.request(commit_id_to_proto(id)))
+            .map_err(|status| read_error("commit", id.hex(), status))?
             .into_inner();
-        Ok(commit_from_proto(proto))
+        commit_from_proto(id, proto)
     }
 
     #[tracing::instrument(skip(sign_with))]
     async fn write_commit(
         &self,
-        commit: Commit,
+        mut commit: Commit,
         sign_with: Option<&mut SigningFn>,
     ) -> BackendResult<(CommitId, Commit)> {
-        assert!(commit.secure_sig.is_none(), "commit.secure_sig was set");
-        assert!(sign_with.is_none(), "sign_with was set");
-
         if commit.parents.is_empty() {
             return Err(BackendError::Other(
                 "Cannot write a commit with no parents".into(),
             ));
         }
-        let proto = commit_to_proto(&commit);
-        let id = self.client.write_commit(proto).unwrap();
+        let mut proto = commit_to_proto(&commit);
+        if let Some(sign) = sign_with {
+            // The signature covers the commit as `commit_from_proto` sees it, without the
+            // signature itself.
+            proto.secure_sig = None;
+            let data = proto.encode_to_vec();
+            let sig = sign(&data).map_err(|err| BackendError::Other(err.into()))?;
+            proto.secure_sig = Some(sig.clone());
+            commit.secure_sig = Some(SecureSig { data, sig });
+        }
+        let id = self
+            .client
+            .write_commit(self.request(proto))
+            .map_err(|status| write_error("commit", status))?;
         let id = id.into_inner();
         Ok((CommitId::new(id.commit_id), commit))
     }
@@ -202,11 +284,42 @@ impl Backend for YakBackend {
         _paths: Option<&[RepoPathBuf]>,
         _roots: &CommitId,
         _heads: &CommitId,
-    ) -> BackendResult<BoxStream<BackendResult<CopyRecord>>> {
+    ) -> BackendResult<BoxStream<'_, BackendResult<CopyRecord>>> {
         Ok(Box::pin(stream::empty()))
     }
 }
 
+/// Names the object a failed read was about, preferring what the daemon reported.
+pub(crate) fn read_error(object_type: &str, hash: String, status: Status) -> BackendError {
+    let (object_type, hash) = match proto::jj_interface::ObjectError::decode(status.details()) {
+        Ok(details) if !details.object_kind.is_empty() => (details.object_kind, details.id),
+        _ => (object_type.to_string(), hash),
+    };
+    match status.code() {
+        Code::NotFound => BackendError::ObjectNotFound {
+            object_type,
+            hash,
+            source: status.into(),
+        },
+        Code::Unavailable => BackendError::Other(status.into()),
+        _ => BackendError::ReadObject {
+            object_type,
+            hash,
+            source: status.into(),
+        },
+    }
+}
+
+fn write_error(object_type: &'static str, status: Status) -> BackendError {
+    match status.code() {
+        Code::Unavailable => BackendError::Other(status.into()),
+        _ => BackendError::WriteObject {
+            object_type,
+            source: status.into(),
+        },
+    }
+}
+
 pub fn file_id_to_proto(file_id: &FileId) -> proto::jj_interface::FileId {
     let mut proto = proto::jj_interface::FileId::default();
     proto.file_id = file_id.to_bytes();
@@ -231,6 +344,12 @@ pub fn symlink_id_to_proto(symlink_id: &SymlinkId) -> proto::jj_interface::Symli
     proto
 }
 
+pub fn conflict_id_to_proto(conflict_id: &ConflictId) -> proto::jj_interface::ConflictId {
+    let mut proto = proto::jj_interface::ConflictId::default();
+    proto.conflict_id = conflict_id.to_bytes();
+    proto
+}
+
 pub fn commit_to_proto(commit: &Commit) -> proto::jj_interface::Commit {
     let mut proto = proto::jj_interface::Commit::default();
     for parent in &commit.parents {
@@ -252,10 +371,14 @@ pub fn commit_to_proto(commit: &Commit) -> proto::jj_interface::Commit {
     proto.description = commit.description.clone();
     proto.author = Some(signature_to_proto(&commit.author));
     proto.committer = Some(signature_to_proto(&commit.committer));
+    proto.secure_sig = commit.secure_sig.as_ref().map(|sig| sig.sig.clone());
     proto
 }
 
-fn commit_from_proto(mut proto: proto::jj_interface::Commit) -> Commit {
+fn commit_from_proto(
+    id: &CommitId,
+    mut proto: proto::jj_interface::Commit,
+) -> BackendResult<Commit> {
     // Note how .take() sets the secure_sig field to None before we encode the data.
     // Needs to be done first since proto is partially moved a bunch below
     let secure_sig = proto.secure_sig.take().map(|sig| SecureSig {
@@ -265,15 +388,23 @@ fn commit_from_proto(mut proto: proto::jj_interface::Commit) -> Commit {
 
     let parents = proto.parents.into_iter().map(CommitId::new).collect();
     let predecessors = proto.predecessors.into_iter().map(CommitId::new).collect();
+    // Merges have an odd number of terms, legacy trees exactly one.
+    let tree_count = proto.root_tree.len();
+    if tree_count.is_multiple_of(2) || (!proto.uses_tree_conflict_format && tree_count != 1) {
+        return Err(BackendError::ReadObject {
+            object_type: "commit".to_string(),
+            hash: id.hex(),
+            source: format!("Commit has {tree_count} root trees").into(),
+        });
+    }
     let root_tree = if proto.uses_tree_conflict_format {
         let merge_builder: MergeBuilder<_> = proto.root_tree.into_iter().map(TreeId::new).collect();
         MergedTreeId::Merge(merge_builder.build())
     } else {
-        assert_eq!(proto.root_tree.len(), 1);
         MergedTreeId::Legacy(TreeId::new(proto.root_tree[0].to_vec()))
     };
     let change_id = ChangeId::new(proto.change_id);
-    Commit {
+    Ok(Commit {
         parents,
         predecessors,
         root_tree,
@@ -282,8 +413,9 @@ fn commit_from_proto(mut proto: proto::jj_interface::Commit) -> Commit {
         author: signature_from_proto(proto.author.unwrap_or_default()),
         committer: signature_from_proto(proto.committer.unwrap_or_default()),
         secure_sig,
-    }
+    })
 }
+
 fn signature_to_proto(signature: &Signature) -> proto::jj_interface::commit::Signature {
     proto::jj_interface::commit::Signature {
         name: signature.name.clone(),
@@ -307,12 +439,46 @@ fn signature_from_proto(proto: proto::jj_interface::commit::Signature) -> Signat
     }
 }
 
-fn file_to_proto(file: &mut dyn Read) -> proto::jj_interface::File {
+fn file_to_proto(file: &mut dyn Read) -> std::io::Result<proto::jj_interface::File> {
     let mut proto = proto::jj_interface::File::default();
     let mut out = vec![];
-    zstd::stream::copy_encode(file, &mut out, 0).unwrap();
+    zstd::stream::copy_encode(file, &mut out, 0)?;
     proto.data = out;
-    proto
+    Ok(proto)
+}
+
+fn conflict_to_proto(conflict: &Conflict) -> proto::jj_interface::Conflict {
+    let term_to_proto = |term: &ConflictTerm| proto::jj_interface::conflict::Term {
+        content: Some(tree_value_to_proto(&term.value)),
+    };
+    proto::jj_interface::Conflict {
+        removes: conflict.removes.iter().map(term_to_proto).collect(),
+        adds: conflict.adds.iter().map(term_to_proto).collect(),
+    }
+}
+
+fn conflict_from_proto(proto: proto::jj_interface::Conflict) -> Result<Conflict, MalformedObject> {
+    let term_from_proto =
+        |term: proto::jj_interface::conflict::Term| -> Result<_, MalformedObject> {
+            Ok(ConflictTerm {
+                value: term
+                    .content
+                    .ok_or_else(|| "Conflict term has no content".to_string())
+                    .and_then(tree_value_from_proto)?,
+            })
+        };
+    Ok(Conflict {
+        removes: proto
+            .removes
+            .into_iter()
+            .map(term_from_proto)
+            .collect::<Result<_, _>>()?,
+        adds: proto
+            .adds
+            .into_iter()
+            .map(term_from_proto)
+            .collect::<Result<_, _>>()?,
+    })
 }
 
 fn tree_to_proto(tree: &Tree) -> proto::jj_interface::Tree {
@@ -369,23 +535,37 @@ fn tree_value_to_proto(value: &TreeValue) -> proto::jj_interface::TreeValue {
     proto
 }
 
-fn file_from_proto(proto: proto::jj_interface::File) -> Box<dyn Read> {
+fn file_from_proto(proto: proto::jj_interface::File) -> std::io::Result<Box<dyn Read>> {
     let mut file = vec![];
-    zstd::stream::copy_decode(proto.data.as_slice(), &mut file).unwrap();
-    Box::new(Cursor::new(file))
+    zstd::stream::copy_decode(proto.data.as_slice(), &mut file)?;
+    Ok(Box::new(Cursor::new(file)))
 }
 
-fn tree_from_proto(proto: proto::jj_interface::Tree) -> Tree {
+/// Describes what is wrong with an object the daemon sent, for `BackendError::ReadObject`.
+type MalformedObject = String;
+
+fn tree_from_proto(proto: proto::jj_interface::Tree) -> Result<Tree, MalformedObject> {
     let mut tree = Tree::default();
     for proto_entry in proto.entries {
-        let value = tree_value_from_proto(proto_entry.value.unwrap());
+        if proto_entry.name.is_empty() || proto_entry.name.contains('/') {
+            return Err(format!("Invalid tree entry name {:?}", proto_entry.name));
+        }
+        let value = proto_entry
+            .value
+            .ok_or_else(|| format!("Tree entry {:?} has no value", proto_entry.name))
+            .and_then(tree_value_from_proto)?;
         tree.set(RepoPathComponentBuf::from(proto_entry.name), value);
     }
-    tree
+    Ok(tree)
 }
 
-fn tree_value_from_proto(proto: proto::jj_interface::TreeValue) -> TreeValue {
-    match proto.value.unwrap() {
+fn tree_value_from_proto(
+    proto: proto::jj_interface::TreeValue,
+) -> Result<TreeValue, MalformedObject> {
+    let value = proto
+        .value
+        .ok_or_else(|| "Tree value has no variant".to_string())?;
+    Ok(match value {
         proto::jj_interface::tree_value::Value::TreeId(id) => TreeValue::Tree(TreeId::new(id)),
         proto::jj_interface::tree_value::Value::File(proto::jj_interface::tree_value::File {
             id,
@@ -401,5 +581,5 @@ fn tree_value_from_proto(proto: proto::jj_interface::TreeValue) -> TreeValue {
         proto::jj_interface::tree_value::Value::ConflictId(id) => {
             TreeValue::Conflict(ConflictId::new(id))
         }
-    }
+    })
 }
@@ -16,7 +16,32 @@ pub struct BlockingJujutsuInterfaceClient {
     rt: Arc<Mutex<Runtime>>,
 }
 
+/// Nothing answered on the daemon's gRPC port.
+#[derive(Debug)]
+pub struct DaemonNotRunning {
+    pub port: usize,
+    source: tonic::transport::Error,
+}
+
+impl std::fmt::Display for DaemonNotRunning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "daemon not running on port {}", self.port)
+    }
+}
+
+impl std::error::Error for DaemonNotRunning {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
 impl BlockingJujutsuInterfaceClient {
+    /// Connects to the daemon's gRPC port on localhost.
+    pub fn connect_to_daemon(port: usize) -> Result<Self, DaemonNotRunning> {
+        Self::connect(format!("http://[::1]:{port}"))
+            .map_err(|source| DaemonNotRunning { port, source })
+    }
+
     pub fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
     where
         D: TryInto<tonic::transport::Endpoint>,
@@ -58,6 +83,24 @@ impl BlockingJujutsuInterfaceClient {
         rt.block_on(client.initialize(request))
     }
 
+    pub fn add_workspace(
+        &self,
+        request: impl tonic::IntoRequest<AddWorkspaceReq>,
+    ) -> Result<tonic::Response<AddWorkspaceReply>, tonic::Status> {
+        let mut client = self.client.lock().unwrap();
+        let rt = self.rt.lock().unwrap();
+        rt.block_on(client.add_workspace(request))
+    }
+
+    pub fn unmount(
+        &self,
+        request: impl tonic::IntoRequest<UnmountReq>,
+    ) -> Result<tonic::Response<UnmountReply>, tonic::Status> {
+        let mut client = self.client.lock().unwrap();
+        let rt = self.rt.lock().unwrap();
+        rt.block_on(client.unmount(request))
+    }
+
     pub fn set_checkout_state(
         &self,
         request: impl tonic::IntoRequest<SetCheckoutStateReq>,
@@ -76,6 +119,15 @@ impl BlockingJujutsuInterfaceClient {
         rt.block_on(client.get_checkout_state(request))
     }
 
+    pub fn get_new_files(
+        &self,
+        request: impl tonic::IntoRequest<GetNewFilesReq>,
+    ) -> Result<tonic::Response<GetNewFilesReply>, tonic::Status> {
+        let mut client = self.client.lock().unwrap();
+        let rt = self.rt.lock().unwrap();
+        rt.block_on(client.get_new_files(request))
+    }
+
     pub fn snapshot(
         &self,
         request: impl tonic::IntoRequest<SnapshotReq>,
@@ -85,6 +137,69 @@ impl BlockingJujutsuInterfaceClient {
         rt.block_on(client.snapshot(request))
     }
 
+    pub fn check_out(
+        &self,
+        request: impl tonic::IntoRequest<CheckOutReq>,
+    ) -> Result<tonic::Response<CheckOutReply>, tonic::Status> {
+        let mut client = self.client.lock().unwrap();
+        let rt = self.rt.lock().unwrap();
+        rt.block_on(client.check_out(request))
+    }
+
+    pub fn reset(
+        &self,
+        request: impl tonic::IntoRequest<ResetReq>,
+    ) -> Result<tonic::Response<ResetReply>, tonic::Status> {
+        let mut client = self.client.lock().unwrap();
+        let rt = self.rt.lock().unwrap();
+        rt.block_on(client.reset(request))
+    }
+
+    pub fn recover(
+        &self,
+        request: impl tonic::IntoRequest<ResetReq>,
+    ) -> Result<tonic::Response<ResetReply>, tonic::Status> {
+        let mut client = self.client.lock().unwrap();
+        let rt = self.rt.lock().unwrap();
+        rt.block_on(client.recover(request))
+    }
+
+    /// Waits for the lock on a working copy. It is held until it is released or the returned
+    /// stream is dropped.
+    pub fn acquire_lock(
+        &self,
+        request: impl tonic::IntoRequest<AcquireLockReq>,
+    ) -> Result<(AcquireLockReply, tonic::Streaming<AcquireLockReply>), tonic::Status> {
+        let mut client = self.client.lock().unwrap();
+        let rt = self.rt.lock().unwrap();
+        rt.block_on(async {
+            let mut stream = client.acquire_lock(request).await?.into_inner();
+            let lease = stream
+                .message()
+                .await?
+                .ok_or_else(|| tonic::Status::unavailable("The daemon did not grant the lock"))?;
+            Ok((lease, stream))
+        })
+    }
+
+    pub fn release_lock(
+        &self,
+        request: impl tonic::IntoRequest<ReleaseLockReq>,
+    ) -> Result<tonic::Response<ReleaseLockReply>, tonic::Status> {
+        let mut client = self.client.lock().unwrap();
+        let rt = self.rt.lock().unwrap();
+        rt.block_on(client.release_lock(request))
+    }
+
+    pub fn set_sparse_patterns(
+        &self,
+        request: impl tonic::IntoRequest<SetSparsePatternsReq>,
+    ) -> Result<tonic::Response<CheckOutReply>, tonic::Status> {
+        let mut client = self.client.lock().unwrap();
+        let rt = self.rt.lock().unwrap();
+        rt.block_on(client.set_sparse_patterns(request))
+    }
+
     pub fn write_commit(
         &self,
         request: impl tonic::IntoRequest<Commit>,
@@ -121,6 +236,24 @@ impl BlockingJujutsuInterfaceClient {
         rt.block_on(client.read_file(request))
     }
 
+    pub fn write_conflict(
+        &self,
+        request: impl tonic::IntoRequest<Conflict>,
+    ) -> Result<tonic::Response<ConflictId>, tonic::Status> {
+        let mut client = self.client.lock().unwrap();
+        let rt = self.rt.lock().unwrap();
+        rt.block_on(client.write_conflict(request))
+    }
+
+    pub fn read_conflict(
+        &self,
+        request: impl tonic::IntoRequest<ConflictId>,
+    ) -> Result<tonic::Response<Conflict>, tonic::Status> {
+        let mut client = self.client.lock().unwrap();
+        let rt = self.rt.lock().unwrap();
+        rt.block_on(client.read_conflict(request))
+    }
+
     pub fn write_tree(
         &self,
         request: impl tonic::IntoRequest<Tree>,
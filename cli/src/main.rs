@@ -1,24 +1,33 @@
 #![deny(warnings)]
 
+use std::path::Path;
+
 use jj_cli::{
     cli_util::{CliRunner, CommandHelper},
-    command_error::{cli_error, user_error_with_message, CommandError},
+    command_error::{
+        cli_error, internal_error_with_message, user_error, user_error_with_message, CommandError,
+    },
     ui::Ui,
 };
 use jj_lib::{
+    backend::BackendLoadError,
     file_util,
     op_store::WorkspaceId,
     repo::{ReadonlyRepo, StoreFactories},
+    settings::ConfigResultExt,
     signing::Signer,
     workspace::{WorkingCopyFactories, Workspace, WorkspaceInitError},
 };
 
 mod backend;
 mod blocking_client;
+mod mount;
+mod templater;
 mod working_copy;
 
 use backend::YakBackend;
-use jj_lib::{local_working_copy::LocalWorkingCopyFactory, working_copy::WorkingCopyFactory};
+use blocking_client::BlockingJujutsuInterfaceClient;
+use templater::YakTemplates;
 use working_copy::{YakWorkingCopy, YakWorkingCopyFactory};
 
 /// Create a new repo in the given directory
@@ -35,10 +44,26 @@ pub(crate) struct InitArgs {
     destination: String,
 }
 
+/// Stop serving a working copy and forget about it
+///
+/// The daemon removes what it kept for the working copy, so working copies with
+/// changes that were not snapshotted are only unmounted with --force.
+#[derive(clap::Args, Clone, Debug)]
+pub(crate) struct UnmountArgs {
+    /// The working copy
+    #[arg(value_hint = clap::ValueHint::DirPath)]
+    path: String,
+
+    /// Unmount even if changes that were not snapshotted are lost
+    #[arg(long)]
+    force: bool,
+}
+
 #[derive(Debug, Clone, clap::Subcommand)]
 enum YakCommands {
     Init(InitArgs),
     Status,
+    Unmount(UnmountArgs),
 }
 
 #[derive(Debug, Clone, clap::Args)]
@@ -62,16 +87,14 @@ fn create_store_factories() -> StoreFactories {
     store_factories.add_backend(
         "yak",
         Box::new(|settings, store_path| {
-            Ok(Box::new(YakBackend::new(settings, store_path).unwrap()))
+            let backend =
+                YakBackend::new(settings, store_path).map_err(|err| BackendLoadError(err.0))?;
+            Ok(Box::new(backend))
         }),
     );
     store_factories
 }
 
-pub fn default_working_copy_factory() -> Box<dyn WorkingCopyFactory> {
-    Box::new(LocalWorkingCopyFactory {})
-}
-
 fn run_yak_command(
     ui: &mut Ui,
     command_helper: &CommandHelper,
@@ -79,21 +102,50 @@ fn run_yak_command(
 ) -> Result<(), CommandError> {
     let YakSubcommand::Yak(YakArgs { command }) = command;
 
-    let grpc_port = command_helper.settings().get::<usize>("grpc_port").unwrap();
-    let client = crate::blocking_client::BlockingJujutsuInterfaceClient::connect(format!(
-        "http://[::1]:{grpc_port}"
-    ))
-    .unwrap();
+    let grpc_port = command_helper.settings().get::<usize>("grpc_port")?;
+    let client =
+        BlockingJujutsuInterfaceClient::connect_to_daemon(grpc_port).map_err(user_error)?;
     match command {
         YakCommands::Status => {
             let resp = client
                 .daemon_status(proto::jj_interface::DaemonStatusReq {})
-                .unwrap();
+                .map_err(|status| {
+                    internal_error_with_message("Failed to get daemon status", status)
+                })?;
+            let resp = resp.into_inner();
             ui.request_pager();
             let mut formatter = ui.stdout_formatter();
-            for session in resp.into_inner().data {
+            for session in resp.data {
                 writeln!(formatter, "{} - {}", session.path, session.remote)?;
             }
+            for unrestored in resp.unrestored {
+                writeln!(
+                    ui.warning_default(),
+                    "Could not restore {}: {}",
+                    unrestored.path,
+                    unrestored.error
+                )?;
+            }
+            if resp.offline {
+                writeln!(
+                    ui.status(),
+                    "Daemon is offline, changes will be uploaded once the remote is reachable"
+                )?;
+            }
+            if resp.pending_uploads > 0 {
+                writeln!(
+                    ui.status(),
+                    "{} objects waiting to be uploaded to the remote",
+                    resp.pending_uploads
+                )?;
+            }
+            if resp.rejected_uploads > 0 {
+                writeln!(
+                    ui.warning_default(),
+                    "{} objects could not be uploaded to the remote, see the daemon log",
+                    resp.rejected_uploads
+                )?;
+            }
             Ok(())
         }
         YakCommands::Init(args) => {
@@ -112,12 +164,34 @@ fn run_yak_command(
             // NOTE: We need to tell the daemon to mount the filesystem BEFORE we
             // initalize the core jj internals or we'll have writes on-disk and on
             // vfs.
-            client
+            let reply = client
                 .initialize(proto::jj_interface::InitializeReq {
                     remote: args.remote,
                     path: wc_path.as_os_str().to_str().unwrap().to_string(),
                 })
-                .unwrap();
+                .map_err(|status| {
+                    user_error_with_message(
+                        "Failed to initialize repo",
+                        status.message().to_string(),
+                    )
+                })?
+                .into_inner();
+            if should_mount(command_helper)? {
+                if let Err(err) = mount::mount(reply.nfs_port, &wc_path) {
+                    // Let the daemon forget the repo, so that init can be retried.
+                    if let Err(status) = client.unmount(proto::jj_interface::UnmountReq {
+                        working_copy_path: wc_path.as_os_str().to_str().unwrap().to_string(),
+                        force: true,
+                    }) {
+                        writeln!(
+                            ui.warning_default(),
+                            "Could not stop serving the repo: {}",
+                            status.message()
+                        )?;
+                    }
+                    return Err(user_error_with_message("Failed to mount the repo", err));
+                }
+            }
 
             Workspace::init_with_factories(
                 command_helper.settings(),
@@ -132,8 +206,7 @@ fn run_yak_command(
                 ReadonlyRepo::default_op_heads_store_initializer(),
                 ReadonlyRepo::default_index_store_initializer(),
                 ReadonlyRepo::default_submodule_store_initializer(),
-                //&YakWorkingCopyFactory {},
-                &*default_working_copy_factory(),
+                &YakWorkingCopyFactory {},
                 WorkspaceId::default(),
             )?;
 
@@ -146,9 +219,76 @@ fn run_yak_command(
 
             Ok(())
         }
+        YakCommands::Unmount(args) => {
+            let cwd = command_helper.cwd();
+            let wc_path = cwd
+                .join(&args.path)
+                .canonicalize()
+                .map_err(|e| user_error_with_message("Failed to find working copy", e))?;
+            let mounted = should_mount(command_helper)?;
+            // Unmounting first sends the writes the system still holds on to the daemon.
+            if mounted {
+                mount::unmount(&wc_path)
+                    .map_err(|e| user_error_with_message("Failed to unmount", e))?;
+            }
+            let unmounted = client.unmount(proto::jj_interface::UnmountReq {
+                working_copy_path: wc_path.as_os_str().to_str().unwrap().to_string(),
+                force: args.force,
+            });
+            if let Err(status) = unmounted {
+                // The daemon still serves the working copy.
+                if mounted {
+                    if let Err(err) = remount(&client, &wc_path) {
+                        writeln!(
+                            ui.warning_default(),
+                            "Could not mount the working copy again: {err}"
+                        )?;
+                    }
+                }
+                let err =
+                    user_error_with_message("Failed to unmount", status.message().to_string());
+                return Err(if status.code() == tonic::Code::FailedPrecondition {
+                    err.hinted(
+                        "Snapshot the changes, e.g. with `jj status`, or pass --force to drop them",
+                    )
+                } else {
+                    err
+                });
+            }
+
+            let relative_wc_path = file_util::relative_path(cwd, &wc_path);
+            writeln!(ui.status(), "Unmounted \"{}\"", relative_wc_path.display())?;
+            Ok(())
+        }
     }
 }
 
+/// Mounts the working copy at `path` again, on the port the daemon serves it on.
+fn remount(client: &BlockingJujutsuInterfaceClient, path: &Path) -> std::io::Result<()> {
+    let status = client
+        .daemon_status(proto::jj_interface::DaemonStatusReq {})
+        .map_err(std::io::Error::other)?
+        .into_inner();
+    let nfs_port = status
+        .data
+        .iter()
+        .flat_map(|session| &session.workspaces)
+        .find(|workspace| Path::new(&workspace.path) == path)
+        .map(|workspace| workspace.nfs_port)
+        .ok_or_else(|| std::io::Error::other("The daemon no longer serves it"))?;
+    mount::mount(nfs_port, path)
+}
+
+/// Whether working copies are mounted, which `yak.mount = false` turns off on machines
+/// without an NFS client.
+fn should_mount(command_helper: &CommandHelper) -> Result<bool, CommandError> {
+    Ok(command_helper
+        .settings()
+        .get_bool("yak.mount")
+        .optional()?
+        .unwrap_or(true))
+}
+
 fn main() -> std::process::ExitCode {
     let mut working_copy_factories = WorkingCopyFactories::new();
     working_copy_factories.insert(
@@ -160,6 +300,7 @@ fn main() -> std::process::ExitCode {
     CliRunner::init()
         .add_store_factories(create_store_factories())
         .add_working_copy_factories(working_copy_factories)
+        .add_commit_template_extension(Box::new(YakTemplates))
         .add_subcommand(run_yak_command)
         .run()
 }
@@ -0,0 +1,50 @@
+//! Mounting the NFS exports the daemon serves working copies from.
+
+use std::{io, path::Path, process::Command};
+
+/// Mounts the export the daemon serves on `port` at `path`.
+pub fn mount(port: u32, path: &Path) -> io::Result<()> {
+    let port = u16::try_from(port)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid port {port}")))?;
+    #[cfg(target_os = "macos")]
+    let mut command = {
+        let mut command = Command::new("/sbin/mount_nfs");
+        command.args([
+            "-o",
+            &format!("nolocks,vers=3,tcp,port={port},mountport={port}"),
+        ]);
+        command
+    };
+    #[cfg(not(target_os = "macos"))]
+    let mut command = {
+        let mut command = Command::new("mount");
+        command.args([
+            "-t",
+            "nfs",
+            "-o",
+            &format!("nolock,vers=3,tcp,port={port},mountport={port}"),
+        ]);
+        command
+    };
+    // The daemon serves working copies on the IPv4 loopback address only.
+    run(command.arg("127.0.0.1:/").arg(path))
+}
+
+/// Unmounts the export mounted at `path`.
+pub fn unmount(path: &Path) -> io::Result<()> {
+    run(Command::new("umount").arg(path))
+}
+
+fn run(command: &mut Command) -> io::Result<()> {
+    let output = command.output()?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(io::Error::other(format!(
+        "{} failed with {}: {}",
+        command.get_program().to_string_lossy(),
+        output.status,
+        stderr.trim()
+    )))
+}
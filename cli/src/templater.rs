@@ -0,0 +1,41 @@
+//! Template methods for yak repos, on top of jj's own.
+
+use jj_cli::{
+    commit_templater::{
+        CommitTemplateBuildFnTable, CommitTemplateLanguage, CommitTemplateLanguageExtension,
+    },
+    template_builder::TemplateLanguage,
+    templater::TemplatePropertyExt as _,
+};
+use jj_lib::{extensions_map::ExtensionsMap, signing::SigStatus};
+
+pub struct YakTemplates;
+
+impl CommitTemplateLanguageExtension for YakTemplates {
+    fn build_fn_table<'repo>(&self) -> CommitTemplateBuildFnTable<'repo> {
+        type L<'repo> = CommitTemplateLanguage<'repo>;
+        let mut table = CommitTemplateBuildFnTable::empty();
+        // jj itself can't show signatures yet. Empty for unsigned commits, otherwise "good",
+        // "bad" or "unknown" (when the key isn't among the allowed signers).
+        table.commit_methods.insert(
+            "signature_status",
+            |_language, _diagnostics, _build_context, property, call| {
+                call.expect_no_arguments()?;
+                Ok(L::wrap_string(property.and_then(|commit| {
+                    let status = match commit.verification()? {
+                        None => "",
+                        Some(verification) => match verification.status {
+                            SigStatus::Good => "good",
+                            SigStatus::Bad => "bad",
+                            SigStatus::Unknown => "unknown",
+                        },
+                    };
+                    Ok(status.to_string())
+                })))
+            },
+        );
+        table
+    }
+
+    fn build_cache_extensions(&self, _extensions: &mut ExtensionsMap) {}
+}
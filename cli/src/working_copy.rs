@@ -1,22 +1,37 @@
-use std::{any::Any, cell::OnceCell, path::PathBuf, sync::Arc};
+use std::{
+    any::Any,
+    cell::OnceCell,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use jj_lib::{
     backend::{MergedTreeId, TreeId},
     commit::Commit,
+    gitignore::{GitIgnoreError, GitIgnoreFile},
     merge::MergeBuilder,
     object_id::ObjectId,
     op_store::{OperationId, WorkspaceId},
     repo_path::RepoPathBuf,
+    settings::HumanByteSize,
     store::Store,
     working_copy::{
         CheckoutError, CheckoutOptions, CheckoutStats, LockedWorkingCopy, ResetError,
         SnapshotError, SnapshotOptions, WorkingCopy, WorkingCopyFactory, WorkingCopyStateError,
     },
 };
-use proto::jj_interface::{GetCheckoutStateReq, GetTreeStateReq, SnapshotReq};
+use proto::jj_interface::{
+    AcquireLockReply, AcquireLockReq, AddWorkspaceReq, CheckOutReply, CheckOutReq,
+    GetCheckoutStateReq, GetNewFilesReq, GetTreeStateReq, ReleaseLockReq, ResetReq,
+    SetSparsePatternsReq, SnapshotReq,
+};
 use tracing::{info, warn};
 
-use crate::blocking_client::BlockingJujutsuInterfaceClient;
+use crate::{
+    backend::{read_error, YakBackend},
+    blocking_client::BlockingJujutsuInterfaceClient,
+};
 
 pub struct YakWorkingCopyFactory {}
 
@@ -43,7 +58,7 @@ impl WorkingCopyFactory for YakWorkingCopyFactory {
         working_copy_path: PathBuf,
         _state_path: PathBuf,
     ) -> Result<Box<dyn WorkingCopy + 'static>, WorkingCopyStateError> {
-        Ok(Box::new(YakWorkingCopy::load(store, working_copy_path)))
+        Ok(Box::new(YakWorkingCopy::load(store, working_copy_path)?))
     }
 }
 
@@ -51,8 +66,8 @@ pub struct YakWorkingCopy {
     store: Arc<Store>,
     working_copy_path: PathBuf,
     client: BlockingJujutsuInterfaceClient,
-    /// Only access through get_checkout_state
-    checkout_state: OnceCell<CheckoutState>,
+    checkout_state: CheckoutState,
+    /// Only access through get_tree_state
     tree_state: OnceCell<TreeState>,
 }
 
@@ -61,13 +76,51 @@ impl YakWorkingCopy {
         "yak"
     }
 
+    /// The daemon is reached through the connection the yak backend already has.
+    fn backend(store: &Store) -> Result<&YakBackend, WorkingCopyStateError> {
+        store
+            .backend_impl()
+            .downcast_ref::<YakBackend>()
+            .ok_or_else(|| WorkingCopyStateError {
+                message: "The yak working copy only works with the yak backend".to_string(),
+                err: "The repo is not backed by the yak daemon".into(),
+            })
+    }
+
     fn init(
         store: Arc<Store>,
         working_copy_path: PathBuf,
         operation_id: OperationId,
         workspace_id: WorkspaceId,
     ) -> Result<Self, WorkingCopyStateError> {
-        let client = BlockingJujutsuInterfaceClient::connect("http://[::1]:10000").unwrap();
+        // The daemon knows working copies by their canonical path, while `jj workspace add`
+        // passes the destination as it was given.
+        let working_copy_path =
+            working_copy_path
+                .canonicalize()
+                .map_err(|err| WorkingCopyStateError {
+                    message: format!("Failed to resolve {}", working_copy_path.display()),
+                    err: err.into(),
+                })?;
+        let backend = Self::backend(&store)?;
+        let client = backend.client().clone();
+        // The daemon set up the repo's first workspace when it was initialized.
+        if working_copy_path != backend.repo_path() {
+            client
+                .add_workspace(AddWorkspaceReq {
+                    repo_path: backend.repo_path().to_str().unwrap().to_string(),
+                    working_copy_path: working_copy_path.to_str().unwrap().to_string(),
+                    workspace_id: workspace_id.as_str().to_string(),
+                })
+                .map_err(|status| WorkingCopyStateError {
+                    message: "Failed to add the workspace in the daemon".to_string(),
+                    err: status.into(),
+                })?;
+        }
+        let lock = DaemonLock::acquire(
+            client.clone(),
+            working_copy_path.to_str().unwrap().to_string(),
+        )?;
         client
             .set_checkout_state(proto::jj_interface::SetCheckoutStateReq {
                 working_copy_path: working_copy_path.to_str().unwrap().to_string(),
@@ -75,26 +128,61 @@ impl YakWorkingCopy {
                     op_id: operation_id.as_bytes().into(),
                     workspace_id: workspace_id.as_str().into(),
                 }),
+                lease_id: lock.lease_id,
             })
-            .unwrap();
+            .map_err(|status| WorkingCopyStateError {
+                message: "Failed to record the working copy in the daemon".to_string(),
+                err: status.into(),
+            })?;
         Ok(YakWorkingCopy {
             store,
             working_copy_path,
             client,
-            checkout_state: OnceCell::new(),
+            checkout_state: CheckoutState {
+                operation_id,
+                workspace_id,
+            },
             tree_state: OnceCell::new(),
         })
     }
 
-    fn load(store: Arc<Store>, working_copy_path: PathBuf) -> Self {
-        let client = BlockingJujutsuInterfaceClient::connect("http://[::1]:10000").unwrap();
-        YakWorkingCopy {
+    fn load(store: Arc<Store>, working_copy_path: PathBuf) -> Result<Self, WorkingCopyStateError> {
+        let client = Self::backend(&store)?.client().clone();
+        let checkout_state = Self::fetch_checkout_state(&client, &working_copy_path)?;
+        Ok(YakWorkingCopy {
             store,
             working_copy_path,
             client,
-            checkout_state: OnceCell::new(),
+            checkout_state,
             tree_state: OnceCell::new(),
-        }
+        })
+    }
+
+    /// The operation and workspace id are needed without a way to report errors, so they are
+    /// fetched up front.
+    fn fetch_checkout_state(
+        client: &BlockingJujutsuInterfaceClient,
+        working_copy_path: &Path,
+    ) -> Result<CheckoutState, WorkingCopyStateError> {
+        let checkout_state = client
+            .get_checkout_state(GetCheckoutStateReq {
+                working_copy_path: working_copy_path.to_str().unwrap().to_string(),
+            })
+            .map_err(|status| WorkingCopyStateError {
+                message: "Failed to get the checkout state from the daemon".to_string(),
+                err: status.into(),
+            })?
+            .into_inner();
+        let workspace_id = String::from_utf8(checkout_state.workspace_id).map_err(|err| {
+            WorkingCopyStateError {
+                message: "The daemon sent an invalid workspace id".to_string(),
+                err: err.into(),
+            }
+        })?;
+        Ok(CheckoutState {
+            operation_id: OperationId::new(checkout_state.op_id),
+            workspace_id: WorkspaceId::new(workspace_id),
+        })
     }
 }
 
@@ -108,6 +196,7 @@ struct CheckoutState {
 #[derive(Clone, Debug)]
 struct TreeState {
     tree_id: MergedTreeId,
+    sparse_patterns: Vec<RepoPathBuf>,
 }
 impl TreeState {
     pub fn current_tree_id(&self) -> &MergedTreeId {
@@ -116,70 +205,202 @@ impl TreeState {
 }
 
 impl YakWorkingCopy {
-    fn get_tree_state<'a>(&'a self) -> &'a TreeState {
-        self.tree_state.get_or_init(|| {
-            let tree_state = self
-                .client
-                .get_tree_state(GetTreeStateReq {
-                    working_copy_path: self.working_copy_path.to_str().unwrap().to_string(),
-                })
-                .unwrap()
-                .into_inner();
-            let tree_ids_builder: MergeBuilder<TreeId> =
-                MergeBuilder::from_iter([TreeId::new(tree_state.tree_id)]);
-            TreeState {
-                tree_id: MergedTreeId::Merge(tree_ids_builder.build()),
-            }
-        })
+    fn get_tree_state(&self) -> Result<&TreeState, WorkingCopyStateError> {
+        if let Some(tree_state) = self.tree_state.get() {
+            return Ok(tree_state);
+        }
+        let tree_state = self
+            .client
+            .get_tree_state(GetTreeStateReq {
+                working_copy_path: self.working_copy_path.to_str().unwrap().to_string(),
+            })
+            .map_err(|status| WorkingCopyStateError {
+                message: "Failed to get the tree state from the daemon".to_string(),
+                err: status.into(),
+            })?
+            .into_inner();
+        let tree_ids_builder: MergeBuilder<TreeId> =
+            MergeBuilder::from_iter([TreeId::new(tree_state.tree_id)]);
+        let tree_state = TreeState {
+            tree_id: MergedTreeId::Merge(tree_ids_builder.build()),
+            sparse_patterns: tree_state
+                .sparse_patterns
+                .iter()
+                .map(RepoPathBuf::from_internal_string)
+                .collect(),
+        };
+        Ok(self.tree_state.get_or_init(|| tree_state))
     }
 
-    fn get_checkout_state<'a>(&'a self) -> &'a CheckoutState {
-        self.checkout_state.get_or_init(|| {
-            let checkout_state = self
-                .client
-                .get_checkout_state(GetCheckoutStateReq {
-                    working_copy_path: self.working_copy_path.to_str().unwrap().to_string(),
-                })
-                .unwrap()
-                .into_inner();
-            CheckoutState {
-                operation_id: OperationId::new(checkout_state.op_id),
-                workspace_id: WorkspaceId::new(
-                    std::str::from_utf8(&checkout_state.workspace_id)
-                        .unwrap()
-                        .to_string(),
-                ),
-            }
-        })
+    /// Keeps the patterns of the cached tree state, if there is one, while switching trees.
+    fn set_tree_id(&mut self, tree_id: MergedTreeId) {
+        if let Some(tree_state) = self.tree_state.get_mut() {
+            tree_state.tree_id = tree_id;
+        }
     }
 
-    fn get_working_copy_lock(&self) -> DaemonLock {
-        DaemonLock::new()
+    fn get_working_copy_lock(&self) -> Result<DaemonLock, WorkingCopyStateError> {
+        DaemonLock::acquire(
+            self.client.clone(),
+            self.working_copy_path.to_str().unwrap().to_string(),
+        )
     }
 
-    fn snapshot(&mut self, _options: &SnapshotOptions) -> TreeState {
+    /// The daemon writes the snapshot, but which new files get tracked is decided here, where
+    /// the ignore rules are known. `lease_id` is the lease holding the lock on the working copy.
+    fn snapshot(
+        &mut self,
+        options: &SnapshotOptions,
+        lease_id: u64,
+    ) -> Result<MergedTreeId, SnapshotError> {
+        let working_copy_path = self.working_copy_path.to_str().unwrap().to_string();
+        let new_files = self
+            .client
+            .get_new_files(GetNewFilesReq {
+                working_copy_path: working_copy_path.clone(),
+            })
+            .map_err(|status| SnapshotError::Other {
+                message: "Failed to get the new files from the daemon".to_string(),
+                err: status.into(),
+            })?
+            .into_inner();
+        let mut git_ignores = GitIgnores {
+            base: options.base_ignores.clone(),
+            files: new_files
+                .gitignores
+                .into_iter()
+                .map(|gitignore| (gitignore.dir, gitignore.contents))
+                .collect(),
+            chained: HashMap::new(),
+        };
+        let mut untracked_paths = vec![];
+        for file in new_files.new_files {
+            let path = RepoPathBuf::from_relative_path(&file.path).map_err(|err| {
+                SnapshotError::Other {
+                    message: format!("The daemon sent an invalid path {:?}", file.path),
+                    err: err.into(),
+                }
+            })?;
+            let dir = path
+                .parent()
+                .map_or("", |dir| dir.as_internal_file_string());
+            let git_ignore = git_ignores.in_dir(dir)?;
+            if git_ignore.matches(&file.path) || !options.start_tracking_matcher.matches(&path) {
+                untracked_paths.push(file.path);
+            } else if file.size > options.max_new_file_size {
+                return Err(SnapshotError::NewFileTooLarge {
+                    path: self.working_copy_path.join(&file.path),
+                    size: HumanByteSize(file.size),
+                    max_size: HumanByteSize(options.max_new_file_size),
+                });
+            }
+        }
+
         let tree_state = self
             .client
             .snapshot(SnapshotReq {
-                working_copy_path: self.working_copy_path.to_str().unwrap().to_string(),
+                working_copy_path,
+                untracked_paths,
+                lease_id,
             })
-            .unwrap()
+            .map_err(|status| SnapshotError::Other {
+                message: "Failed to snapshot the working copy in the daemon".to_string(),
+                err: status.into(),
+            })?
             .into_inner();
         let tree_ids_builder: MergeBuilder<TreeId> =
             MergeBuilder::from_iter([TreeId::new(tree_state.tree_id)]);
-        TreeState {
-            tree_id: MergedTreeId::Merge(tree_ids_builder.build()),
+        Ok(MergedTreeId::Merge(tree_ids_builder.build()))
+    }
+}
+
+/// Ignore rules of the directories in a working copy, from the base ignores and the contents of
+/// the `.gitignore` files the daemon sent.
+struct GitIgnores {
+    base: Arc<GitIgnoreFile>,
+    /// `.gitignore` contents by directory, the empty path stands for the root
+    files: HashMap<String, Vec<u8>>,
+    /// Rules already built for a directory
+    chained: HashMap<String, Arc<GitIgnoreFile>>,
+}
+
+impl GitIgnores {
+    /// The rules in `dir`, with the `.gitignore` files of it and the directories above it.
+    fn in_dir(&mut self, dir: &str) -> Result<Arc<GitIgnoreFile>, GitIgnoreError> {
+        if let Some(git_ignore) = self.chained.get(dir) {
+            return Ok(git_ignore.clone());
         }
+        let (mut git_ignore, prefix) = match dir.rsplit_once('/') {
+            _ if dir.is_empty() => (self.base.clone(), String::new()),
+            Some((parent, _)) => (self.in_dir(parent)?, format!("{dir}/")),
+            None => (self.in_dir("")?, format!("{dir}/")),
+        };
+        if let Some(contents) = self.files.get(dir) {
+            git_ignore = git_ignore.chain(&prefix, contents)?;
+        }
+        self.chained.insert(dir.to_string(), git_ignore.clone());
+        Ok(git_ignore)
+    }
+}
+
+/// The daemon only knows about single trees, not merges of them.
+fn resolved_tree_id(tree_id: &MergedTreeId) -> Option<&TreeId> {
+    match tree_id {
+        MergedTreeId::Legacy(tree_id) => Some(tree_id),
+        MergedTreeId::Merge(tree_ids) => tree_ids.as_resolved(),
+    }
+}
+
+fn checkout_stats(stats: CheckOutReply) -> CheckoutStats {
+    CheckoutStats {
+        updated_files: stats.updated_files,
+        added_files: stats.added_files,
+        removed_files: stats.removed_files,
+        skipped_files: stats.skipped_files,
     }
 }
 
 /// Distributed lock. The daemon hold the lock since all work
-/// is done in it.
-struct DaemonLock {}
+/// is done in it. It is released when dropped, and by the daemon if this process goes away
+/// while holding it.
+struct DaemonLock {
+    client: BlockingJujutsuInterfaceClient,
+    working_copy_path: String,
+    lease_id: u64,
+    /// The lock is held for as long as this is open
+    _stream: tonic::Streaming<AcquireLockReply>,
+}
+
 impl DaemonLock {
-    pub fn new() -> Self {
-        warn!("DaemonLock is unimplemented. No locking currently done.");
-        DaemonLock {}
+    fn acquire(
+        client: BlockingJujutsuInterfaceClient,
+        working_copy_path: String,
+    ) -> Result<Self, WorkingCopyStateError> {
+        let (lease, stream) = client
+            .acquire_lock(AcquireLockReq {
+                working_copy_path: working_copy_path.clone(),
+            })
+            .map_err(|status| WorkingCopyStateError {
+                message: "Failed to lock the working copy".to_string(),
+                err: status.into(),
+            })?;
+        Ok(DaemonLock {
+            client,
+            working_copy_path,
+            lease_id: lease.lease_id,
+            _stream: stream,
+        })
+    }
+}
+
+impl Drop for DaemonLock {
+    fn drop(&mut self) {
+        if let Err(status) = self.client.release_lock(ReleaseLockReq {
+            working_copy_path: self.working_copy_path.clone(),
+            lease_id: self.lease_id,
+        }) {
+            warn!("Failed to release the working copy lock: {status}");
+        }
     }
 }
 
@@ -193,29 +414,30 @@ impl WorkingCopy for YakWorkingCopy {
     }
 
     fn workspace_id(&self) -> &WorkspaceId {
-        &self.get_checkout_state().workspace_id
+        &self.checkout_state.workspace_id
     }
 
     fn operation_id(&self) -> &OperationId {
-        &self.get_checkout_state().operation_id
+        &self.checkout_state.operation_id
     }
 
     fn tree_id(&self) -> Result<&MergedTreeId, WorkingCopyStateError> {
-        Ok(self.get_tree_state().current_tree_id())
+        Ok(self.get_tree_state()?.current_tree_id())
     }
 
     fn sparse_patterns(&self) -> Result<&[RepoPathBuf], WorkingCopyStateError> {
-        todo!()
+        Ok(&self.get_tree_state()?.sparse_patterns)
     }
 
     fn start_mutation(&self) -> Result<Box<dyn LockedWorkingCopy>, WorkingCopyStateError> {
         info!("Starting mutation");
-        let lock = self.get_working_copy_lock();
+        let lock = self.get_working_copy_lock()?;
         let wc = YakWorkingCopy {
             client: self.client.clone(),
             store: self.store.clone(),
             working_copy_path: self.working_copy_path.clone(),
-            checkout_state: OnceCell::new(),
+            // Another process may have changed the state before we got the lock.
+            checkout_state: Self::fetch_checkout_state(&self.client, &self.working_copy_path)?,
             tree_state: OnceCell::new(),
         };
         let old_operation_id = wc.operation_id().clone();
@@ -231,12 +453,47 @@ impl WorkingCopy for YakWorkingCopy {
 
 struct LockedYakWorkingCopy {
     wc: YakWorkingCopy,
-    #[allow(dead_code)]
     lock: DaemonLock,
     old_operation_id: OperationId,
     old_tree_id: MergedTreeId,
 }
 
+impl LockedYakWorkingCopy {
+    /// Records the commit's tree for the working copy without changing what it shows.
+    fn reset_to(&mut self, commit: &Commit, recover: bool) -> Result<(), ResetError> {
+        let Some(tree_id) = resolved_tree_id(commit.tree_id()) else {
+            return Err(ResetError::Other {
+                message: "The yak working copy can't reset to conflicted trees".to_string(),
+                err: format!("Cannot reset to {}", commit.id().hex()).into(),
+            });
+        };
+        let req = ResetReq {
+            working_copy_path: self.wc.working_copy_path.to_str().unwrap().to_string(),
+            tree_id: tree_id.to_bytes(),
+            lease_id: self.lock.lease_id,
+        };
+        if recover {
+            self.wc.client.recover(req)
+        } else {
+            self.wc.client.reset(req)
+        }
+        .map_err(|status| match status.code() {
+            tonic::Code::NotFound => ResetError::SourceNotFound {
+                source: status.into(),
+            },
+            tonic::Code::DataLoss | tonic::Code::Unavailable => {
+                ResetError::InternalBackendError(read_error("tree", tree_id.hex(), status))
+            }
+            _ => ResetError::Other {
+                message: "Failed to reset the working copy in the daemon".to_string(),
+                err: status.into(),
+            },
+        })?;
+        self.wc.set_tree_id(commit.tree_id().clone());
+        Ok(())
+    }
+}
+
 impl LockedWorkingCopy for LockedYakWorkingCopy {
     fn as_any(&self) -> &dyn Any {
         self
@@ -254,13 +511,12 @@ impl LockedWorkingCopy for LockedYakWorkingCopy {
         &self.old_tree_id
     }
 
-    fn recover(&mut self, _commit: &Commit) -> Result<(), ResetError> {
-        todo!()
+    fn recover(&mut self, commit: &Commit) -> Result<(), ResetError> {
+        self.reset_to(commit, true)
     }
 
     fn snapshot(&mut self, options: &SnapshotOptions) -> Result<MergedTreeId, SnapshotError> {
-        let tree_state = self.wc.snapshot(options);
-        Ok(tree_state.tree_id)
+        self.wc.snapshot(options, self.lock.lease_id)
     }
 
     fn check_out(
@@ -268,28 +524,84 @@ impl LockedWorkingCopy for LockedYakWorkingCopy {
         commit: &Commit,
         _options: &CheckoutOptions,
     ) -> Result<CheckoutStats, CheckoutError> {
-        let _new_tree = commit.tree()?;
-        todo!()
+        let (Some(old_tree_id), Some(new_tree_id)) = (
+            resolved_tree_id(&self.old_tree_id),
+            resolved_tree_id(commit.tree_id()),
+        ) else {
+            return Err(CheckoutError::Other {
+                message: "The yak working copy can't check out conflicted trees".to_string(),
+                err: format!("Cannot check out {}", commit.id().hex()).into(),
+            });
+        };
+        let stats = self
+            .wc
+            .client
+            .check_out(CheckOutReq {
+                working_copy_path: self.wc.working_copy_path.to_str().unwrap().to_string(),
+                old_tree_id: old_tree_id.to_bytes(),
+                new_tree_id: new_tree_id.to_bytes(),
+                lease_id: self.lock.lease_id,
+            })
+            .map_err(|status| match status.code() {
+                tonic::Code::FailedPrecondition => CheckoutError::ConcurrentCheckout,
+                tonic::Code::NotFound => CheckoutError::SourceNotFound {
+                    source: status.into(),
+                },
+                tonic::Code::DataLoss | tonic::Code::Unavailable => {
+                    CheckoutError::InternalBackendError(read_error(
+                        "tree",
+                        new_tree_id.hex(),
+                        status,
+                    ))
+                }
+                _ => CheckoutError::Other {
+                    message: "Failed to check out in the daemon".to_string(),
+                    err: status.into(),
+                },
+            })?
+            .into_inner();
+        self.wc.set_tree_id(commit.tree_id().clone());
+        Ok(checkout_stats(stats))
     }
 
-    fn rename_workspace(&mut self, _new_workspace_id: WorkspaceId) {
-        todo!()
+    fn rename_workspace(&mut self, new_workspace_id: WorkspaceId) {
+        // The daemon learns about the new name when the mutation finishes.
+        self.wc.checkout_state.workspace_id = new_workspace_id;
     }
 
-    fn reset(&mut self, _commit: &Commit) -> Result<(), ResetError> {
-        todo!()
+    fn reset(&mut self, commit: &Commit) -> Result<(), ResetError> {
+        self.reset_to(commit, false)
     }
 
     fn sparse_patterns(&self) -> Result<&[RepoPathBuf], WorkingCopyStateError> {
-        todo!()
+        self.wc.sparse_patterns()
     }
 
     fn set_sparse_patterns(
         &mut self,
-        _new_sparse_patterns: Vec<RepoPathBuf>,
+        new_sparse_patterns: Vec<RepoPathBuf>,
         _options: &CheckoutOptions,
     ) -> Result<CheckoutStats, CheckoutError> {
-        todo!()
+        let stats = self
+            .wc
+            .client
+            .set_sparse_patterns(SetSparsePatternsReq {
+                working_copy_path: self.wc.working_copy_path.to_str().unwrap().to_string(),
+                sparse_patterns: new_sparse_patterns
+                    .iter()
+                    .map(|pattern| pattern.as_internal_file_string().to_string())
+                    .collect(),
+                lease_id: self.lock.lease_id,
+            })
+            .map_err(|status| CheckoutError::Other {
+                message: "Failed to set the sparse patterns in the daemon".to_string(),
+                err: status.into(),
+            })?
+            .into_inner();
+        if let Some(tree_state) = self.wc.tree_state.get_mut() {
+            tree_state.sparse_patterns = new_sparse_patterns;
+        }
+        Ok(checkout_stats(stats))
     }
 
     fn finish(
@@ -297,6 +609,31 @@ impl LockedWorkingCopy for LockedYakWorkingCopy {
         operation_id: OperationId,
     ) -> Result<Box<dyn WorkingCopy>, WorkingCopyStateError> {
         info!("Finished: {operation_id:?}");
-        Ok(Box::new(self.wc))
+        let LockedYakWorkingCopy { wc, lock, .. } = *self;
+        wc.client
+            .set_checkout_state(proto::jj_interface::SetCheckoutStateReq {
+                working_copy_path: wc.working_copy_path.to_str().unwrap().to_string(),
+                checkout_state: Some(proto::jj_interface::CheckoutState {
+                    op_id: operation_id.as_bytes().into(),
+                    workspace_id: wc.workspace_id().as_str().into(),
+                }),
+                lease_id: lock.lease_id,
+            })
+            .map_err(|status| WorkingCopyStateError {
+                message: "Failed to record the operation in the daemon".to_string(),
+                err: status.into(),
+            })?;
+        drop(lock);
+        // The cached tree state is out of date now.
+        Ok(Box::new(YakWorkingCopy {
+            store: wc.store,
+            working_copy_path: wc.working_copy_path,
+            client: wc.client,
+            checkout_state: CheckoutState {
+                operation_id,
+                workspace_id: wc.checkout_state.workspace_id,
+            },
+            tree_state: OnceCell::new(),
+        }))
     }
 }
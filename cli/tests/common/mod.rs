@@ -83,6 +83,15 @@ impl Default for TestEnvironment {
         let daemon_child = command
             .spawn()
             .expect("Failed to start daemon for integration test");
+        // Commands fail if they run before the daemon is listening.
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while std::net::TcpStream::connect(("::1", daemon_port as u16)).is_err() {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "Daemon did not start listening on port {daemon_port}"
+            );
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
 
         let env_vars = HashMap::new();
         let env = Self {
@@ -101,6 +110,8 @@ impl Default for TestEnvironment {
             daemon_dir,
         };
         env.add_config(format!(r#"grpc_port = {daemon_port}"#).as_str());
+        // Mounting needs an NFS client and permission to mount, tests that mount turn it on.
+        env.add_config("yak.mount = false");
         // Use absolute timestamps in the operation log to make tests independent of the
         // current time.
         env.add_config(
@@ -1,3 +1,5 @@
 mod common;
 
+mod test_daemon_errors;
 mod test_init;
+mod test_signing;
@@ -0,0 +1,32 @@
+use crate::common::TestEnvironment;
+
+#[test]
+fn test_daemon_not_running() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_ok(test_env.env_root(), &["yak", "init", "localhost", "repo"]);
+    let repo_path = test_env.env_root().join("repo");
+
+    // Nothing listens on port 1.
+    test_env.add_config("grpc_port = 1");
+
+    let stderr = test_env.jj_cmd_internal_error(&repo_path, &["log"]);
+    insta::assert_snapshot!(stderr, @r"
+    Internal error: The repository appears broken or inaccessible
+    Caused by:
+    1: daemon not running on port 1
+    2: transport error
+    3: error trying to connect: tcp connect error: Connection refused (os error 111)
+    4: tcp connect error: Connection refused (os error 111)
+    5: Connection refused (os error 111)
+    ");
+
+    let stderr = test_env.jj_cmd_failure(&repo_path, &["yak", "status"]);
+    insta::assert_snapshot!(stderr, @r"
+    Error: daemon not running on port 1
+    Caused by:
+    1: transport error
+    2: error trying to connect: tcp connect error: Connection refused (os error 111)
+    3: tcp connect error: Connection refused (os error 111)
+    4: Connection refused (os error 111)
+    ");
+}
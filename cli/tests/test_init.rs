@@ -17,6 +17,59 @@ fn test_init() {
     ");
 }
 
+#[test]
+fn test_init_uses_yak_working_copy() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_ok(test_env.env_root(), &["yak", "init", "localhost", "repo"]);
+    let repo_path = test_env.env_root().join("repo");
+
+    let working_copy_type =
+        std::fs::read_to_string(repo_path.join(".jj/working_copy/type")).unwrap();
+    assert_eq!(working_copy_type, "yak");
+
+    // The working copy's state lives in the daemon, and survives commands that update it.
+    test_env.jj_cmd_ok(&repo_path, &["new"]);
+    let stdout = test_env.jj_cmd_success(
+        &repo_path,
+        &["op", "log", "--no-graph", "-T", "description ++ \"\\n\""],
+    );
+    insta::assert_snapshot!(stdout, @r"
+    new empty commit
+    add workspace 'default'
+    ");
+    let stdout = test_env.jj_cmd_success(&repo_path, &["workspace", "list"]);
+    insta::assert_snapshot!(stdout, @"default: rlvkpnrz 256ffb0d (empty) (no description set)");
+}
+
+#[test]
+fn test_check_out_commits() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_ok(test_env.env_root(), &["yak", "init", "localhost", "repo"]);
+    let repo_path = test_env.env_root().join("repo");
+
+    test_env.jj_cmd_ok(&repo_path, &["new", "-m", "second"]);
+    let (_, stderr) = test_env.jj_cmd_ok(&repo_path, &["edit", "@-"]);
+    insta::assert_snapshot!(stderr, @r"
+    Working copy now at: qpvuntsm b4e46adb (empty) (no description set)
+    Parent commit      : zzzzzzzz 00000000 (empty) (no description set)
+    ");
+    let (_, stderr) = test_env.jj_cmd_ok(&repo_path, &["abandon", "@"]);
+    insta::assert_snapshot!(stderr, @r"
+    Abandoned commit qpvuntsm b4e46adb (empty) (no description set)
+    Rebased 1 descendant commits onto parents of abandoned commits
+    Working copy now at: zsuskuln 4f7a4801 (empty) (no description set)
+    Parent commit      : zzzzzzzz 00000000 (empty) (no description set)
+    ");
+    let stdout = test_env.jj_cmd_success(&repo_path, &["log"]);
+    insta::assert_snapshot!(stdout, @r"
+    @  zsuskuln test.user@example.com 2001-02-03 08:05:10 4f7a4801
+    │  (empty) (no description set)
+    │ ○  rlvkpnrz test.user@example.com 2001-02-03 08:05:10 c89b52b2
+    ├─╯  (empty) second
+    ◆  zzzzzzzz root() 00000000
+    ");
+}
+
 #[test]
 fn test_multiple_init() {
     let test_env = TestEnvironment::default();
@@ -98,3 +151,150 @@ fn test_repos_are_independent() {
     $TEST_ENV/repo2 - localhost
     ");
 }
+
+#[test]
+fn test_sparse_patterns() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_ok(test_env.env_root(), &["yak", "init", "localhost", "repo"]);
+    let repo_path = test_env.env_root().join("repo");
+
+    let stdout = test_env.jj_cmd_success(&repo_path, &["sparse", "list"]);
+    insta::assert_snapshot!(stdout, @".");
+    let (_, stderr) = test_env.jj_cmd_ok(
+        &repo_path,
+        &[
+            "sparse", "set", "--clear", "--add", "src", "--add", "docs/api",
+        ],
+    );
+    insta::assert_snapshot!(stderr, @"");
+    let stdout = test_env.jj_cmd_success(&repo_path, &["sparse", "list"]);
+    insta::assert_snapshot!(stdout, @r"
+    docs/api
+    src
+    ");
+}
+
+#[test]
+fn test_workspaces() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_ok(test_env.env_root(), &["yak", "init", "localhost", "repo"]);
+    let repo_path = test_env.env_root().join("repo");
+
+    let (_, stderr) = test_env.jj_cmd_ok(&repo_path, &["workspace", "add", "../second"]);
+    insta::assert_snapshot!(stderr, @r#"
+    Created workspace in "../second"
+    Working copy now at: uuqppmxq 769ed2a8 (empty) (no description set)
+    Parent commit      : zzzzzzzz 00000000 (empty) (no description set)
+    "#);
+    let second_path = test_env.env_root().join("second");
+    let working_copy_type =
+        std::fs::read_to_string(second_path.join(".jj/working_copy/type")).unwrap();
+    assert_eq!(working_copy_type, "yak");
+
+    test_env.jj_cmd_ok(&second_path, &["workspace", "rename", "renamed"]);
+    let stdout = test_env.jj_cmd_success(&second_path, &["workspace", "list"]);
+    insta::assert_snapshot!(stdout, @r"
+    default: qpvuntsm b4e46adb (empty) (no description set)
+    renamed: uuqppmxq 769ed2a8 (empty) (no description set)
+    ");
+    // Each workspace has its own working copy.
+    test_env.jj_cmd_ok(&second_path, &["new", "-m", "in second"]);
+    let stdout = test_env.jj_cmd_success(&repo_path, &["log"]);
+    insta::assert_snapshot!(stdout, @r"
+    @  qpvuntsm test.user@example.com 2001-02-03 08:05:07 default@ b4e46adb
+    │  (empty) (no description set)
+    │ ○  mzvwutvl test.user@example.com 2001-02-03 08:05:11 renamed@ ba030a93
+    │ │  (empty) in second
+    │ ○  uuqppmxq test.user@example.com 2001-02-03 08:05:08 769ed2a8
+    ├─╯  (empty) (no description set)
+    ◆  zzzzzzzz root() 00000000
+    ");
+
+    test_env.jj_cmd_ok(&repo_path, &["workspace", "forget", "renamed"]);
+    let stdout = test_env.jj_cmd_success(&repo_path, &["workspace", "list"]);
+    insta::assert_snapshot!(stdout, @"default: qpvuntsm b4e46adb (empty) (no description set)");
+}
+
+#[test]
+fn test_unmount() {
+    let test_env = TestEnvironment::default();
+    test_env.jj_cmd_ok(test_env.env_root(), &["yak", "init", "localhost", "repo1"]);
+    test_env.jj_cmd_ok(test_env.env_root(), &["yak", "init", "localhost", "repo2"]);
+
+    let (stdout, stderr) = test_env.jj_cmd_ok(test_env.env_root(), &["yak", "unmount", "repo1"]);
+    insta::assert_snapshot!(stdout, @"");
+    insta::assert_snapshot!(stderr, @r#"Unmounted "repo1""#);
+    let stdout = test_env.jj_cmd_success(test_env.env_root(), &["yak", "status"]);
+    insta::assert_snapshot!(stdout, @"$TEST_ENV/repo2 - localhost");
+
+    let stderr = test_env.jj_cmd_failure(test_env.env_root(), &["yak", "unmount", "repo1"]);
+    insta::assert_snapshot!(stderr, @r"
+    Error: Failed to unmount
+    Caused by: No working copy at $TEST_ENV/repo1
+    ");
+
+    // The path can hold a repo again.
+    std::fs::remove_dir_all(test_env.env_root().join("repo1")).unwrap();
+    test_env.jj_cmd_ok(test_env.env_root(), &["yak", "init", "localhost", "repo1"]);
+    let stdout = test_env.jj_cmd_success(test_env.env_root(), &["yak", "status"]);
+    insta::assert_snapshot!(stdout, @r"
+    $TEST_ENV/repo2 - localhost
+    $TEST_ENV/repo1 - localhost
+    ");
+}
+
+#[test]
+fn test_init_from_remote_repos() {
+    let test_env = TestEnvironment::default();
+    // Nothing listens on the remote, so the repo works offline.
+    test_env.jj_cmd_ok(
+        test_env.env_root(),
+        &["yak", "init", "bwb@127.0.0.1:1/repo", "repo"],
+    );
+    let repo_path = test_env.env_root().join("repo");
+    test_env.jj_cmd_ok(&repo_path, &["commit", "-m", "first"]);
+    let stdout = test_env.jj_cmd_success(&repo_path, &["log", "-T", "description"]);
+    insta::assert_snapshot!(stdout, @r"
+    @
+    ○  first
+    ◆
+    ");
+
+    let stderr = test_env.jj_cmd_failure(
+        test_env.env_root(),
+        &["yak", "init", "not a remote", "other"],
+    );
+    insta::assert_snapshot!(stderr, @r#"
+    Error: Failed to initialize repo
+    Caused by: Remote "not a remote" does not name a host, and the daemon has no remote_addr
+    "#);
+    let stderr = test_env.jj_cmd_failure(
+        test_env.env_root(),
+        &["yak", "init", "thelastyak.com/.hidden", "other"],
+    );
+    insta::assert_snapshot!(stderr, @r#"
+    Error: Failed to initialize repo
+    Caused by: Invalid repo name in remote "thelastyak.com/.hidden"
+    "#);
+}
+
+#[test]
+#[ignore = "needs an NFS client and permission to mount"]
+fn test_init_mounts_the_working_copy() {
+    let test_env = TestEnvironment::default();
+    test_env.add_config("yak.mount = true");
+    test_env.jj_cmd_ok(test_env.env_root(), &["yak", "init", "localhost", "repo"]);
+    let repo_path = test_env.env_root().join("repo");
+
+    // Files written through the mount land in the daemon's overlay, which snapshots read.
+    std::fs::create_dir(repo_path.join("dir")).unwrap();
+    std::fs::write(repo_path.join("dir/file"), "contents\n").unwrap();
+    let stdout = test_env.jj_cmd_success(&repo_path, &["diff", "--summary"]);
+    insta::assert_snapshot!(stdout, @"A dir/file");
+    let stdout = test_env.jj_cmd_success(&repo_path, &["file", "show", "dir/file"]);
+    insta::assert_snapshot!(stdout, @"contents");
+
+    // Nothing was written to the disk under the mount.
+    test_env.jj_cmd_ok(test_env.env_root(), &["yak", "unmount", "repo"]);
+    assert_eq!(std::fs::read_dir(&repo_path).unwrap().count(), 0);
+}
@@ -0,0 +1,46 @@
+use crate::common::TestEnvironment;
+
+// jj signs with ssh keys by running `ssh-keygen`, so this test needs it installed.
+#[test]
+fn test_sign_all() {
+    let test_env = TestEnvironment::default();
+    let key_path = test_env.env_root().join("signing_key");
+    let status = std::process::Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+        .arg(&key_path)
+        .status()
+        .expect("Failed to run ssh-keygen, which signing tests need");
+    assert!(status.success());
+    let public_key = std::fs::read_to_string(key_path.with_extension("pub")).unwrap();
+    let allowed_signers_path = test_env.env_root().join("allowed_signers");
+    std::fs::write(&allowed_signers_path, format!("test {public_key}")).unwrap();
+    test_env.add_config(&format!(
+        r#"
+[signing]
+sign-all = true
+backend = "ssh"
+key = "{}"
+backends.ssh.allowed-signers = "{}"
+"#,
+        key_path.to_str().unwrap(),
+        allowed_signers_path.to_str().unwrap()
+    ));
+
+    test_env.jj_cmd_ok(test_env.env_root(), &["yak", "init", "localhost", "repo"]);
+    let repo_path = test_env.env_root().join("repo");
+    test_env.jj_cmd_ok(&repo_path, &["describe", "-m", "signed"]);
+
+    // The key is generated per run, so the signed commit's id isn't stable.
+    let stdout = test_env.jj_cmd_success(
+        &repo_path,
+        &[
+            "log",
+            "-T",
+            r#"separate(" ", change_id.short(), signature_status, description)"#,
+        ],
+    );
+    insta::assert_snapshot!(stdout, @r"
+    @  qpvuntsmwlqt good signed
+    ◆  zzzzzzzzzzzz
+    ");
+}
@@ -0,0 +1,151 @@
+//! Working copy locks, held by clients through leases.
+
+use std::{collections::HashMap, sync::Arc};
+
+use parking_lot::Mutex;
+use tokio::sync::OwnedMutexGuard;
+
+/// Hands out leases on one lock per working copy.
+#[derive(Clone, Default)]
+pub struct Leases {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    /// Locks by working copy path
+    locks: HashMap<String, Arc<tokio::sync::Mutex<()>>>,
+    /// Leases that have not been released yet, by id
+    held: HashMap<u64, Held>,
+}
+
+struct Held {
+    path: String,
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl Leases {
+    /// Waits until the lock on the working copy at `path` is free and takes it. The lock is
+    /// held until the lease is released or dropped.
+    pub async fn acquire(&self, path: &str) -> Lease {
+        let lock = self
+            .inner
+            .lock()
+            .locks
+            .entry(path.to_string())
+            .or_default()
+            .clone();
+        let guard = lock.lock_owned().await;
+        let mut inner = self.inner.lock();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        inner.held.insert(
+            id,
+            Held {
+                path: path.to_string(),
+                _guard: guard,
+            },
+        );
+        Lease {
+            leases: self.clone(),
+            id,
+        }
+    }
+
+    /// Whether lease `id` holds the lock on `path`.
+    pub fn holds(&self, path: &str, id: u64) -> bool {
+        self.inner
+            .lock()
+            .held
+            .get(&id)
+            .is_some_and(|held| held.path == path)
+    }
+
+    /// Frees the lock held by lease `id`. Returns false if that lease does not hold the lock on
+    /// `path`, e.g. because it was released already.
+    pub fn release(&self, path: &str, id: u64) -> bool {
+        let held = {
+            let mut inner = self.inner.lock();
+            match inner.held.get(&id) {
+                Some(held) if held.path == path => inner.held.remove(&id),
+                _ => None,
+            }
+        };
+        held.is_some()
+    }
+}
+
+/// A lease on a working copy lock. Dropping it releases the lock, so a lease that is tied to a
+/// client connection expires with it.
+pub struct Lease {
+    leases: Leases,
+    id: u64,
+}
+
+impl Lease {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let held = self.leases.inner.lock().held.remove(&self.id);
+        drop(held);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    async fn is_free(leases: &Leases, path: &str) -> bool {
+        tokio::time::timeout(Duration::from_millis(50), leases.acquire(path))
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn one_lease_per_working_copy() {
+        let leases = Leases::default();
+        let lease = leases.acquire("/repo").await;
+        assert!(!is_free(&leases, "/repo").await);
+        assert!(is_free(&leases, "/other").await);
+
+        assert!(leases.holds("/repo", lease.id()));
+        assert!(!leases.holds("/other", lease.id()));
+        assert!(!leases.holds("/repo", lease.id() + 1));
+
+        // Only the holder of the lease can release it.
+        assert!(!leases.release("/other", lease.id()));
+        assert!(!leases.release("/repo", lease.id() + 1));
+        assert!(leases.release("/repo", lease.id()));
+        assert!(!leases.release("/repo", lease.id()));
+        assert!(!leases.holds("/repo", lease.id()));
+        assert!(is_free(&leases, "/repo").await);
+
+        // Dropping a released lease leaves newer leases alone.
+        let next = leases.acquire("/repo").await;
+        drop(lease);
+        assert!(!is_free(&leases, "/repo").await);
+        drop(next);
+        assert!(is_free(&leases, "/repo").await);
+    }
+
+    #[tokio::test]
+    async fn waiters_get_the_lock_in_turn() {
+        let leases = Leases::default();
+        let lease = leases.acquire("/repo").await;
+        let waiter = tokio::spawn({
+            let leases = leases.clone();
+            async move { leases.acquire("/repo").await.id() }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+        drop(lease);
+        assert_eq!(waiter.await.unwrap(), 2);
+    }
+}
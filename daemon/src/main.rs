@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc};
 
 use anyhow::anyhow;
 use serde::Deserialize;
@@ -6,13 +6,21 @@ use tonic::transport::Server as GrpcServer;
 use tracing::info;
 
 mod hash;
+mod lease;
+mod object_store;
+mod remote;
 mod service;
 mod store;
 mod ty;
 mod vfs;
 mod vfs_mgr;
+mod working_copy;
 
 use clap::Parser;
+use object_store::{
+    DiskObjectStore, InMemoryObjectStore, ObjectStore, RemoteObjectStore, WriteBackObjectStore,
+};
+use remote::{RemoteStores, Remotes};
 use vfs_mgr::*;
 
 /// JJ Daemon
@@ -28,18 +36,89 @@ struct Args {
 struct Config {
     /// Address the jj CLI connects over
     pub grpc_addr: String,
+    /// Address of the remote backend, `host[:port]`. Remotes that leave out the host are on it,
+    /// remotes that leave out the port use its port, and remote stores use it unless they give
+    /// their own.
+    pub remote_addr: Option<String>,
     /// local cache
     pub cache: PathBuf,
     /// NFS configuration
     pub nfs: NfsConfig,
+    /// Where objects are stored
+    #[serde(default)]
+    pub store: StoreConfig,
 }
 
 #[derive(Deserialize, Debug)]
 struct NfsConfig {
     /// Minimum of the port range an NFS mount can be served over
-    pub min_port: usize,
-    /// Maximum of the port range an NFS mount can be served over
-    pub max_port: usize,
+    pub min_port: u16,
+    /// Maximum of the port range an NFS mount can be served over, inclusive
+    pub max_port: u16,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum StoreConfig {
+    /// Keep objects in memory, losing them on restart
+    Memory,
+    /// Keep objects on local disk under `cache`
+    #[default]
+    Disk,
+    /// Keep objects in a remote backend, caching them on local disk under `cache`. Writes are
+    /// uploaded in the background.
+    Remote {
+        /// Address of the remote backend, `remote_addr` if left out
+        addr: Option<String>,
+        /// Repository on the remote backend to store objects in
+        repo: String,
+    },
+}
+
+/// Works out how remotes are resolved from `remote_addr`.
+fn remotes(config: &Config) -> Result<Remotes, anyhow::Error> {
+    let Some(addr) = &config.remote_addr else {
+        return Ok(Remotes::default());
+    };
+    let default_port = remote::port(addr)
+        .map_err(|e| anyhow!("Invalid remote_addr: {}", e))?
+        .unwrap_or(remote::DEFAULT_PORT);
+    Ok(Remotes {
+        default_port,
+        default_addr: Some(remote::parse_addr(addr, default_port)?),
+    })
+}
+
+/// Objects of repos from `localhost` are kept in this store.
+async fn open_object_store(
+    config: &Config,
+    remotes: &Remotes,
+) -> Result<Arc<dyn ObjectStore>, anyhow::Error> {
+    let open_disk = || async {
+        let path = config.cache.join("store");
+        DiskObjectStore::new(&path)
+            .await
+            .map_err(|e| anyhow!("Could not open store in {}: {}", path.display(), e))
+    };
+    Ok(match &config.store {
+        StoreConfig::Memory => Arc::new(InMemoryObjectStore::default()),
+        StoreConfig::Disk => Arc::new(open_disk().await?),
+        StoreConfig::Remote { addr, repo } => {
+            let addr = addr
+                .as_ref()
+                .or(config.remote_addr.as_ref())
+                .ok_or_else(|| anyhow!("The remote store needs an addr, or a remote_addr"))?;
+            let addr = remote::parse_addr(addr, remotes.default_port)?;
+            let remote = Arc::new(RemoteObjectStore::new(&addr, repo)?);
+            let queue = config.cache.join("upload_queue");
+            let store = WriteBackObjectStore::new(open_disk().await?, remote, &queue)
+                .await
+                .map_err(|e| {
+                    anyhow!("Could not open upload queue in {}: {}", queue.display(), e)
+                })?;
+            Arc::new(store)
+        }
+    })
 }
 
 async fn run_with_config(config: Config) -> Result<(), anyhow::Error> {
@@ -47,12 +126,37 @@ async fn run_with_config(config: Config) -> Result<(), anyhow::Error> {
 
     let addr = config.grpc_addr.parse()?;
 
+    let ports_path = config.cache.join("nfs_ports.toml");
     let mut vfs_mgr = VfsManager::new(VfsManagerConfig {
         min_nfs_port: config.nfs.min_port,
         max_nfs_port: config.nfs.max_port,
-    });
-
-    let jj_svc = service::JujutsuService::new();
+        ports_path: ports_path.clone(),
+    })
+    .await
+    .map_err(|e| {
+        anyhow!(
+            "Could not load NFS ports from {}: {}",
+            ports_path.display(),
+            e
+        )
+    })?;
+
+    // Restoring the working copies served before a restart binds them, so the manager has to be
+    // running first.
+    let vfs_mgr_handle = vfs_mgr.handle();
+    let nfs_fut = tokio::spawn(async move { vfs_mgr.serve().await });
+
+    let remotes = remotes(&config)?;
+    let store = store::Store::new(open_object_store(&config, &remotes).await?).await?;
+    let remote_stores = RemoteStores::new(config.cache.join("remotes")).await;
+    let jj_svc = service::JujutsuService::new(
+        store,
+        config.cache.clone(),
+        vfs_mgr_handle,
+        remotes,
+        remote_stores,
+    )
+    .await;
 
     let reflection_svc = tonic_reflection::server::Builder::configure()
         .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
@@ -64,7 +168,6 @@ async fn run_with_config(config: Config) -> Result<(), anyhow::Error> {
         .add_service(jj_svc)
         .serve(addr);
 
-    let nfs_fut = vfs_mgr.serve();
     tokio::select! {
         ret = nfs_fut => {
             panic!("NFS: {:?}", ret );
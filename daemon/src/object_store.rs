@@ -0,0 +1,150 @@
+//! Pluggable storage for the encoded objects behind [`Store`](crate::store::Store).
+//!
+//! An [`ObjectStore`] only deals in opaque, content-addressed blobs. Encoding, decoding and
+//! hashing stay in `Store`, so every implementation sees exactly the same bytes.
+
+use std::{fmt::Debug, io};
+
+use async_trait::async_trait;
+
+use crate::ty::Id;
+
+mod disk;
+mod memory;
+mod remote;
+mod write_back;
+
+pub use disk::DiskObjectStore;
+pub use memory::InMemoryObjectStore;
+pub use remote::RemoteObjectStore;
+pub use write_back::WriteBackObjectStore;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ObjectKind {
+    Commit,
+    File,
+    Symlink,
+    Tree,
+    Conflict,
+}
+
+impl ObjectKind {
+    pub const ALL: [ObjectKind; 5] = [
+        ObjectKind::Commit,
+        ObjectKind::File,
+        ObjectKind::Symlink,
+        ObjectKind::Tree,
+        ObjectKind::Conflict,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ObjectKind::Commit => "commit",
+            ObjectKind::File => "file",
+            ObjectKind::Symlink => "symlink",
+            ObjectKind::Tree => "tree",
+            ObjectKind::Conflict => "conflict",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ObjectKind> {
+        ObjectKind::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    pub fn as_proto(&self) -> proto::remote::ObjectKind {
+        match self {
+            ObjectKind::Commit => proto::remote::ObjectKind::Commit,
+            ObjectKind::File => proto::remote::ObjectKind::File,
+            ObjectKind::Symlink => proto::remote::ObjectKind::Symlink,
+            ObjectKind::Tree => proto::remote::ObjectKind::Tree,
+            ObjectKind::Conflict => proto::remote::ObjectKind::Conflict,
+        }
+    }
+}
+
+/// How far a store is from being in sync with its backing storage.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SyncStatus {
+    /// Objects acknowledged locally that have not reached the remote yet
+    pub pending_uploads: u64,
+    /// Objects the remote refused, which are no longer retried
+    pub rejected_uploads: u64,
+    /// The remote could not be reached the last time we tried
+    pub offline: bool,
+}
+
+impl SyncStatus {
+    /// The status of two stores together.
+    pub fn merge(self, other: SyncStatus) -> SyncStatus {
+        SyncStatus {
+            pending_uploads: self.pending_uploads + other.pending_uploads,
+            rejected_uploads: self.rejected_uploads + other.rejected_uploads,
+            offline: self.offline || other.offline,
+        }
+    }
+}
+
+/// Typed get/put/has/list over encoded objects.
+///
+/// Implementations must make `put` durable before returning: once a write is acknowledged a
+/// later `get` has to return it, even across daemon restarts for persistent stores.
+#[async_trait]
+pub trait ObjectStore: Debug + Send + Sync {
+    async fn get(&self, kind: ObjectKind, id: Id) -> io::Result<Option<Vec<u8>>>;
+
+    async fn put(&self, kind: ObjectKind, id: Id, data: Vec<u8>) -> io::Result<()>;
+
+    async fn has(&self, kind: ObjectKind, id: Id) -> io::Result<bool>;
+
+    /// Every object of `kind` the store can see. Nothing in the daemon needs this yet; it is
+    /// part of the contract so stores can be enumerated, e.g. for garbage collection.
+    #[allow(dead_code)]
+    async fn list(&self, kind: ObjectKind) -> io::Result<Vec<Id>>;
+
+    /// Stores that write through immediately are always in sync.
+    async fn sync_status(&self) -> SyncStatus {
+        SyncStatus::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn exercise(store: &dyn ObjectStore) {
+        let id = Id([7; 32]);
+        assert!(!store.has(ObjectKind::File, id).await.unwrap());
+        assert_eq!(store.get(ObjectKind::File, id).await.unwrap(), None);
+
+        store
+            .put(ObjectKind::File, id, b"contents".to_vec())
+            .await
+            .unwrap();
+        // Putting the same object twice is fine.
+        store
+            .put(ObjectKind::File, id, b"contents".to_vec())
+            .await
+            .unwrap();
+
+        assert!(store.has(ObjectKind::File, id).await.unwrap());
+        assert_eq!(
+            store.get(ObjectKind::File, id).await.unwrap(),
+            Some(b"contents".to_vec())
+        );
+        // Kinds are separate namespaces.
+        assert!(!store.has(ObjectKind::Tree, id).await.unwrap());
+        assert_eq!(store.list(ObjectKind::File).await.unwrap(), vec![id]);
+        assert!(store.list(ObjectKind::Commit).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn in_memory() {
+        exercise(&InMemoryObjectStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn disk() {
+        let dir = tempfile::tempdir().unwrap();
+        exercise(&DiskObjectStore::new(dir.path()).await.unwrap()).await;
+    }
+}
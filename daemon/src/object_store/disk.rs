@@ -0,0 +1,124 @@
+use std::{io, path::PathBuf};
+
+use async_trait::async_trait;
+use durable::write_durably;
+
+use super::{ObjectKind, ObjectStore};
+use crate::ty::Id;
+
+/// Persists objects on local disk, one file per object:
+///
+/// ```text
+/// <root>/commits/<hex id>
+/// <root>/conflicts/<hex id>
+/// <root>/files/<hex id>
+/// <root>/symlinks/<hex id>
+/// <root>/trees/<hex id>
+/// <root>/tmp/
+/// ```
+///
+/// Objects are written to `tmp/` first and renamed into place once they are on disk, so a
+/// crash never leaves a partially written object behind.
+#[derive(Clone, Debug)]
+pub struct DiskObjectStore {
+    root: PathBuf,
+}
+
+const TMP: &str = "tmp";
+
+fn kind_dir(kind: ObjectKind) -> &'static str {
+    match kind {
+        ObjectKind::Commit => "commits",
+        ObjectKind::File => "files",
+        ObjectKind::Symlink => "symlinks",
+        ObjectKind::Tree => "trees",
+        ObjectKind::Conflict => "conflicts",
+    }
+}
+
+impl DiskObjectStore {
+    pub async fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        for kind in ObjectKind::ALL {
+            tokio::fs::create_dir_all(root.join(kind_dir(kind))).await?;
+        }
+        tokio::fs::create_dir_all(root.join(TMP)).await?;
+        Ok(DiskObjectStore { root })
+    }
+
+    fn object_path(&self, kind: ObjectKind, id: Id) -> PathBuf {
+        self.root.join(kind_dir(kind)).join(id.hex())
+    }
+}
+
+#[async_trait]
+impl ObjectStore for DiskObjectStore {
+    async fn get(&self, kind: ObjectKind, id: Id) -> io::Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.object_path(kind, id)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn put(&self, kind: ObjectKind, id: Id, data: Vec<u8>) -> io::Result<()> {
+        let path = self.object_path(kind, id);
+        // Objects are content addressed, so an existing object is already what we would write.
+        if tokio::fs::try_exists(&path).await? {
+            return Ok(());
+        }
+
+        let tmp_path =
+            self.root
+                .join(TMP)
+                .join(format!("{}-{:016x}", id.hex(), rand::random::<u64>()));
+        let result = write_durably(&tmp_path, &path, &data).await;
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+        }
+        result
+    }
+
+    async fn has(&self, kind: ObjectKind, id: Id) -> io::Result<bool> {
+        tokio::fs::try_exists(self.object_path(kind, id)).await
+    }
+
+    async fn list(&self, kind: ObjectKind) -> io::Result<Vec<Id>> {
+        let mut ids = vec![];
+        let mut entries = tokio::fs::read_dir(self.root.join(kind_dir(kind))).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(id) = entry.file_name().to_str().and_then(Id::from_hex) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn objects_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let id = Id([3; 32]);
+
+        let store = DiskObjectStore::new(dir.path()).await.unwrap();
+        store
+            .put(ObjectKind::Tree, id, b"tree".to_vec())
+            .await
+            .unwrap();
+        drop(store);
+
+        let store = DiskObjectStore::new(dir.path()).await.unwrap();
+        assert_eq!(
+            store.get(ObjectKind::Tree, id).await.unwrap(),
+            Some(b"tree".to_vec())
+        );
+
+        // Nothing is left behind in the staging directory.
+        let mut tmp = tokio::fs::read_dir(dir.path().join(TMP)).await.unwrap();
+        assert!(tmp.next_entry().await.unwrap().is_none());
+    }
+}
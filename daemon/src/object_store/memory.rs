@@ -0,0 +1,39 @@
+use std::{collections::HashMap, io};
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+
+use super::{ObjectKind, ObjectStore};
+use crate::ty::Id;
+
+/// Keeps every object in memory. Nothing survives a restart.
+#[derive(Debug, Default)]
+pub struct InMemoryObjectStore {
+    objects: Mutex<HashMap<(ObjectKind, Id), Vec<u8>>>,
+}
+
+#[async_trait]
+impl ObjectStore for InMemoryObjectStore {
+    async fn get(&self, kind: ObjectKind, id: Id) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.objects.lock().get(&(kind, id)).cloned())
+    }
+
+    async fn put(&self, kind: ObjectKind, id: Id, data: Vec<u8>) -> io::Result<()> {
+        self.objects.lock().insert((kind, id), data);
+        Ok(())
+    }
+
+    async fn has(&self, kind: ObjectKind, id: Id) -> io::Result<bool> {
+        Ok(self.objects.lock().contains_key(&(kind, id)))
+    }
+
+    async fn list(&self, kind: ObjectKind) -> io::Result<Vec<Id>> {
+        Ok(self
+            .objects
+            .lock()
+            .keys()
+            .filter(|(k, _)| *k == kind)
+            .map(|(_, id)| *id)
+            .collect())
+    }
+}
@@ -0,0 +1,116 @@
+use std::{io, time::Duration};
+
+use async_trait::async_trait;
+use proto::remote::{
+    remote_backend_client::RemoteBackendClient, GetObjectReq, HasObjectReq, ListObjectsReq,
+    PutObjectReq,
+};
+use tonic::{transport::Channel, Code, Status};
+
+use super::{ObjectKind, ObjectStore};
+use crate::ty::Id;
+
+/// Give up on requests quickly enough that an unreachable remote is noticed.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Reads and writes objects through the `server` crate's gRPC interface.
+#[derive(Clone, Debug)]
+pub struct RemoteObjectStore {
+    client: RemoteBackendClient<Channel>,
+    repo: String,
+}
+
+impl RemoteObjectStore {
+    /// Connects lazily, so the daemon can start while the remote is unreachable.
+    pub fn new(addr: &str, repo: impl Into<String>) -> anyhow::Result<Self> {
+        let channel = Channel::from_shared(format!("http://{addr}"))?
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .connect_lazy();
+        Ok(RemoteObjectStore {
+            client: RemoteBackendClient::new(channel),
+            repo: repo.into(),
+        })
+    }
+}
+
+fn status_to_io(status: Status) -> io::Error {
+    let kind = match status.code() {
+        Code::NotFound => io::ErrorKind::NotFound,
+        Code::InvalidArgument => io::ErrorKind::InvalidInput,
+        Code::DataLoss => io::ErrorKind::InvalidData,
+        Code::Unavailable => io::ErrorKind::NotConnected,
+        Code::DeadlineExceeded => io::ErrorKind::TimedOut,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, status)
+}
+
+#[async_trait]
+impl ObjectStore for RemoteObjectStore {
+    async fn get(&self, kind: ObjectKind, id: Id) -> io::Result<Option<Vec<u8>>> {
+        let req = GetObjectReq {
+            repo: self.repo.clone(),
+            kind: kind.as_proto().into(),
+            id: id.into(),
+        };
+        match self.client.clone().get_object(req).await {
+            Ok(resp) => Ok(Some(resp.into_inner().data)),
+            Err(status) if status.code() == Code::NotFound => Ok(None),
+            Err(status) => Err(status_to_io(status)),
+        }
+    }
+
+    async fn put(&self, kind: ObjectKind, id: Id, data: Vec<u8>) -> io::Result<()> {
+        let req = PutObjectReq {
+            repo: self.repo.clone(),
+            kind: kind.as_proto().into(),
+            id: id.into(),
+            data,
+        };
+        self.client
+            .clone()
+            .put_object(req)
+            .await
+            .map_err(status_to_io)?;
+        Ok(())
+    }
+
+    async fn has(&self, kind: ObjectKind, id: Id) -> io::Result<bool> {
+        let req = HasObjectReq {
+            repo: self.repo.clone(),
+            kind: kind.as_proto().into(),
+            id: id.into(),
+        };
+        let resp = self
+            .client
+            .clone()
+            .has_object(req)
+            .await
+            .map_err(status_to_io)?;
+        Ok(resp.into_inner().present)
+    }
+
+    async fn list(&self, kind: ObjectKind) -> io::Result<Vec<Id>> {
+        let req = ListObjectsReq {
+            repo: self.repo.clone(),
+            kind: kind.as_proto().into(),
+        };
+        let resp = self
+            .client
+            .clone()
+            .list_objects(req)
+            .await
+            .map_err(status_to_io)?;
+        resp.into_inner()
+            .ids
+            .into_iter()
+            .map(|id| {
+                id.as_slice().try_into().map(Id).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "remote returned a malformed id")
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,688 @@
+use std::{
+    collections::BTreeSet,
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use durable::write_durably;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use super::{DiskObjectStore, ObjectKind, ObjectStore, SyncStatus};
+use crate::ty::Id;
+
+/// How often the uploader looks at the queue when nobody wakes it up.
+const UPLOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Bounds for how long the uploader waits before retrying an unreachable remote.
+const MIN_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+const TMP: &str = "tmp";
+const REJECTED: &str = "rejected";
+
+/// Acknowledges writes once they are on local disk and uploads them to `remote` in the
+/// background. Reads that miss locally fall through to `remote` and are cached.
+///
+/// Every write leaves an entry in a durable queue and is then stored locally before it is
+/// acknowledged:
+///
+/// ```text
+/// <queue>/<sequence>-<kind>-<hex id>
+/// ```
+///
+/// An entry is only removed once `remote` accepted the object, so a restarted daemon picks up
+/// the uploads where the previous one left off. Entries whose object was never stored, because
+/// the daemon stopped in between, are dropped, as their writes were never acknowledged. Entries
+/// the remote rejects are moved to `<queue>/rejected/` so they don't hold up the rest of the
+/// queue.
+///
+/// The store keeps working while `remote` is unreachable: everything cached locally can still be
+/// read, new writes pile up in the queue, and the uploader keeps probing the remote until it
+/// comes back.
+#[derive(Debug)]
+pub struct WriteBackObjectStore {
+    inner: Arc<Inner>,
+    wake: Arc<Notify>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    local: DiskObjectStore,
+    remote: Arc<dyn ObjectStore>,
+    queue: PathBuf,
+    next_sequence: AtomicU64,
+    offline: AtomicBool,
+    /// Held for reading by writes that are queued but not stored yet
+    storing: tokio::sync::RwLock<()>,
+}
+
+#[derive(Debug)]
+struct QueueEntry {
+    file_name: String,
+    sequence: u64,
+    kind: ObjectKind,
+    id: Id,
+}
+
+impl QueueEntry {
+    fn parse(file_name: &str) -> Option<QueueEntry> {
+        let mut parts = file_name.splitn(3, '-');
+        let sequence = parts.next()?.parse().ok()?;
+        let kind = ObjectKind::from_name(parts.next()?)?;
+        let id = Id::from_hex(parts.next()?)?;
+        Some(QueueEntry {
+            file_name: file_name.to_string(),
+            sequence,
+            kind,
+            id,
+        })
+    }
+}
+
+impl WriteBackObjectStore {
+    /// Opens the queue in `queue` and starts uploading anything left over from a previous run.
+    pub async fn new(
+        local: DiskObjectStore,
+        remote: Arc<dyn ObjectStore>,
+        queue: impl Into<PathBuf>,
+    ) -> io::Result<Self> {
+        let store = Self::without_uploader(local, remote, queue).await?;
+        tokio::spawn(upload_forever(
+            Arc::downgrade(&store.inner),
+            store.wake.clone(),
+        ));
+        Ok(store)
+    }
+
+    async fn without_uploader(
+        local: DiskObjectStore,
+        remote: Arc<dyn ObjectStore>,
+        queue: impl Into<PathBuf>,
+    ) -> io::Result<Self> {
+        let queue = queue.into();
+        tokio::fs::create_dir_all(queue.join(TMP)).await?;
+        tokio::fs::create_dir_all(queue.join(REJECTED)).await?;
+        let mut inner = Inner {
+            local,
+            remote,
+            queue,
+            next_sequence: AtomicU64::new(0),
+            offline: AtomicBool::new(false),
+            storing: tokio::sync::RwLock::new(()),
+        };
+        let next_sequence = inner
+            .pending()
+            .await?
+            .last()
+            .map_or(0, |entry| entry.sequence + 1);
+        inner.next_sequence = AtomicU64::new(next_sequence);
+        Ok(WriteBackObjectStore {
+            inner: Arc::new(inner),
+            wake: Arc::new(Notify::new()),
+        })
+    }
+}
+
+/// Errors that mean we could not talk to the remote at all, as opposed to the remote rejecting
+/// a request.
+fn is_unreachable(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::NotConnected
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+impl Inner {
+    fn is_offline(&self) -> bool {
+        self.offline.load(Ordering::SeqCst)
+    }
+
+    fn set_offline(&self, offline: bool) {
+        if self.offline.swap(offline, Ordering::SeqCst) == offline {
+            return;
+        }
+        if offline {
+            warn!("Remote is unreachable, working offline");
+        } else {
+            info!("Remote is reachable again");
+        }
+    }
+
+    /// Tracks whether the remote is reachable based on the outcome of a call to it.
+    fn observe<T>(&self, result: io::Result<T>) -> io::Result<T> {
+        match &result {
+            Ok(_) => self.set_offline(false),
+            Err(err) if is_unreachable(err) => self.set_offline(true),
+            Err(_) => {}
+        }
+        result
+    }
+
+    /// Queue entries in the order they were written.
+    async fn pending(&self) -> io::Result<Vec<QueueEntry>> {
+        Self::entries(&self.queue).await
+    }
+
+    /// Entries that will not be uploaded without someone looking into them.
+    async fn rejected(&self) -> io::Result<Vec<QueueEntry>> {
+        Self::entries(&self.queue.join(REJECTED)).await
+    }
+
+    async fn entries(dir: &std::path::Path) -> io::Result<Vec<QueueEntry>> {
+        let mut pending = vec![];
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(entry) = entry.file_name().to_str().and_then(QueueEntry::parse) {
+                pending.push(entry);
+            }
+        }
+        pending.sort_by_key(|entry| entry.sequence);
+        Ok(pending)
+    }
+
+    async fn enqueue(&self, kind: ObjectKind, id: Id) -> io::Result<()> {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
+        let file_name = format!("{sequence:020}-{}-{}", kind.name(), id.hex());
+        write_durably(
+            &self.queue.join(TMP).join(&file_name),
+            &self.queue.join(&file_name),
+            &[],
+        )
+        .await
+    }
+
+    /// The object of a queue entry, once the write that queued it stored it.
+    async fn stored(&self, entry: &QueueEntry) -> io::Result<Option<Vec<u8>>> {
+        if let Some(data) = self.local.get(entry.kind, entry.id).await? {
+            return Ok(Some(data));
+        }
+        drop(self.storing.write().await);
+        self.local.get(entry.kind, entry.id).await
+    }
+
+    /// Uploads every queued object, stopping when the remote is unreachable.
+    async fn upload_pending(&self) -> io::Result<()> {
+        for entry in self.pending().await? {
+            let Some(data) = self.stored(&entry).await? else {
+                warn!(
+                    "Dropping upload of {} {}, it was never stored",
+                    entry.kind.name(),
+                    entry.id.hex()
+                );
+                tokio::fs::remove_file(self.queue.join(&entry.file_name)).await?;
+                continue;
+            };
+            let result = self.observe(self.remote.put(entry.kind, entry.id, data).await);
+            match result {
+                Ok(()) => tokio::fs::remove_file(self.queue.join(&entry.file_name)).await?,
+                Err(err) if is_unreachable(&err) => return Err(err),
+                // Retrying won't help, keep the entry for someone to look at.
+                Err(err) => {
+                    warn!(
+                        "Setting aside upload of {} {}: {err}",
+                        entry.kind.name(),
+                        entry.id.hex()
+                    );
+                    tokio::fs::rename(
+                        self.queue.join(&entry.file_name),
+                        self.queue.join(REJECTED).join(&entry.file_name),
+                    )
+                    .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Brings the remote up to date, checking first whether it is back if we are offline.
+    async fn reconcile(&self) -> io::Result<()> {
+        if self.is_offline() {
+            // Any cheap request will do to find out whether the remote is back.
+            self.observe(self.remote.has(ObjectKind::Tree, Id::default()).await)?;
+        }
+        self.upload_pending().await
+    }
+}
+
+async fn upload_forever(inner: Weak<Inner>, wake: Arc<Notify>) {
+    let mut retry_interval = MIN_RETRY_INTERVAL;
+    loop {
+        let Some(store) = inner.upgrade() else {
+            return;
+        };
+        let result = store.reconcile().await;
+        drop(store);
+        match result {
+            Ok(()) => {
+                retry_interval = MIN_RETRY_INTERVAL;
+                let _ = tokio::time::timeout(UPLOAD_INTERVAL, wake.notified()).await;
+            }
+            Err(err) => {
+                warn!("Uploading to the remote failed, retrying in {retry_interval:?}: {err}");
+                tokio::time::sleep(retry_interval).await;
+                retry_interval = (retry_interval * 2).min(MAX_RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for WriteBackObjectStore {
+    async fn get(&self, kind: ObjectKind, id: Id) -> io::Result<Option<Vec<u8>>> {
+        if let Some(data) = self.inner.local.get(kind, id).await? {
+            return Ok(Some(data));
+        }
+        let remote = self.inner.observe(self.inner.remote.get(kind, id).await);
+        let Some(data) = remote.map_err(|err| {
+            if is_unreachable(&err) {
+                io::Error::new(
+                    err.kind(),
+                    format!(
+                        "{} {} is not cached locally and the remote is unreachable",
+                        kind.name(),
+                        id.hex()
+                    ),
+                )
+            } else {
+                err
+            }
+        })?
+        else {
+            return Ok(None);
+        };
+        info!("Fetched {} {} from the remote", kind.name(), id.hex());
+        self.inner.local.put(kind, id, data.clone()).await?;
+        Ok(Some(data))
+    }
+
+    async fn put(&self, kind: ObjectKind, id: Id, data: Vec<u8>) -> io::Result<()> {
+        if self.inner.local.has(kind, id).await? {
+            return Ok(());
+        }
+        // Queue first, so that everything stored locally is uploaded eventually. The uploader
+        // waits for writes in progress before it drops entries of objects that aren't stored.
+        let storing = self.inner.storing.read().await;
+        self.inner.enqueue(kind, id).await?;
+        self.inner.local.put(kind, id, data).await?;
+        drop(storing);
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    async fn has(&self, kind: ObjectKind, id: Id) -> io::Result<bool> {
+        if self.inner.local.has(kind, id).await? {
+            return Ok(true);
+        }
+        self.inner.observe(self.inner.remote.has(kind, id).await)
+    }
+
+    async fn list(&self, kind: ObjectKind) -> io::Result<Vec<Id>> {
+        let mut ids: BTreeSet<_> = self
+            .inner
+            .local
+            .list(kind)
+            .await?
+            .into_iter()
+            .map(|id| id.0)
+            .collect();
+        match self.inner.observe(self.inner.remote.list(kind).await) {
+            Ok(remote) => ids.extend(remote.into_iter().map(|id| id.0)),
+            // Offline, the local objects are all we can see.
+            Err(err) if is_unreachable(&err) => {}
+            Err(err) => return Err(err),
+        }
+        Ok(ids.into_iter().map(Id).collect())
+    }
+
+    async fn sync_status(&self) -> SyncStatus {
+        let count = |entries: io::Result<Vec<QueueEntry>>| match entries {
+            Ok(entries) => entries.len() as u64,
+            Err(err) => {
+                warn!("Could not read the upload queue: {err}");
+                0
+            }
+        };
+        SyncStatus {
+            pending_uploads: count(self.inner.pending().await),
+            rejected_uploads: count(self.inner.rejected().await),
+            offline: self.inner.is_offline(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object_store::InMemoryObjectStore;
+
+    /// A remote that can be taken offline.
+    #[derive(Debug, Default)]
+    struct Flaky {
+        unreachable: AtomicBool,
+        objects: InMemoryObjectStore,
+    }
+
+    impl Flaky {
+        fn unreachable() -> Self {
+            Flaky {
+                unreachable: AtomicBool::new(true),
+                ..Default::default()
+            }
+        }
+
+        fn check(&self) -> io::Result<()> {
+            if self.unreachable.load(Ordering::SeqCst) {
+                Err(io::ErrorKind::NotConnected.into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ObjectStore for Flaky {
+        async fn get(&self, kind: ObjectKind, id: Id) -> io::Result<Option<Vec<u8>>> {
+            self.check()?;
+            self.objects.get(kind, id).await
+        }
+
+        async fn put(&self, kind: ObjectKind, id: Id, data: Vec<u8>) -> io::Result<()> {
+            self.check()?;
+            self.objects.put(kind, id, data).await
+        }
+
+        async fn has(&self, kind: ObjectKind, id: Id) -> io::Result<bool> {
+            self.check()?;
+            self.objects.has(kind, id).await
+        }
+
+        async fn list(&self, kind: ObjectKind) -> io::Result<Vec<Id>> {
+            self.check()?;
+            self.objects.list(kind).await
+        }
+    }
+
+    async fn open(dir: &std::path::Path, remote: Arc<dyn ObjectStore>) -> WriteBackObjectStore {
+        let local = DiskObjectStore::new(dir.join("store")).await.unwrap();
+        WriteBackObjectStore::without_uploader(local, remote, dir.join("queue"))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn queue_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let id = Id([5; 32]);
+
+        let store = open(dir.path(), Arc::new(Flaky::unreachable())).await;
+        store
+            .put(ObjectKind::File, id, b"file".to_vec())
+            .await
+            .unwrap();
+        assert!(store.inner.upload_pending().await.is_err());
+        assert_eq!(store.sync_status().await.pending_uploads, 1);
+        drop(store);
+
+        let remote = Arc::new(InMemoryObjectStore::default());
+        let store = open(dir.path(), remote.clone()).await;
+        assert_eq!(store.sync_status().await.pending_uploads, 1);
+        store.inner.upload_pending().await.unwrap();
+        assert_eq!(store.sync_status().await.pending_uploads, 0);
+        assert_eq!(
+            remote.get(ObjectKind::File, id).await.unwrap(),
+            Some(b"file".to_vec())
+        );
+    }
+
+    /// A remote that refuses some objects.
+    #[derive(Debug, Default)]
+    struct Picky {
+        refused: Id,
+        objects: InMemoryObjectStore,
+    }
+
+    #[async_trait]
+    impl ObjectStore for Picky {
+        async fn get(&self, kind: ObjectKind, id: Id) -> io::Result<Option<Vec<u8>>> {
+            self.objects.get(kind, id).await
+        }
+
+        async fn put(&self, kind: ObjectKind, id: Id, data: Vec<u8>) -> io::Result<()> {
+            if id == self.refused {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "refused"));
+            }
+            self.objects.put(kind, id, data).await
+        }
+
+        async fn has(&self, kind: ObjectKind, id: Id) -> io::Result<bool> {
+            self.objects.has(kind, id).await
+        }
+
+        async fn list(&self, kind: ObjectKind) -> io::Result<Vec<Id>> {
+            self.objects.list(kind).await
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_uploads_are_set_aside() {
+        let dir = tempfile::tempdir().unwrap();
+        let refused = Id([11; 32]);
+        let missing = Id([12; 32]);
+        let accepted = Id([13; 32]);
+
+        let remote = Arc::new(Picky {
+            refused,
+            ..Default::default()
+        });
+        let store = open(dir.path(), remote.clone()).await;
+        store
+            .put(ObjectKind::File, refused, b"refused".to_vec())
+            .await
+            .unwrap();
+        store
+            .inner
+            .enqueue(ObjectKind::File, missing)
+            .await
+            .unwrap();
+        store
+            .put(ObjectKind::File, accepted, b"accepted".to_vec())
+            .await
+            .unwrap();
+
+        // Neither blocks the objects queued after them. The entry of the object that was never
+        // stored, as if the daemon stopped in between, is dropped.
+        store.inner.upload_pending().await.unwrap();
+        assert!(remote
+            .objects
+            .has(ObjectKind::File, accepted)
+            .await
+            .unwrap());
+        assert_eq!(
+            store.sync_status().await,
+            SyncStatus {
+                pending_uploads: 0,
+                rejected_uploads: 1,
+                offline: false,
+            }
+        );
+        let rejected: Vec<_> = store
+            .inner
+            .rejected()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.id)
+            .collect();
+        assert_eq!(rejected, [refused]);
+    }
+
+    #[tokio::test]
+    async fn uploads_wait_for_writes_in_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let id = Id([14; 32]);
+
+        let remote = Arc::new(InMemoryObjectStore::default());
+        let store = Arc::new(open(dir.path(), remote.clone()).await);
+        // A write that queued its object but didn't store it yet.
+        let storing = store.inner.storing.read().await;
+        store.inner.enqueue(ObjectKind::File, id).await.unwrap();
+        let upload = tokio::spawn({
+            let store = store.clone();
+            async move { store.inner.upload_pending().await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!upload.is_finished());
+
+        store
+            .inner
+            .local
+            .put(ObjectKind::File, id, b"file".to_vec())
+            .await
+            .unwrap();
+        drop(storing);
+        upload.await.unwrap().unwrap();
+        assert!(remote.has(ObjectKind::File, id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn reads_fall_through_to_remote() {
+        let dir = tempfile::tempdir().unwrap();
+        let id = Id([6; 32]);
+
+        let remote = Arc::new(InMemoryObjectStore::default());
+        remote
+            .put(ObjectKind::Tree, id, b"tree".to_vec())
+            .await
+            .unwrap();
+        let store = open(dir.path(), remote).await;
+        assert_eq!(
+            store.get(ObjectKind::Tree, id).await.unwrap(),
+            Some(b"tree".to_vec())
+        );
+        // Cached locally, and nothing to upload since the remote already has it.
+        assert!(store.inner.local.has(ObjectKind::Tree, id).await.unwrap());
+        assert_eq!(store.sync_status().await.pending_uploads, 0);
+    }
+
+    #[tokio::test]
+    async fn lists_local_and_remote_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote_only = Id([11; 32]);
+        let local_only = Id([12; 32]);
+
+        let remote = Arc::new(Flaky::default());
+        remote
+            .objects
+            .put(ObjectKind::Tree, remote_only, b"remote".to_vec())
+            .await
+            .unwrap();
+        let store = open(dir.path(), remote.clone()).await;
+        store
+            .put(ObjectKind::Tree, local_only, b"local".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            store.list(ObjectKind::Tree).await.unwrap(),
+            vec![remote_only, local_only]
+        );
+
+        remote.unreachable.store(true, Ordering::SeqCst);
+        assert_eq!(
+            store.list(ObjectKind::Tree).await.unwrap(),
+            vec![local_only]
+        );
+    }
+
+    #[tokio::test]
+    async fn uploader_drains_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let id = Id([7; 32]);
+
+        let remote = Arc::new(InMemoryObjectStore::default());
+        let local = DiskObjectStore::new(dir.path().join("store"))
+            .await
+            .unwrap();
+        let store = WriteBackObjectStore::new(local, remote.clone(), dir.path().join("queue"))
+            .await
+            .unwrap();
+        store
+            .put(ObjectKind::Commit, id, b"commit".to_vec())
+            .await
+            .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while store.sync_status().await.pending_uploads > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(remote.has(ObjectKind::Commit, id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn offline_and_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let cached = Id([8; 32]);
+        let written = Id([9; 32]);
+        let missing = Id([10; 32]);
+
+        let remote = Arc::new(Flaky::default());
+        remote
+            .objects
+            .put(ObjectKind::Tree, cached, b"cached".to_vec())
+            .await
+            .unwrap();
+        let store = open(dir.path(), remote.clone()).await;
+        store.get(ObjectKind::Tree, cached).await.unwrap();
+        assert!(!store.sync_status().await.offline);
+
+        remote.unreachable.store(true, Ordering::SeqCst);
+
+        // Writes are still accepted, cached objects can still be read.
+        store
+            .put(ObjectKind::Commit, written, b"written".to_vec())
+            .await
+            .unwrap();
+        assert!(store.inner.reconcile().await.is_err());
+        assert_eq!(
+            store.sync_status().await,
+            SyncStatus {
+                pending_uploads: 1,
+                rejected_uploads: 0,
+                offline: true,
+            }
+        );
+        assert_eq!(
+            store.get(ObjectKind::Tree, cached).await.unwrap(),
+            Some(b"cached".to_vec())
+        );
+        assert_eq!(
+            store.get(ObjectKind::Commit, written).await.unwrap(),
+            Some(b"written".to_vec())
+        );
+        let err = store.get(ObjectKind::Tree, missing).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+
+        remote.unreachable.store(false, Ordering::SeqCst);
+
+        store.inner.reconcile().await.unwrap();
+        assert_eq!(store.sync_status().await, SyncStatus::default());
+        assert!(remote
+            .objects
+            .has(ObjectKind::Commit, written)
+            .await
+            .unwrap());
+    }
+}
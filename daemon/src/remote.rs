@@ -0,0 +1,476 @@
+//! Remotes repos are initialized from, written `[user@]host[:port]/repo` as in
+//! `jj yak init bwb@thelastyak.com/repo`, and the stores that keep their objects.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context};
+use durable::write_durably;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{
+    object_store::{
+        DiskObjectStore, ObjectStore, RemoteObjectStore, SyncStatus, WriteBackObjectStore,
+    },
+    store::Store,
+};
+
+/// Port remote backends listen on unless a remote says otherwise
+pub const DEFAULT_PORT: u16 = 23000;
+
+/// Remote that keeps a repo's objects with the daemon only
+const LOCAL: &str = "localhost";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Remote {
+    /// Objects stay in the daemon's store and are not uploaded anywhere
+    Local,
+    /// A repository on a remote backend
+    Repo(RemoteRepo),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RemoteRepo {
+    pub user: Option<String>,
+    /// `host:port` of the remote backend
+    pub addr: String,
+    pub repo: String,
+}
+
+impl Remote {
+    /// Ports left out of `remote` default to `default_port`.
+    pub fn parse(remote: &str, default_port: u16) -> anyhow::Result<Remote> {
+        if remote == LOCAL {
+            return Ok(Remote::Local);
+        }
+        let (addr, repo) = remote
+            .split_once('/')
+            .ok_or_else(|| anyhow!("Remote {remote:?} does not name a repo"))?;
+        let (user, addr) = match addr.split_once('@') {
+            Some((user, addr)) => {
+                if user.is_empty() || !user.chars().all(is_valid_user_char) {
+                    bail!("Invalid user in remote {remote:?}");
+                }
+                (Some(user.to_string()), addr)
+            }
+            None => (None, addr),
+        };
+        if !is_valid_repo_name(repo) {
+            bail!("Invalid repo name in remote {remote:?}");
+        }
+        Ok(Remote::Repo(RemoteRepo {
+            user,
+            addr: parse_addr(addr, default_port)?,
+            repo: repo.to_string(),
+        }))
+    }
+}
+
+/// How the daemon resolves the remotes repos are initialized from.
+#[derive(Clone, Debug)]
+pub struct Remotes {
+    /// Port of remotes that leave it out
+    pub default_port: u16,
+    /// `host:port` of the remote backend of repos written without a host, `[user@]repo`
+    pub default_addr: Option<String>,
+}
+
+impl Default for Remotes {
+    fn default() -> Self {
+        Remotes {
+            default_port: DEFAULT_PORT,
+            default_addr: None,
+        }
+    }
+}
+
+impl Remotes {
+    /// Remotes written without a host, `[user@]repo`, are on `default_addr`.
+    pub fn resolve(&self, remote: &str) -> anyhow::Result<Remote> {
+        if remote == LOCAL || remote.contains('/') {
+            return Remote::parse(remote, self.default_port);
+        }
+        let addr = self.default_addr.as_ref().ok_or_else(|| {
+            anyhow!("Remote {remote:?} does not name a host, and the daemon has no remote_addr")
+        })?;
+        let (user, repo) = match remote.split_once('@') {
+            Some((user, repo)) => (format!("{user}@"), repo),
+            None => (String::new(), remote),
+        };
+        Remote::parse(&format!("{user}{addr}/{repo}"), self.default_port)
+    }
+}
+
+/// Connects to `repo` on the remote backend at `addr`.
+pub type Connect = Arc<dyn Fn(&str, &str) -> anyhow::Result<Arc<dyn ObjectStore>> + Send + Sync>;
+
+/// The stores of remote repos, one per address and repo. Each keeps a copy of its objects on
+/// local disk and uploads writes to its repo in the background:
+///
+/// ```text
+/// <root>/<hash of addr/repo>/remote.toml
+/// <root>/<hash of addr/repo>/store/
+/// <root>/<hash of addr/repo>/upload_queue/
+/// ```
+#[derive(Clone)]
+pub struct RemoteStores {
+    root: PathBuf,
+    connect: Connect,
+    /// Stores opened so far, by address and repo
+    stores: Arc<Mutex<HashMap<(String, String), Store>>>,
+}
+
+/// Which remote repo a store under the root belongs to.
+#[derive(Serialize, Deserialize)]
+struct SavedRemote {
+    addr: String,
+    repo: String,
+}
+
+impl RemoteStores {
+    /// Stores of remote repos in `root`, uploading through the `server` crate's gRPC interface.
+    pub async fn new(root: impl Into<PathBuf>) -> Self {
+        let connect: Connect = Arc::new(|addr, repo| {
+            let store: Arc<dyn ObjectStore> = Arc::new(RemoteObjectStore::new(addr, repo)?);
+            Ok(store)
+        });
+        Self::with_connect(root, connect).await
+    }
+
+    /// Reopens the stores left in `root` by a previous run, so uploads they still have queued
+    /// resume even if their repo is not checked out anymore.
+    pub async fn with_connect(root: impl Into<PathBuf>, connect: Connect) -> Self {
+        let stores = RemoteStores {
+            root: root.into(),
+            connect,
+            stores: Arc::default(),
+        };
+        if let Err(err) = stores.reopen().await {
+            warn!(
+                "Could not reopen remote stores in {}: {err:#}",
+                stores.root.display()
+            );
+        }
+        stores
+    }
+
+    async fn reopen(&self) -> anyhow::Result<()> {
+        let mut entries = match tokio::fs::read_dir(&self.root).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path().join("remote.toml");
+            let reopened = async {
+                let saved: SavedRemote = toml::from_str(&tokio::fs::read_to_string(&path).await?)?;
+                self.open(&saved.addr, &saved.repo).await
+            };
+            if let Err(err) = reopened.await {
+                warn!("Could not reopen the store in {}: {err:#}", path.display());
+            }
+        }
+        Ok(())
+    }
+
+    /// The store of `repo`, opened the first time it is asked for.
+    pub async fn store(&self, repo: &RemoteRepo) -> anyhow::Result<Store> {
+        self.open(&repo.addr, &repo.repo).await
+    }
+
+    async fn open(&self, addr: &str, repo: &str) -> anyhow::Result<Store> {
+        let key = (addr.to_string(), repo.to_string());
+        let mut stores = self.stores.lock().await;
+        if let Some(store) = stores.get(&key) {
+            return Ok(store.clone());
+        }
+        let dir = self.root.join(
+            blake3::hash(format!("{addr}/{repo}").as_bytes())
+                .to_hex()
+                .as_str(),
+        );
+        save_remote(&dir, addr, repo)
+            .await
+            .with_context(|| format!("Could not record the remote in {}", dir.display()))?;
+        let local_path = dir.join("store");
+        let local = DiskObjectStore::new(&local_path)
+            .await
+            .with_context(|| format!("Could not open store in {}", local_path.display()))?;
+        let queue = dir.join("upload_queue");
+        let objects = WriteBackObjectStore::new(local, (self.connect)(addr, repo)?, &queue)
+            .await
+            .with_context(|| format!("Could not open upload queue in {}", queue.display()))?;
+        let store = Store::new(Arc::new(objects)).await?;
+        info!("Opened the store of {addr}/{repo}");
+        stores.insert(key, store.clone());
+        Ok(store)
+    }
+
+    /// How far the stores opened so far are from being in sync with their repos, together.
+    pub async fn sync_status(&self) -> SyncStatus {
+        let stores: Vec<Store> = self.stores.lock().await.values().cloned().collect();
+        let mut status = SyncStatus::default();
+        for store in stores {
+            status = status.merge(store.sync_status().await);
+        }
+        status
+    }
+}
+
+async fn save_remote(dir: &Path, addr: &str, repo: &str) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    let contents = toml::to_string(&SavedRemote {
+        addr: addr.to_string(),
+        repo: repo.to_string(),
+    })?;
+    write_durably(
+        &dir.join("remote.toml.tmp"),
+        &dir.join("remote.toml"),
+        contents.as_bytes(),
+    )
+    .await?;
+    Ok(())
+}
+
+impl fmt::Display for RemoteRepo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{user}@")?;
+        }
+        write!(f, "{}/{}", self.addr, self.repo)
+    }
+}
+
+/// Resolves `host[:port]` into the `host:port` address of a remote backend. IPv6 hosts are
+/// written in brackets, e.g. `[::1]:23000`.
+pub fn parse_addr(addr: &str, default_port: u16) -> anyhow::Result<String> {
+    let (host, port) = split_addr(addr)?;
+    Ok(format!("{host}:{}", port.unwrap_or(default_port)))
+}
+
+/// The port of a `host[:port]` address, if it has one.
+pub fn port(addr: &str) -> anyhow::Result<Option<u16>> {
+    Ok(split_addr(addr)?.1)
+}
+
+fn split_addr(addr: &str) -> anyhow::Result<(String, Option<u16>)> {
+    let (host, port) = if let Some(rest) = addr.strip_prefix('[') {
+        let (host, rest) = rest
+            .split_once(']')
+            .ok_or_else(|| anyhow!("Unclosed bracket in address {addr:?}"))?;
+        if host.parse::<std::net::Ipv6Addr>().is_err() {
+            bail!("Invalid IPv6 address in {addr:?}");
+        }
+        let port = match rest {
+            "" => None,
+            _ => Some(
+                rest.strip_prefix(':')
+                    .ok_or_else(|| anyhow!("Invalid address {addr:?}"))?,
+            ),
+        };
+        (format!("[{host}]"), port)
+    } else {
+        let (host, port) = match addr.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (addr, None),
+        };
+        if host.is_empty() || !host.chars().all(is_valid_host_char) {
+            bail!("Invalid host in address {addr:?}");
+        }
+        (host.to_string(), port)
+    };
+    let port = port
+        .map(|port| {
+            port.parse::<u16>()
+                .map_err(|_| anyhow!("Invalid port in address {addr:?}"))
+        })
+        .transpose()?;
+    Ok((host, port))
+}
+
+fn is_valid_host_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '.')
+}
+
+fn is_valid_user_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')
+}
+
+/// The remote backend only accepts repo names that are safe as directory names.
+fn is_valid_repo_name(repo: &str) -> bool {
+    !repo.is_empty()
+        && !repo.starts_with('.')
+        && repo
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo(user: Option<&str>, addr: &str, repo: &str) -> Remote {
+        Remote::Repo(RemoteRepo {
+            user: user.map(str::to_string),
+            addr: addr.to_string(),
+            repo: repo.to_string(),
+        })
+    }
+
+    #[test]
+    fn remotes_are_parsed() {
+        assert_eq!(Remote::parse("localhost", 23000).unwrap(), Remote::Local);
+        assert_eq!(
+            Remote::parse("bwb@thelastyak.com/repo", 23000).unwrap(),
+            repo(Some("bwb"), "thelastyak.com:23000", "repo")
+        );
+        assert_eq!(
+            Remote::parse("localhost:12000/my-repo", 23000).unwrap(),
+            repo(None, "localhost:12000", "my-repo")
+        );
+        assert_eq!(
+            Remote::parse("bwb@[::1]/repo", 12000).unwrap(),
+            repo(Some("bwb"), "[::1]:12000", "repo")
+        );
+        assert_eq!(
+            Remote::parse("[::1]:23000/repo", 12000).unwrap(),
+            repo(None, "[::1]:23000", "repo")
+        );
+
+        for invalid in [
+            "",
+            "thelastyak.com",
+            "thelastyak.com/",
+            "/repo",
+            "@thelastyak.com/repo",
+            "bwb@thelastyak.com/a/b",
+            "thelastyak.com/.hidden",
+            "thelastyak.com:port/repo",
+            "thelastyak.com:70000/repo",
+            "[thelastyak.com]/repo",
+            "[::1/repo",
+            "a b/repo",
+        ] {
+            assert!(
+                Remote::parse(invalid, 23000).is_err(),
+                "{invalid:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn remote_repos_are_displayed_as_written() {
+        let Remote::Repo(remote) = Remote::parse("bwb@[::1]:23000/repo", 0).unwrap() else {
+            panic!("not a remote repo");
+        };
+        assert_eq!(remote.to_string(), "bwb@[::1]:23000/repo");
+    }
+
+    #[test]
+    fn remotes_without_a_host_are_on_remote_addr() {
+        let remotes = Remotes {
+            default_port: 23000,
+            default_addr: Some("thelastyak.com:23000".to_string()),
+        };
+        assert_eq!(remotes.resolve("localhost").unwrap(), Remote::Local);
+        assert_eq!(
+            remotes.resolve("bwb@repo").unwrap(),
+            repo(Some("bwb"), "thelastyak.com:23000", "repo")
+        );
+        assert_eq!(
+            remotes.resolve("elsewhere:12000/other").unwrap(),
+            repo(None, "elsewhere:12000", "other")
+        );
+        assert!(remotes.resolve("bwb@.hidden").is_err());
+        assert_eq!(
+            Remotes::default().resolve("repo").unwrap_err().to_string(),
+            "Remote \"repo\" does not name a host, and the daemon has no remote_addr"
+        );
+    }
+
+    #[tokio::test]
+    async fn remote_repos_get_their_own_stores() {
+        use crate::{
+            object_store::{InMemoryObjectStore, ObjectKind},
+            ty::File,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let remote_objects: Arc<std::sync::Mutex<HashMap<String, Arc<InMemoryObjectStore>>>> =
+            Arc::default();
+        let connect: Connect = {
+            let remote_objects = remote_objects.clone();
+            Arc::new(move |addr, repo| {
+                let objects = remote_objects
+                    .lock()
+                    .unwrap()
+                    .entry(format!("{addr}/{repo}"))
+                    .or_default()
+                    .clone();
+                Ok(objects)
+            })
+        };
+        let stores = RemoteStores::with_connect(dir.path(), connect.clone()).await;
+        let Remote::Repo(a) = Remote::parse("bwb@thelastyak.com/a", 23000).unwrap() else {
+            panic!("not a remote repo");
+        };
+        let Remote::Repo(b) = Remote::parse("elsewhere/b", 23000).unwrap() else {
+            panic!("not a remote repo");
+        };
+
+        let store = stores.store(&a).await.unwrap();
+        let file_id = store
+            .write_file(File {
+                content: b"hello".to_vec(),
+            })
+            .await
+            .unwrap();
+        // The store is shared by sessions of the same repo, whoever they are for.
+        let Remote::Repo(a_as_other_user) = Remote::parse("thelastyak.com/a", 23000).unwrap()
+        else {
+            panic!("not a remote repo");
+        };
+        let same = stores.store(&a_as_other_user).await.unwrap();
+        assert!(same.get_file(file_id).await.unwrap().is_some());
+        // Other repos don't see the objects.
+        let other = stores.store(&b).await.unwrap();
+        assert!(other.get_file(file_id).await.unwrap().is_none());
+
+        // The write is uploaded to the repo it was written to.
+        let uploads = async {
+            while stores.sync_status().await.pending_uploads > 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(30), uploads)
+            .await
+            .expect("the write should be uploaded within 30s");
+        let uploaded = |name: &str| remote_objects.lock().unwrap()[name].clone();
+        assert!(uploaded("thelastyak.com:23000/a")
+            .has(ObjectKind::File, file_id)
+            .await
+            .unwrap());
+        assert!(!uploaded("elsewhere:23000/b")
+            .has(ObjectKind::File, file_id)
+            .await
+            .unwrap());
+
+        // Stores come back after a restart.
+        let reopened = RemoteStores::with_connect(dir.path(), connect).await;
+        assert_eq!(reopened.stores.lock().await.len(), 2);
+    }
+
+    #[test]
+    fn ports_default() {
+        assert_eq!(parse_addr("[::1]", 23000).unwrap(), "[::1]:23000");
+        assert_eq!(port("[::1]:12000").unwrap(), Some(12000));
+        assert_eq!(port("thelastyak.com").unwrap(), None);
+        assert!(port("thelastyak.com:").is_err());
+    }
+}
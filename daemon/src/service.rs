@@ -1,32 +1,484 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt::Display,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
 
+use anyhow::Context as _;
+use prost::Message;
 use proto::jj_interface::*;
 use tokio::sync::Mutex;
-use tonic::{Request, Response, Status};
-use tracing::info;
+use tokio_stream::Stream;
+use tonic::{Code, Request, Response, Status};
+use tracing::{error, info};
 
-use crate::{store::Store, ty::Id};
+use crate::{
+    lease::{Lease, Leases},
+    object_store::ObjectKind,
+    remote::{Remote, RemoteStores, Remotes},
+    store::Store,
+    ty::{self, Id},
+    vfs::VirtualFileSystem,
+    vfs_mgr::VfsManagerHandle,
+    working_copy::{SavedSession, SavedSessions, WorkingCopyState},
+};
 
+/// A repo and the workspaces it is checked out in.
 #[derive(Clone)]
 struct Session {
     remote: String,
+    /// Where the repo's objects are kept
+    store: Store,
+    /// Where the repo was initialized
+    path: String,
+    workspaces: Vec<Workspace>,
+}
+
+#[derive(Clone)]
+struct Workspace {
     path: String,
+    /// What is mounted at `path`
+    vfs: VirtualFileSystem,
+    /// Port `vfs` is served over
+    nfs_port: u16,
+    state: WorkingCopyState,
 }
 
 pub struct JujutsuService {
+    /// Objects of repos from `localhost`, and of store requests that don't name a repo
     store: Store,
+    /// Where per working copy state lives
+    cache: PathBuf,
     sessions: Arc<Mutex<Vec<Session>>>,
+    /// Working copies that are being set up outside of the `sessions` lock
+    reserved: Arc<parking_lot::Mutex<HashSet<String>>>,
+    leases: Leases,
+    vfs_mgr: VfsManagerHandle,
+    /// Resolves the remotes repos are initialized from
+    remotes: Remotes,
+    /// Objects of repos from remote backends
+    remote_stores: RemoteStores,
+    /// Working copies that were served before the daemon restarted, but could not be restored
+    unrestored: Vec<daemon_status_reply::Unrestored>,
 }
 
 impl JujutsuService {
-    pub fn new() -> jujutsu_interface_server::JujutsuInterfaceServer<Self> {
-        jujutsu_interface_server::JujutsuInterfaceServer::new(JujutsuService {
-            store: Store::new(),
+    /// Serves the working copies that were served before the daemon restarted again.
+    pub async fn new(
+        store: Store,
+        cache: PathBuf,
+        vfs_mgr: VfsManagerHandle,
+        remotes: Remotes,
+        remote_stores: RemoteStores,
+    ) -> jujutsu_interface_server::JujutsuInterfaceServer<Self> {
+        let mut service = JujutsuService {
+            store,
+            cache,
             sessions: Arc::new(Mutex::new(vec![])),
+            reserved: Arc::default(),
+            leases: Leases::default(),
+            vfs_mgr,
+            remotes,
+            remote_stores,
+            unrestored: vec![],
+        };
+        service.restore().await;
+        jujutsu_interface_server::JujutsuInterfaceServer::new(service)
+    }
+
+    /// Brings back the sessions saved in the cache. Working copies that can't be restored are
+    /// left out and reported in the daemon status.
+    async fn restore(&mut self) {
+        let saved = match SavedSessions::load(&self.cache).await {
+            Ok(saved) => saved,
+            Err(err) => {
+                error!("Could not load sessions: {err}");
+                self.unrestored.push(daemon_status_reply::Unrestored {
+                    path: self.cache.display().to_string(),
+                    error: format!("Could not load sessions: {err}"),
+                });
+                return;
+            }
+        };
+        let mut sessions = vec![];
+        for saved in saved.sessions {
+            let store = async { self.repo_store(&self.remotes.resolve(&saved.remote)?).await };
+            let store = match store.await {
+                Ok(store) => store,
+                Err(err) => {
+                    error!("Could not open the store of {}: {err:#}", saved.path);
+                    for path in saved.workspaces {
+                        self.unrestored.push(daemon_status_reply::Unrestored {
+                            path,
+                            error: format!("Could not open the store of the repo: {err:#}"),
+                        });
+                    }
+                    continue;
+                }
+            };
+            let mut workspaces = vec![];
+            for path in saved.workspaces {
+                match self.restore_workspace(&path, &store).await {
+                    Ok(workspace) => workspaces.push(workspace),
+                    Err(err) => {
+                        error!("Could not restore working copy at {path}: {err:#}");
+                        self.unrestored.push(daemon_status_reply::Unrestored {
+                            path,
+                            error: format!("{err:#}"),
+                        });
+                    }
+                }
+            }
+            if !workspaces.is_empty() {
+                sessions.push(Session {
+                    remote: saved.remote,
+                    store,
+                    path: saved.path,
+                    workspaces,
+                });
+            }
+        }
+        info!("Restored {} repos", sessions.len());
+        *self.sessions.lock().await = sessions;
+    }
+
+    async fn restore_workspace(&self, path: &str, store: &Store) -> anyhow::Result<Workspace> {
+        let dir = self.working_copy_dir(path);
+        let mut state = WorkingCopyState::load(&dir)
+            .await
+            .with_context(|| format!("Could not load state from {}", dir.display()))?;
+        let vfs = VirtualFileSystem::new(store.clone(), state.tree_id, dir.join("overlay"))
+            .await
+            .context("Could not open overlay")?;
+        // The overlay saves its tree along with its changes, so it is ahead of the state if the
+        // daemon stopped in between.
+        state.tree_id = vfs.root_tree();
+        vfs.set_sparse_patterns(state.sparse_patterns.clone())
+            .await
+            .context("Could not apply sparse patterns")?;
+        let nfs_port = self
+            .vfs_mgr
+            .bind(path.to_string(), vfs.clone())
+            .await
+            .context("Could not serve over NFS")?;
+        Ok(Workspace {
+            path: path.to_string(),
+            vfs,
+            nfs_port,
+            state,
+        })
+    }
+
+    /// Where the objects of a repo from `remote` are kept.
+    async fn repo_store(&self, remote: &Remote) -> anyhow::Result<Store> {
+        match remote {
+            Remote::Local => Ok(self.store.clone()),
+            Remote::Repo(repo) => self.remote_stores.store(repo).await,
+        }
+    }
+
+    /// The store a store request is for, that of the repo named in its
+    /// [`proto::REPO_PATH_METADATA`]. Requests may only leave the repo out while every repo keeps
+    /// its objects in the daemon's own store.
+    async fn request_store<T>(&self, request: &Request<T>) -> Result<Store, Status> {
+        let Some(path) = request.metadata().get_bin(proto::REPO_PATH_METADATA) else {
+            let sessions = self.sessions.lock().await;
+            let remote_repos = sessions
+                .iter()
+                .any(|session| !matches!(self.remotes.resolve(&session.remote), Ok(Remote::Local)));
+            if remote_repos {
+                return Err(Status::invalid_argument(format!(
+                    "Store requests have to name their repo in {} metadata",
+                    proto::REPO_PATH_METADATA
+                )));
+            }
+            return Ok(self.store.clone());
+        };
+        let path = path
+            .to_bytes()
+            .ok()
+            .and_then(|path| String::from_utf8(path.to_vec()).ok())
+            .ok_or_else(|| Status::invalid_argument("Invalid repo path"))?;
+        let sessions = self.sessions.lock().await;
+        sessions
+            .iter()
+            .find(|session| session.path == path)
+            .map(|session| session.store.clone())
+            .ok_or_else(|| Status::not_found(format!("No repo at {path}")))
+    }
+
+    /// The store of the repo checked out in the working copy at `path`.
+    async fn working_copy_store(&self, path: &str) -> Result<Store, Status> {
+        let sessions = self.sessions.lock().await;
+        sessions
+            .iter()
+            .find(|session| {
+                session
+                    .workspaces
+                    .iter()
+                    .any(|workspace| workspace.path == path)
+            })
+            .map(|session| session.store.clone())
+            .ok_or_else(|| working_copy_not_found(path))
+    }
+
+    /// Records which working copies are served, so they can be restored after a restart.
+    async fn save_sessions(&self, sessions: &[Session]) -> Result<(), Status> {
+        let saved = SavedSessions {
+            sessions: sessions
+                .iter()
+                .map(|session| SavedSession {
+                    remote: session.remote.clone(),
+                    path: session.path.clone(),
+                    workspaces: session
+                        .workspaces
+                        .iter()
+                        .map(|workspace| workspace.path.clone())
+                        .collect(),
+                })
+                .collect(),
+        };
+        saved
+            .save(&self.cache)
+            .await
+            .map_err(|err| Status::internal(format!("Could not save sessions: {err}")))
+    }
+
+    /// State for the working copy at `path` lives in `<cache>/working_copies/<hash of path>/`.
+    fn working_copy_dir(&self, path: &str) -> PathBuf {
+        self.cache
+            .join("working_copies")
+            .join(blake3::hash(path.as_bytes()).to_hex().as_str())
+    }
+
+    async fn workspace(&self, path: &str) -> Result<Workspace, Status> {
+        let sessions = self.sessions.lock().await;
+        sessions
+            .iter()
+            .flat_map(|session| &session.workspaces)
+            .find(|workspace| workspace.path == path)
+            .cloned()
+            .ok_or_else(|| working_copy_not_found(path))
+    }
+
+    /// Sets up a working copy at `path` from scratch, whatever was left behind at the same path
+    /// before, and serves it over NFS.
+    async fn new_workspace(
+        &self,
+        path: String,
+        state: WorkingCopyState,
+        store: &Store,
+    ) -> Result<Workspace, Status> {
+        let dir = self.working_copy_dir(&path);
+        let setup = async {
+            match tokio::fs::remove_dir_all(&dir).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+            tokio::fs::create_dir_all(&dir).await?;
+            state.save(&dir).await?;
+            VirtualFileSystem::new(store.clone(), state.tree_id, dir.join("overlay")).await
+        };
+        let vfs = setup.await.map_err(|err| {
+            Status::internal(format!(
+                "Could not set up working copy in {}: {err}",
+                dir.display()
+            ))
+        })?;
+        let nfs_port = self
+            .vfs_mgr
+            .bind(path.clone(), vfs.clone())
+            .await
+            .map_err(|err| Status::internal(format!("Could not serve {path} over NFS: {err:#}")))?;
+        Ok(Workspace {
+            path,
+            vfs,
+            nfs_port,
+            state,
+        })
+    }
+
+    /// Changes the recorded state of the working copy at `path` and persists it.
+    async fn update_state(
+        &self,
+        path: &str,
+        update: impl FnOnce(&mut WorkingCopyState),
+    ) -> Result<WorkingCopyState, Status> {
+        let mut sessions = self.sessions.lock().await;
+        let workspace = sessions
+            .iter_mut()
+            .flat_map(|session| &mut session.workspaces)
+            .find(|workspace| workspace.path == path)
+            .ok_or_else(|| working_copy_not_found(path))?;
+        let mut state = workspace.state.clone();
+        update(&mut state);
+        state
+            .save(&self.working_copy_dir(path))
+            .await
+            .map_err(|err| Status::internal(format!("Could not save working copy state: {err}")))?;
+        workspace.state = state.clone();
+        Ok(state)
+    }
+
+    /// The working copy at `path`, to be changed by the holder of lease `lease_id`. Changes are
+    /// only taken from whoever holds the lock on the working copy.
+    async fn locked_workspace(&self, path: &str, lease_id: u64) -> Result<Workspace, Status> {
+        let workspace = self.workspace(path).await?;
+        if !self.leases.holds(path, lease_id) {
+            return Err(Status::permission_denied(format!(
+                "Lease {lease_id} does not hold the lock on {path}"
+            )));
+        }
+        Ok(workspace)
+    }
+
+    /// Points the working copy at another tree without changing what it shows, see
+    /// [`VirtualFileSystem::reset`].
+    async fn reset_working_copy(&self, req: ResetReq, recover: bool) -> Result<(), Status> {
+        let tree_id = Id::try_from(req.tree_id.as_slice())
+            .map_err(|err| invalid_id(ObjectKind::Tree, &req.tree_id, err))?;
+        self.working_copy_store(&req.working_copy_path)
+            .await?
+            .get_tree(tree_id)
+            .await
+            .map_err(|err| read_error(ObjectKind::Tree, tree_id, err))?
+            .ok_or_else(|| not_found(ObjectKind::Tree, tree_id))?;
+
+        let workspace = self
+            .locked_workspace(&req.working_copy_path, req.lease_id)
+            .await?;
+        if recover {
+            workspace.vfs.recover(tree_id).await
+        } else {
+            workspace.vfs.reset(tree_id).await
+        }
+        .map_err(tree_walk_error)?;
+        self.update_state(&req.working_copy_path, |state| state.tree_id = tree_id)
+            .await?;
+        Ok(())
+    }
+}
+
+/// A working copy path that is being set up. Nothing else can be checked out there until the
+/// reservation is dropped.
+struct Reservation {
+    reserved: Arc<parking_lot::Mutex<HashSet<String>>>,
+    path: String,
+}
+
+impl Reservation {
+    /// Reserves `path` unless a workspace is at it or it is reserved already.
+    fn new(service: &JujutsuService, sessions: &[Session], path: &str) -> Option<Reservation> {
+        if is_checked_out(sessions, path) || !service.reserved.lock().insert(path.to_string()) {
+            return None;
+        }
+        Some(Reservation {
+            reserved: service.reserved.clone(),
+            path: path.to_string(),
         })
     }
 }
 
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.reserved.lock().remove(&self.path);
+    }
+}
+
+/// Sends the lease once it is granted, then stays open for as long as the client holds the lock.
+pub struct LockStream {
+    reply: Option<AcquireLockReply>,
+    _lease: Lease,
+}
+
+impl Stream for LockStream {
+    type Item = Result<AcquireLockReply, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.reply.take() {
+            Some(reply) => Poll::Ready(Some(Ok(reply))),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Sparse patterns are directories relative to the working copy root, or the empty path.
+fn is_valid_sparse_pattern(pattern: &str) -> bool {
+    pattern.is_empty()
+        || pattern
+            .split('/')
+            .all(|name| !name.is_empty() && name != "." && name != "..")
+}
+
+/// Whether any workspace of any repo is at `path`.
+fn is_checked_out(sessions: &[Session], path: &str) -> bool {
+    sessions
+        .iter()
+        .flat_map(|session| &session.workspaces)
+        .any(|workspace| workspace.path == path)
+}
+
+fn working_copy_not_found(path: &str) -> Status {
+    Status::not_found(format!("No working copy at {path}"))
+}
+
+fn store_error(err: std::io::Error) -> Status {
+    let code = match err.kind() {
+        std::io::ErrorKind::InvalidData => Code::DataLoss,
+        std::io::ErrorKind::NotConnected | std::io::ErrorKind::TimedOut => Code::Unavailable,
+        _ => Code::Internal,
+    };
+    Status::new(code, format!("Store error: {err}"))
+}
+
+/// Like [`store_error`], except that a tree missing from the store while updating a working
+/// copy is reported as not found.
+fn tree_walk_error(err: std::io::Error) -> Status {
+    if err.kind() == std::io::ErrorKind::NotFound {
+        return Status::not_found(err.to_string());
+    }
+    store_error(err)
+}
+
+/// Attaches an [`ObjectError`] naming the object the failed request was about.
+fn object_status(code: Code, kind: ObjectKind, id: &[u8], message: impl Display) -> Status {
+    let details = ObjectError {
+        object_kind: kind.name().to_string(),
+        id: ty::hex(id),
+    };
+    Status::with_details(code, message.to_string(), details.encode_to_vec().into())
+}
+
+fn invalid_id(kind: ObjectKind, id: &[u8], err: ty::ProtoError) -> Status {
+    object_status(
+        Code::InvalidArgument,
+        kind,
+        id,
+        format!("Invalid {} id: {err}", kind.name()),
+    )
+}
+
+fn not_found(kind: ObjectKind, id: Id) -> Status {
+    object_status(
+        Code::NotFound,
+        kind,
+        &id.0,
+        format!("{} {} not found", kind.name(), id.hex()),
+    )
+}
+
+fn read_error(kind: ObjectKind, id: Id, err: std::io::Error) -> Status {
+    let status = store_error(err);
+    object_status(status.code(), kind, &id.0, status.message())
+}
+
+fn invalid_argument(err: ty::ProtoError) -> Status {
+    Status::invalid_argument(format!("Invalid request: {err}"))
+}
+
 #[tonic::async_trait]
 impl jujutsu_interface_server::JujutsuInterface for JujutsuService {
     #[tracing::instrument(skip(self))]
@@ -39,12 +491,167 @@ impl jujutsu_interface_server::JujutsuInterface for JujutsuService {
             "Initializing a new repo at {} for {}",
             &req.path, &req.remote
         );
+        let remote = self
+            .remotes
+            .resolve(&req.remote)
+            .map_err(|err| Status::invalid_argument(format!("{err}")))?;
+        let store = self.repo_store(&remote).await.map_err(|err| {
+            Status::internal(format!(
+                "Could not open the store of {}: {err:#}",
+                req.remote
+            ))
+        })?;
+        // Serving the working copy takes a while, so other requests are not held up meanwhile.
+        let _reservation = {
+            let sessions = self.sessions.lock().await;
+            Reservation::new(self, &sessions, &req.path).ok_or_else(|| {
+                Status::already_exists(format!("A repo is already initialized at {}", req.path))
+            })?
+        };
+        let state = WorkingCopyState::new(self.store.get_empty_tree_id());
+        let workspace = self.new_workspace(req.path.clone(), state, &store).await?;
+        let nfs_port = workspace.nfs_port.into();
         let mut sessions = self.sessions.lock().await;
         sessions.push(Session {
             remote: req.remote,
+            store,
             path: req.path,
+            workspaces: vec![workspace],
         });
-        Ok(Response::new(InitializeReply {}))
+        self.save_sessions(&sessions).await?;
+        Ok(Response::new(InitializeReply { nfs_port }))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn unmount(
+        &self,
+        request: Request<UnmountReq>,
+    ) -> Result<Response<UnmountReply>, Status> {
+        let req = request.into_inner();
+        info!("Unmounting {}", &req.working_copy_path);
+        let workspace = self.workspace(&req.working_copy_path).await?;
+        // Let commands that are working on the working copy finish first.
+        let _lease = self.leases.acquire(&req.working_copy_path).await;
+        // The overlay goes away along with the working copy.
+        if !req.force && workspace.vfs.has_changes().await {
+            return Err(Status::failed_precondition(format!(
+                "{} has changes that were not snapshotted",
+                req.working_copy_path
+            )));
+        }
+        workspace.vfs.flush().await.map_err(store_error)?;
+        self.vfs_mgr
+            .unmount(req.working_copy_path.clone())
+            .await
+            .map_err(|err| {
+                Status::internal(format!(
+                    "Could not stop serving {} over NFS: {err:#}",
+                    req.working_copy_path
+                ))
+            })?;
+
+        let mut sessions = self.sessions.lock().await;
+        for session in sessions.iter_mut() {
+            session
+                .workspaces
+                .retain(|workspace| workspace.path != req.working_copy_path);
+        }
+        sessions.retain(|session| !session.workspaces.is_empty());
+        self.save_sessions(&sessions).await?;
+        drop(sessions);
+
+        // Nothing serves the overlay and state anymore.
+        let dir = self.working_copy_dir(&req.working_copy_path);
+        match tokio::fs::remove_dir_all(&dir).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(Status::internal(format!(
+                    "Could not remove {}: {err}",
+                    dir.display()
+                )))
+            }
+            _ => {}
+        }
+        Ok(Response::new(UnmountReply {}))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn remount(
+        &self,
+        request: Request<RemountReq>,
+    ) -> Result<Response<RemountReply>, Status> {
+        let req = request.into_inner();
+        info!("Remounting {}", &req.working_copy_path);
+        let workspace = self.workspace(&req.working_copy_path).await?;
+        let nfs_port = self
+            .vfs_mgr
+            .remount(req.working_copy_path.clone(), workspace.vfs)
+            .await
+            .map_err(|err| {
+                Status::internal(format!(
+                    "Could not serve {} over NFS: {err:#}",
+                    req.working_copy_path
+                ))
+            })?;
+        let mut sessions = self.sessions.lock().await;
+        if let Some(workspace) = sessions
+            .iter_mut()
+            .flat_map(|session| &mut session.workspaces)
+            .find(|workspace| workspace.path == req.working_copy_path)
+        {
+            workspace.nfs_port = nfs_port;
+        }
+        self.save_sessions(&sessions).await?;
+        Ok(Response::new(RemountReply {
+            nfs_port: nfs_port.into(),
+        }))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn add_workspace(
+        &self,
+        request: Request<AddWorkspaceReq>,
+    ) -> Result<Response<AddWorkspaceReply>, Status> {
+        let req = request.into_inner();
+        info!(
+            "Adding workspace {} of {} at {}",
+            &req.workspace_id, &req.repo_path, &req.working_copy_path
+        );
+        let no_repo = || Status::not_found(format!("No repo at {}", req.repo_path));
+        let (_reservation, store) = {
+            let sessions = self.sessions.lock().await;
+            let reservation = Reservation::new(self, &sessions, &req.working_copy_path)
+                .ok_or_else(|| {
+                    Status::already_exists(format!(
+                        "A repo is already checked out at {}",
+                        req.working_copy_path
+                    ))
+                })?;
+            let session = sessions
+                .iter()
+                .find(|session| session.path == req.repo_path)
+                .ok_or_else(no_repo)?;
+            (reservation, session.store.clone())
+        };
+        let mut state = WorkingCopyState::new(self.store.get_empty_tree_id());
+        state.workspace_id = req.workspace_id;
+        let workspace = self
+            .new_workspace(req.working_copy_path.clone(), state, &store)
+            .await?;
+        let nfs_port = workspace.nfs_port.into();
+        let mut sessions = self.sessions.lock().await;
+        let Some(session) = sessions
+            .iter_mut()
+            .find(|session| session.path == req.repo_path)
+        else {
+            // The repo's last workspace was unmounted meanwhile.
+            if let Err(err) = self.vfs_mgr.unmount(req.working_copy_path).await {
+                error!("Could not stop serving the new workspace: {err:#}");
+            }
+            return Err(no_repo());
+        };
+        session.workspaces.push(workspace);
+        self.save_sessions(&sessions).await?;
+        Ok(Response::new(AddWorkspaceReply { nfs_port }))
     }
 
     #[tracing::instrument(skip(self))]
@@ -53,16 +660,42 @@ impl jujutsu_interface_server::JujutsuInterface for JujutsuService {
         request: Request<DaemonStatusReq>,
     ) -> Result<Response<DaemonStatusReply>, Status> {
         let _req = request.into_inner();
+        let sync_status = self
+            .store
+            .sync_status()
+            .await
+            .merge(self.remote_stores.sync_status().await);
         let sessions = self.sessions.lock().await;
         let data = sessions
             .clone()
             .into_iter()
             .map(|sess| proto::jj_interface::daemon_status_reply::Data {
+                // The workspace the repo was initialized in may have been unmounted.
+                nfs_port: sess
+                    .workspaces
+                    .iter()
+                    .find(|workspace| workspace.path == sess.path)
+                    .map_or(0, |workspace| workspace.nfs_port.into()),
                 path: sess.path,
                 remote: sess.remote,
+                workspaces: sess
+                    .workspaces
+                    .into_iter()
+                    .map(|workspace| daemon_status_reply::Workspace {
+                        workspace_id: workspace.state.workspace_id,
+                        path: workspace.path,
+                        nfs_port: workspace.nfs_port.into(),
+                    })
+                    .collect(),
             })
             .collect();
-        Ok(Response::new(DaemonStatusReply { data }))
+        Ok(Response::new(DaemonStatusReply {
+            data,
+            unrestored: self.unrestored.clone(),
+            pending_uploads: sync_status.pending_uploads,
+            rejected_uploads: sync_status.rejected_uploads,
+            offline: sync_status.offline,
+        }))
     }
 
     #[tracing::instrument(skip(self))]
@@ -77,15 +710,27 @@ impl jujutsu_interface_server::JujutsuInterface for JujutsuService {
 
     #[tracing::instrument(skip(self))]
     async fn write_file(&self, request: Request<File>) -> Result<Response<FileId>, Status> {
+        let store = self.request_store(&request).await?;
         let file = request.into_inner();
-        let file_id = self.store.write_file(file.into()).await.into();
+        let file_id = store
+            .write_file(file.into())
+            .await
+            .map_err(store_error)?
+            .into();
         Ok(Response::new(FileId { file_id }))
     }
 
     #[tracing::instrument(skip(self))]
     async fn read_file(&self, request: Request<FileId>) -> Result<Response<File>, Status> {
-        let file_id: Id = request.into_inner().into();
-        let file = self.store.get_file(file_id).unwrap();
+        let store = self.request_store(&request).await?;
+        let file_id = request.into_inner().file_id;
+        let file_id = Id::try_from(file_id.as_slice())
+            .map_err(|err| invalid_id(ObjectKind::File, &file_id, err))?;
+        let file = store
+            .get_file(file_id)
+            .await
+            .map_err(|err| read_error(ObjectKind::File, file_id, err))?
+            .ok_or_else(|| not_found(ObjectKind::File, file_id))?;
         Ok(Response::new(file.as_proto()))
     }
 
@@ -94,48 +739,115 @@ impl jujutsu_interface_server::JujutsuInterface for JujutsuService {
         &self,
         request: Request<Symlink>,
     ) -> Result<Response<SymlinkId>, Status> {
+        let store = self.request_store(&request).await?;
         let symlink = request.into_inner();
-        let symlink_id = self.store.write_symlink(symlink.into()).await.into();
+        let symlink_id = store
+            .write_symlink(symlink.into())
+            .await
+            .map_err(store_error)?
+            .into();
         Ok(Response::new(SymlinkId { symlink_id }))
     }
 
     #[tracing::instrument(skip(self))]
     async fn read_symlink(&self, request: Request<SymlinkId>) -> Result<Response<Symlink>, Status> {
-        let symlink_id: Id = request.into_inner().into();
-        let symlink = self.store.get_symlink(symlink_id).unwrap();
+        let store = self.request_store(&request).await?;
+        let symlink_id = request.into_inner().symlink_id;
+        let symlink_id = Id::try_from(symlink_id.as_slice())
+            .map_err(|err| invalid_id(ObjectKind::Symlink, &symlink_id, err))?;
+        let symlink = store
+            .get_symlink(symlink_id)
+            .await
+            .map_err(|err| read_error(ObjectKind::Symlink, symlink_id, err))?
+            .ok_or_else(|| not_found(ObjectKind::Symlink, symlink_id))?;
         Ok(Response::new(symlink.as_proto()))
     }
 
     #[tracing::instrument(skip(self))]
     async fn write_tree(&self, request: Request<Tree>) -> Result<Response<TreeId>, Status> {
+        let store = self.request_store(&request).await?;
         let tree = request.into_inner();
-        let tree_id = self.store.write_tree(tree.into()).await.into();
+        let tree_id = store
+            .write_tree(tree.try_into().map_err(invalid_argument)?)
+            .await
+            .map_err(store_error)?
+            .into();
         Ok(Response::new(TreeId { tree_id }))
     }
 
     #[tracing::instrument(skip(self))]
     async fn read_tree(&self, request: Request<TreeId>) -> Result<Response<Tree>, Status> {
-        let tree_id: Id = request.into_inner().into();
-        let tree = self.store.get_tree(tree_id).unwrap();
+        let store = self.request_store(&request).await?;
+        let tree_id = request.into_inner().tree_id;
+        let tree_id = Id::try_from(tree_id.as_slice())
+            .map_err(|err| invalid_id(ObjectKind::Tree, &tree_id, err))?;
+        let tree = store
+            .get_tree(tree_id)
+            .await
+            .map_err(|err| read_error(ObjectKind::Tree, tree_id, err))?
+            .ok_or_else(|| not_found(ObjectKind::Tree, tree_id))?;
         Ok(Response::new(tree.as_proto()))
     }
 
     #[tracing::instrument(skip(self))]
     async fn write_commit(&self, request: Request<Commit>) -> Result<Response<CommitId>, Status> {
+        let store = self.request_store(&request).await?;
         let commit = request.into_inner();
         if commit.parents.is_empty() {
             return Err(Status::internal("Cannot write a commit with no parents"));
         }
-        let commit_id = self.store.write_commit(commit.into()).await.into();
+        let commit_id = store
+            .write_commit(commit.try_into().map_err(invalid_argument)?)
+            .await
+            .map_err(store_error)?
+            .into();
         Ok(Response::new(CommitId { commit_id }))
     }
 
     #[tracing::instrument(skip(self))]
     async fn read_commit(&self, request: Request<CommitId>) -> Result<Response<Commit>, Status> {
-        let commit_id: Id = request.into_inner().into();
-        let commits = self.store.commits.lock();
-        let commit = commits.get(&commit_id).unwrap();
-        Ok(Response::new((*commit).as_proto()))
+        let store = self.request_store(&request).await?;
+        let commit_id = request.into_inner().commit_id;
+        let commit_id = Id::try_from(commit_id.as_slice())
+            .map_err(|err| invalid_id(ObjectKind::Commit, &commit_id, err))?;
+        let commit = store
+            .get_commit(commit_id)
+            .await
+            .map_err(|err| read_error(ObjectKind::Commit, commit_id, err))?
+            .ok_or_else(|| not_found(ObjectKind::Commit, commit_id))?;
+        Ok(Response::new(commit.as_proto()))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn write_conflict(
+        &self,
+        request: Request<Conflict>,
+    ) -> Result<Response<ConflictId>, Status> {
+        let store = self.request_store(&request).await?;
+        let conflict = request.into_inner();
+        let conflict_id = store
+            .write_conflict(conflict.try_into().map_err(invalid_argument)?)
+            .await
+            .map_err(store_error)?
+            .into();
+        Ok(Response::new(ConflictId { conflict_id }))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn read_conflict(
+        &self,
+        request: Request<ConflictId>,
+    ) -> Result<Response<Conflict>, Status> {
+        let store = self.request_store(&request).await?;
+        let conflict_id = request.into_inner().conflict_id;
+        let conflict_id = Id::try_from(conflict_id.as_slice())
+            .map_err(|err| invalid_id(ObjectKind::Conflict, &conflict_id, err))?;
+        let conflict = store
+            .get_conflict(conflict_id)
+            .await
+            .map_err(|err| read_error(ObjectKind::Conflict, conflict_id, err))?
+            .ok_or_else(|| not_found(ObjectKind::Conflict, conflict_id))?;
+        Ok(Response::new(conflict.as_proto()))
     }
 
     #[tracing::instrument(skip(self))]
@@ -144,8 +856,12 @@ impl jujutsu_interface_server::JujutsuInterface for JujutsuService {
         request: Request<GetTreeStateReq>,
     ) -> Result<Response<GetTreeStateReply>, Status> {
         info!("Getting tree state");
-        let _req = request.into_inner();
-        todo!()
+        let req = request.into_inner();
+        let workspace = self.workspace(&req.working_copy_path).await?;
+        Ok(Response::new(GetTreeStateReply {
+            tree_id: workspace.state.tree_id.into(),
+            sparse_patterns: workspace.state.sparse_patterns,
+        }))
     }
 
     #[tracing::instrument(skip(self))]
@@ -154,8 +870,18 @@ impl jujutsu_interface_server::JujutsuInterface for JujutsuService {
         request: Request<GetCheckoutStateReq>,
     ) -> Result<Response<CheckoutState>, Status> {
         info!("Getting checkout state");
-        let _req = request.into_inner();
-        todo!()
+        let req = request.into_inner();
+        let workspace = self.workspace(&req.working_copy_path).await?;
+        let op_id = ty::from_hex(&workspace.state.op_id).ok_or_else(|| {
+            Status::data_loss(format!(
+                "Invalid operation id {:?} in the state of {}",
+                workspace.state.op_id, req.working_copy_path
+            ))
+        })?;
+        Ok(Response::new(CheckoutState {
+            op_id,
+            workspace_id: workspace.state.workspace_id.into_bytes(),
+        }))
     }
 
     #[tracing::instrument(skip(self))]
@@ -163,8 +889,166 @@ impl jujutsu_interface_server::JujutsuInterface for JujutsuService {
         &self,
         request: Request<SetCheckoutStateReq>,
     ) -> Result<Response<SetCheckoutStateReply>, Status> {
-        let _req = request.into_inner();
-        todo!()
+        let req = request.into_inner();
+        self.locked_workspace(&req.working_copy_path, req.lease_id)
+            .await?;
+        let checkout_state = req
+            .checkout_state
+            .ok_or(ty::ProtoError::MissingField("checkout_state"))
+            .map_err(invalid_argument)?;
+        let workspace_id = String::from_utf8(checkout_state.workspace_id)
+            .map_err(|_| Status::invalid_argument("Workspace id is not valid UTF-8"))?;
+        self.update_state(&req.working_copy_path, |state| {
+            state.op_id = ty::hex(&checkout_state.op_id);
+            state.workspace_id = workspace_id;
+        })
+        .await?;
+        Ok(Response::new(SetCheckoutStateReply {}))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn check_out(
+        &self,
+        request: Request<CheckOutReq>,
+    ) -> Result<Response<CheckOutReply>, Status> {
+        let req = request.into_inner();
+        let old_tree_id = Id::try_from(req.old_tree_id.as_slice())
+            .map_err(|err| invalid_id(ObjectKind::Tree, &req.old_tree_id, err))?;
+        let new_tree_id = Id::try_from(req.new_tree_id.as_slice())
+            .map_err(|err| invalid_id(ObjectKind::Tree, &req.new_tree_id, err))?;
+        self.working_copy_store(&req.working_copy_path)
+            .await?
+            .get_tree(new_tree_id)
+            .await
+            .map_err(|err| read_error(ObjectKind::Tree, new_tree_id, err))?
+            .ok_or_else(|| not_found(ObjectKind::Tree, new_tree_id))?;
+
+        let workspace = self
+            .locked_workspace(&req.working_copy_path, req.lease_id)
+            .await?;
+        let stats = workspace
+            .vfs
+            .check_out(old_tree_id, new_tree_id)
+            .await
+            .map_err(tree_walk_error)?
+            .ok_or_else(|| {
+                Status::failed_precondition(format!(
+                    "Concurrent checkout: the working copy is at tree {}, not {}",
+                    workspace.vfs.root_tree().hex(),
+                    old_tree_id.hex()
+                ))
+            })?;
+        self.update_state(&req.working_copy_path, |state| state.tree_id = new_tree_id)
+            .await?;
+        Ok(Response::new(CheckOutReply {
+            updated_files: stats.updated_files,
+            added_files: stats.added_files,
+            removed_files: stats.removed_files,
+            skipped_files: stats.skipped_files,
+        }))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn reset(&self, request: Request<ResetReq>) -> Result<Response<ResetReply>, Status> {
+        self.reset_working_copy(request.into_inner(), false).await?;
+        Ok(Response::new(ResetReply {}))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn recover(&self, request: Request<ResetReq>) -> Result<Response<ResetReply>, Status> {
+        self.reset_working_copy(request.into_inner(), true).await?;
+        Ok(Response::new(ResetReply {}))
+    }
+
+    type AcquireLockStream = LockStream;
+
+    #[tracing::instrument(skip(self))]
+    async fn acquire_lock(
+        &self,
+        request: Request<AcquireLockReq>,
+    ) -> Result<Response<LockStream>, Status> {
+        let req = request.into_inner();
+        self.workspace(&req.working_copy_path).await?;
+        let lease = self.leases.acquire(&req.working_copy_path).await;
+        info!("Lease {} acquired", lease.id());
+        Ok(Response::new(LockStream {
+            reply: Some(AcquireLockReply {
+                lease_id: lease.id(),
+            }),
+            _lease: lease,
+        }))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn release_lock(
+        &self,
+        request: Request<ReleaseLockReq>,
+    ) -> Result<Response<ReleaseLockReply>, Status> {
+        let req = request.into_inner();
+        if !self.leases.release(&req.working_copy_path, req.lease_id) {
+            return Err(Status::not_found(format!(
+                "Lease {} does not hold the lock on {}",
+                req.lease_id, req.working_copy_path
+            )));
+        }
+        Ok(Response::new(ReleaseLockReply {}))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn set_sparse_patterns(
+        &self,
+        request: Request<SetSparsePatternsReq>,
+    ) -> Result<Response<CheckOutReply>, Status> {
+        let req = request.into_inner();
+        if let Some(pattern) = req
+            .sparse_patterns
+            .iter()
+            .find(|pattern| !is_valid_sparse_pattern(pattern))
+        {
+            return Err(Status::invalid_argument(format!(
+                "Invalid sparse pattern {pattern:?}"
+            )));
+        }
+        let workspace = self
+            .locked_workspace(&req.working_copy_path, req.lease_id)
+            .await?;
+        let stats = workspace
+            .vfs
+            .set_sparse_patterns(req.sparse_patterns.clone())
+            .await
+            .map_err(store_error)?;
+        self.update_state(&req.working_copy_path, |state| {
+            state.sparse_patterns = req.sparse_patterns
+        })
+        .await?;
+        Ok(Response::new(CheckOutReply {
+            updated_files: stats.updated_files,
+            added_files: stats.added_files,
+            removed_files: stats.removed_files,
+            skipped_files: stats.skipped_files,
+        }))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_new_files(
+        &self,
+        request: Request<GetNewFilesReq>,
+    ) -> Result<Response<GetNewFilesReply>, Status> {
+        let req = request.into_inner();
+        let workspace = self.workspace(&req.working_copy_path).await?;
+        let new_files = workspace.vfs.new_files().await.map_err(store_error)?;
+        Ok(Response::new(GetNewFilesReply {
+            new_files: new_files
+                .files
+                .into_iter()
+                .map(|(path, size)| get_new_files_reply::NewFile { path, size })
+                .collect(),
+            gitignores: new_files
+                .gitignores
+                .into_iter()
+                .map(|(dir, contents)| get_new_files_reply::GitIgnore { dir, contents })
+                .collect(),
+        }))
     }
 
     #[tracing::instrument(skip(self))]
@@ -172,8 +1056,21 @@ impl jujutsu_interface_server::JujutsuInterface for JujutsuService {
         &self,
         request: Request<SnapshotReq>,
     ) -> Result<Response<SnapshotReply>, Status> {
-        let _req = request.into_inner();
-        todo!()
+        let req = request.into_inner();
+        let workspace = self
+            .locked_workspace(&req.working_copy_path, req.lease_id)
+            .await?;
+        let untracked: BTreeSet<_> = req.untracked_paths.into_iter().collect();
+        let tree_id = workspace
+            .vfs
+            .snapshot(&untracked)
+            .await
+            .map_err(store_error)?;
+        self.update_state(&req.working_copy_path, |state| state.tree_id = tree_id)
+            .await?;
+        Ok(Response::new(SnapshotReply {
+            tree_id: tree_id.into(),
+        }))
     }
 }
 
@@ -182,17 +1079,63 @@ mod tests {
     const COMMIT_ID_LENGTH: usize = 32;
     const CHANGE_ID_LENGTH: usize = 16;
 
+    use std::{path::Path, time::Duration};
+
     use assert_matches::assert_matches;
     use proto::jj_interface::jujutsu_interface_server::JujutsuInterface;
+    use tokio_stream::StreamExt;
+    use tonic::metadata::MetadataValue;
 
     use super::*;
+    use crate::{
+        object_store::{InMemoryObjectStore, ObjectStore},
+        vfs_mgr::{VfsManager, VfsManagerConfig},
+    };
 
-    #[tokio::test]
-    async fn write_commit_parents() {
-        let svc = JujutsuService {
-            store: Store::new(),
+    /// A service with its cache in a temporary directory, which lives as long as the service.
+    async fn service(objects: Arc<InMemoryObjectStore>) -> (JujutsuService, tempfile::TempDir) {
+        let cache = tempfile::tempdir().unwrap();
+        let svc = service_in(objects, cache.path()).await;
+        (svc, cache)
+    }
+
+    /// A service that restores what was served out of `cache` before.
+    async fn service_in(objects: Arc<InMemoryObjectStore>, cache: &Path) -> JujutsuService {
+        let mut vfs_mgr = VfsManager::new(VfsManagerConfig {
+            min_nfs_port: 30000,
+            max_nfs_port: 40000,
+            ports_path: cache.join("nfs_ports.toml"),
+        })
+        .await
+        .unwrap();
+        let vfs_mgr_handle = vfs_mgr.handle();
+        tokio::spawn(async move { vfs_mgr.serve().await });
+        let mut svc = JujutsuService {
+            store: Store::new(objects).await.unwrap(),
+            cache: cache.to_path_buf(),
             sessions: Arc::new(Mutex::new(vec![])),
+            reserved: Arc::default(),
+            leases: Leases::default(),
+            vfs_mgr: vfs_mgr_handle,
+            remotes: Remotes::default(),
+            remote_stores: RemoteStores::with_connect(
+                cache.join("remotes"),
+                Arc::new(|_, _| Ok(Arc::new(InMemoryObjectStore::default()))),
+            )
+            .await,
+            unrestored: vec![],
         };
+        svc.restore().await;
+        svc
+    }
+
+    fn object_error(status: &Status) -> ObjectError {
+        ObjectError::decode(status.details()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn write_commit_parents() {
+        let (svc, _cache) = service(Arc::new(InMemoryObjectStore::default())).await;
         let mut commit = Commit::default();
 
         // No parents
@@ -258,4 +1201,1104 @@ mod tests {
             .into_inner();
         assert_eq!(root_merge_commit, commit);
     }
+
+    #[tokio::test]
+    async fn missing_objects_are_not_found() {
+        let (svc, _cache) = service(Default::default()).await;
+        let id = vec![3; 32];
+
+        let status = svc
+            .read_file(Request::new(FileId {
+                file_id: id.clone(),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(
+            object_error(&status),
+            ObjectError {
+                object_kind: "file".to_string(),
+                id: ty::hex(&id),
+            }
+        );
+
+        let status = svc
+            .read_commit(Request::new(CommitId { commit_id: id }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(object_error(&status).object_kind, "commit");
+    }
+
+    #[tokio::test]
+    async fn invalid_ids_are_rejected() {
+        let (svc, _cache) = service(Default::default()).await;
+
+        let status = svc
+            .read_tree(Request::new(TreeId {
+                tree_id: vec![1, 2, 3],
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            object_error(&status),
+            ObjectError {
+                object_kind: "tree".to_string(),
+                id: "010203".to_string(),
+            }
+        );
+
+        let tree = Tree {
+            entries: vec![tree::Entry {
+                name: "file".to_string(),
+                value: Some(TreeValue {
+                    value: Some(tree_value::Value::SymlinkId(vec![0; 4])),
+                }),
+            }],
+        };
+        let status = svc.write_tree(Request::new(tree)).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn corrupt_objects_are_data_loss() {
+        let objects = Arc::new(InMemoryObjectStore::default());
+        let (svc, _cache) = service(objects.clone()).await;
+        let id = Id([5; 32]);
+        objects
+            .put(ObjectKind::Tree, id, b"not a tree".to_vec())
+            .await
+            .unwrap();
+
+        let status = svc
+            .read_tree(Request::new(TreeId { tree_id: id.into() }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::DataLoss);
+        assert_eq!(object_error(&status).id, id.hex());
+    }
+
+    #[tokio::test]
+    async fn conflicts_round_trip() {
+        let (svc, _cache) = service(Default::default()).await;
+        let file = |id: u8| TreeValue {
+            value: Some(tree_value::Value::File(tree_value::File {
+                id: vec![id; 32],
+                executable: false,
+            })),
+        };
+        let conflict = Conflict {
+            removes: vec![conflict::Term {
+                content: Some(file(1)),
+            }],
+            adds: vec![
+                conflict::Term {
+                    content: Some(file(2)),
+                },
+                conflict::Term {
+                    content: Some(file(3)),
+                },
+            ],
+        };
+        let conflict_id = svc
+            .write_conflict(Request::new(conflict.clone()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            svc.read_conflict(Request::new(conflict_id.clone()))
+                .await
+                .unwrap()
+                .into_inner(),
+            conflict
+        );
+
+        // Trees can refer to the conflict.
+        let tree = Tree {
+            entries: vec![tree::Entry {
+                name: "file".to_string(),
+                value: Some(TreeValue {
+                    value: Some(tree_value::Value::ConflictId(conflict_id.conflict_id)),
+                }),
+            }],
+        };
+        let tree_id = svc
+            .write_tree(Request::new(tree.clone()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            svc.read_tree(Request::new(tree_id))
+                .await
+                .unwrap()
+                .into_inner(),
+            tree
+        );
+    }
+
+    #[tokio::test]
+    async fn signed_commits_keep_their_signature() {
+        let (svc, _cache) = service(Default::default()).await;
+        let unsigned = Commit {
+            parents: vec![vec![0; COMMIT_ID_LENGTH]],
+            ..Default::default()
+        };
+        let signed = Commit {
+            secure_sig: Some(b"signature".to_vec()),
+            ..unsigned.clone()
+        };
+
+        let signed_id = svc
+            .write_commit(Request::new(signed.clone()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            svc.read_commit(Request::new(signed_id.clone()))
+                .await
+                .unwrap()
+                .into_inner(),
+            signed
+        );
+
+        let unsigned_id = svc
+            .write_commit(Request::new(unsigned))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_ne!(signed_id, unsigned_id);
+    }
+
+    #[tokio::test]
+    async fn snapshot_the_mounted_working_copy() {
+        use nfsserve::vfs::NFSFileSystem;
+
+        let (svc, _cache) = service(Default::default()).await;
+        let lease = svc.leases.acquire("/repo").await;
+        let snapshot = |path: &str| {
+            svc.snapshot(Request::new(SnapshotReq {
+                working_copy_path: path.to_string(),
+                untracked_paths: vec![],
+                lease_id: lease.id(),
+            }))
+        };
+        assert_matches!(
+            snapshot("/repo").await,
+            Err(status) if status.code() == Code::NotFound
+        );
+
+        let initialize = InitializeReq {
+            path: "/repo".to_string(),
+            remote: "localhost".to_string(),
+        };
+        svc.initialize(Request::new(initialize.clone()))
+            .await
+            .unwrap();
+        assert_matches!(
+            svc.initialize(Request::new(initialize)).await,
+            Err(status) if status.code() == Code::AlreadyExists
+        );
+        let empty_tree_id: Vec<u8> = svc.store.get_empty_tree_id().into();
+        assert_eq!(
+            snapshot("/repo").await.unwrap().into_inner().tree_id,
+            empty_tree_id
+        );
+
+        let vfs = svc.workspace("/repo").await.unwrap().vfs;
+        let (file, _) = vfs
+            .create(
+                vfs.root_dir(),
+                &b"README".to_vec().into(),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        vfs.write(file, 0, b"hello").await.unwrap();
+        let tree_id = snapshot("/repo").await.unwrap().into_inner().tree_id;
+        let tree = svc
+            .read_tree(Request::new(TreeId {
+                tree_id: tree_id.clone(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(tree.entries.len(), 1);
+        assert_eq!(tree.entries[0].name, "README");
+
+        // Files the client leaves untracked stay out of snapshots.
+        let (file, _) = vfs
+            .create(
+                vfs.root_dir(),
+                &b"notes".to_vec().into(),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        vfs.write(file, 0, b"todo").await.unwrap();
+        let new_files = svc
+            .get_new_files(Request::new(GetNewFilesReq {
+                working_copy_path: "/repo".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            new_files.new_files,
+            [get_new_files_reply::NewFile {
+                path: "notes".to_string(),
+                size: 4,
+            }]
+        );
+        let untracked_tree_id = svc
+            .snapshot(Request::new(SnapshotReq {
+                working_copy_path: "/repo".to_string(),
+                untracked_paths: vec!["notes".to_string()],
+                lease_id: lease.id(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .tree_id;
+        assert_eq!(untracked_tree_id, tree_id);
+    }
+
+    #[tokio::test]
+    async fn checkout_state_is_recorded_per_working_copy() {
+        let (svc, cache) = service(Default::default()).await;
+        let lease = svc.leases.acquire("/repo").await;
+        let path = |path: &str| path.to_string();
+        assert_matches!(
+            svc.get_checkout_state(Request::new(GetCheckoutStateReq {
+                working_copy_path: path("/repo"),
+            }))
+            .await,
+            Err(status) if status.code() == Code::NotFound
+        );
+        assert_matches!(
+            svc.get_tree_state(Request::new(GetTreeStateReq {
+                working_copy_path: path("/repo"),
+            }))
+            .await,
+            Err(status) if status.code() == Code::NotFound
+        );
+        let checkout_state = CheckoutState {
+            op_id: vec![1, 2, 3],
+            workspace_id: b"default".to_vec(),
+        };
+        assert_matches!(
+            svc.set_checkout_state(Request::new(SetCheckoutStateReq {
+                working_copy_path: path("/repo"),
+                checkout_state: Some(checkout_state.clone()),
+                lease_id: lease.id(),
+            }))
+            .await,
+            Err(status) if status.code() == Code::NotFound
+        );
+
+        for repo in ["/repo", "/other"] {
+            svc.initialize(Request::new(InitializeReq {
+                path: path(repo),
+                remote: "localhost".to_string(),
+            }))
+            .await
+            .unwrap();
+        }
+        let tree_state = svc
+            .get_tree_state(Request::new(GetTreeStateReq {
+                working_copy_path: path("/repo"),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let empty_tree_id: Vec<u8> = svc.store.get_empty_tree_id().into();
+        assert_eq!(tree_state.tree_id, empty_tree_id);
+
+        assert_matches!(
+            svc.set_checkout_state(Request::new(SetCheckoutStateReq {
+                working_copy_path: path("/repo"),
+                checkout_state: None,
+                lease_id: lease.id(),
+            }))
+            .await,
+            Err(status) if status.code() == Code::InvalidArgument
+        );
+        svc.set_checkout_state(Request::new(SetCheckoutStateReq {
+            working_copy_path: path("/repo"),
+            checkout_state: Some(checkout_state.clone()),
+            lease_id: lease.id(),
+        }))
+        .await
+        .unwrap();
+        let get = |repo: &str| {
+            svc.get_checkout_state(Request::new(GetCheckoutStateReq {
+                working_copy_path: path(repo),
+            }))
+        };
+        assert_eq!(get("/repo").await.unwrap().into_inner(), checkout_state);
+        assert_eq!(
+            get("/other").await.unwrap().into_inner(),
+            CheckoutState::default()
+        );
+
+        // The state is kept in the cache.
+        let state = std::fs::read_to_string(
+            cache
+                .path()
+                .join("working_copies")
+                .join(blake3::hash(b"/repo").to_hex().as_str())
+                .join("state.toml"),
+        )
+        .unwrap();
+        assert!(state.contains("op_id = \"010203\""));
+        assert!(state.contains("workspace_id = \"default\""));
+
+        svc.sessions.lock().await[0].workspaces[0].state.op_id = "not hex".to_string();
+        assert_matches!(
+            get("/repo").await,
+            Err(status) if status.code() == Code::DataLoss
+        );
+    }
+
+    #[tokio::test]
+    async fn check_out_switches_the_tree() {
+        let (svc, _cache) = service(Default::default()).await;
+        svc.initialize(Request::new(InitializeReq {
+            path: "/repo".to_string(),
+            remote: "localhost".to_string(),
+        }))
+        .await
+        .unwrap();
+        let empty_tree_id: Vec<u8> = svc.store.get_empty_tree_id().into();
+        let file_id = svc
+            .write_file(Request::new(File {
+                data: b"contents".to_vec(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .file_id;
+        let tree = Tree {
+            entries: vec![tree::Entry {
+                name: "file".to_string(),
+                value: Some(TreeValue {
+                    value: Some(tree_value::Value::File(tree_value::File {
+                        id: file_id,
+                        executable: false,
+                    })),
+                }),
+            }],
+        };
+        let tree_id = svc
+            .write_tree(Request::new(tree))
+            .await
+            .unwrap()
+            .into_inner()
+            .tree_id;
+        let lease = svc.leases.acquire("/repo").await;
+        let check_out = |old_tree_id: &[u8], new_tree_id: &[u8]| {
+            svc.check_out(Request::new(CheckOutReq {
+                working_copy_path: "/repo".to_string(),
+                old_tree_id: old_tree_id.to_vec(),
+                new_tree_id: new_tree_id.to_vec(),
+                lease_id: lease.id(),
+            }))
+        };
+
+        assert_matches!(
+            check_out(&empty_tree_id, &[7; 32]).await,
+            Err(status) if status.code() == Code::NotFound
+        );
+        assert_matches!(
+            check_out(&tree_id, &tree_id).await,
+            Err(status) if status.code() == Code::FailedPrecondition
+        );
+        let stats = check_out(&empty_tree_id, &tree_id)
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            stats,
+            CheckOutReply {
+                added_files: 1,
+                ..Default::default()
+            }
+        );
+        let tree_state = svc
+            .get_tree_state(Request::new(GetTreeStateReq {
+                working_copy_path: "/repo".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(tree_state.tree_id, tree_id);
+    }
+
+    #[tokio::test]
+    async fn reset_records_the_tree() {
+        let (svc, _cache) = service(Default::default()).await;
+        let lease = svc.leases.acquire("/repo").await;
+        let reset = |tree_id: Vec<u8>| {
+            svc.reset(Request::new(ResetReq {
+                working_copy_path: "/repo".to_string(),
+                tree_id,
+                lease_id: lease.id(),
+            }))
+        };
+        let empty_tree_id: Vec<u8> = svc.store.get_empty_tree_id().into();
+        assert_matches!(
+            reset(empty_tree_id.clone()).await,
+            Err(status) if status.code() == Code::NotFound
+        );
+        svc.initialize(Request::new(InitializeReq {
+            path: "/repo".to_string(),
+            remote: "localhost".to_string(),
+        }))
+        .await
+        .unwrap();
+        assert_matches!(
+            reset(vec![7; 32]).await,
+            Err(status) if status.code() == Code::NotFound
+        );
+
+        let tree_id = svc
+            .write_tree(Request::new(Tree {
+                entries: vec![tree::Entry {
+                    name: "dir".to_string(),
+                    value: Some(TreeValue {
+                        value: Some(tree_value::Value::TreeId(empty_tree_id.clone())),
+                    }),
+                }],
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .tree_id;
+        reset(tree_id.clone()).await.unwrap();
+        let tree_state = || {
+            svc.get_tree_state(Request::new(GetTreeStateReq {
+                working_copy_path: "/repo".to_string(),
+            }))
+        };
+        assert_eq!(tree_state().await.unwrap().into_inner().tree_id, tree_id);
+
+        // The directory differs, so the reset needs a subtree the store doesn't have.
+        let broken_tree_id = svc
+            .write_tree(Request::new(Tree {
+                entries: vec![tree::Entry {
+                    name: "dir".to_string(),
+                    value: Some(TreeValue {
+                        value: Some(tree_value::Value::TreeId(vec![7; 32])),
+                    }),
+                }],
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .tree_id;
+        assert_matches!(
+            reset(broken_tree_id).await,
+            Err(status) if status.code() == Code::NotFound
+        );
+        assert_eq!(tree_state().await.unwrap().into_inner().tree_id, tree_id);
+
+        svc.recover(Request::new(ResetReq {
+            working_copy_path: "/repo".to_string(),
+            tree_id: empty_tree_id.clone(),
+            lease_id: lease.id(),
+        }))
+        .await
+        .unwrap();
+        assert_eq!(
+            tree_state().await.unwrap().into_inner().tree_id,
+            empty_tree_id
+        );
+    }
+
+    #[tokio::test]
+    async fn sparse_patterns_are_recorded() {
+        let (svc, _cache) = service(Default::default()).await;
+        svc.initialize(Request::new(InitializeReq {
+            path: "/repo".to_string(),
+            remote: "localhost".to_string(),
+        }))
+        .await
+        .unwrap();
+        let lease = svc.leases.acquire("/repo").await;
+        let set = |patterns: &[&str]| {
+            svc.set_sparse_patterns(Request::new(SetSparsePatternsReq {
+                working_copy_path: "/repo".to_string(),
+                sparse_patterns: patterns.iter().map(|p| p.to_string()).collect(),
+                lease_id: lease.id(),
+            }))
+        };
+        let get = || {
+            svc.get_tree_state(Request::new(GetTreeStateReq {
+                working_copy_path: "/repo".to_string(),
+            }))
+        };
+        assert_eq!(get().await.unwrap().into_inner().sparse_patterns, [""]);
+
+        for invalid in ["/src", "src/", "a//b", "../src", "src/./lib"] {
+            assert_matches!(
+                set(&[invalid]).await,
+                Err(status) if status.code() == Code::InvalidArgument
+            );
+        }
+        set(&["src", "docs/api"]).await.unwrap();
+        assert_eq!(
+            get().await.unwrap().into_inner().sparse_patterns,
+            ["src", "docs/api"]
+        );
+    }
+
+    #[tokio::test]
+    async fn locks_are_leased() {
+        let (svc, _cache) = service(Default::default()).await;
+        let acquire = || {
+            svc.acquire_lock(Request::new(AcquireLockReq {
+                working_copy_path: "/repo".to_string(),
+            }))
+        };
+        assert_matches!(
+            acquire().await.map(|_| ()),
+            Err(status) if status.code() == Code::NotFound
+        );
+        svc.initialize(Request::new(InitializeReq {
+            path: "/repo".to_string(),
+            remote: "localhost".to_string(),
+        }))
+        .await
+        .unwrap();
+
+        let mut stream = acquire().await.unwrap().into_inner();
+        let lease_id = stream.next().await.unwrap().unwrap().lease_id;
+        let waiting = Duration::from_millis(50);
+        assert!(tokio::time::timeout(waiting, acquire()).await.is_err());
+
+        // Closing the stream gives up the lock.
+        drop(stream);
+        let stream = tokio::time::timeout(waiting, acquire())
+            .await
+            .unwrap()
+            .unwrap()
+            .into_inner();
+        let release = |lease_id| {
+            svc.release_lock(Request::new(ReleaseLockReq {
+                working_copy_path: "/repo".to_string(),
+                lease_id,
+            }))
+        };
+        assert_matches!(
+            release(lease_id).await,
+            Err(status) if status.code() == Code::NotFound
+        );
+        release(lease_id + 1).await.unwrap();
+        tokio::time::timeout(waiting, acquire())
+            .await
+            .unwrap()
+            .unwrap();
+        drop(stream);
+    }
+
+    #[tokio::test]
+    async fn locks_are_released_when_clients_disconnect() {
+        let (svc, _cache) = service(Default::default()).await;
+        svc.initialize(Request::new(InitializeReq {
+            path: "/repo".to_string(),
+            remote: "localhost".to_string(),
+        }))
+        .await
+        .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let incoming =
+            tonic::transport::server::TcpIncoming::from_listener(listener, true, None).unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(jujutsu_interface_server::JujutsuInterfaceServer::new(svc))
+                .serve_with_incoming(incoming),
+        );
+        let connect = || {
+            jujutsu_interface_client::JujutsuInterfaceClient::connect(format!(
+                "http://127.0.0.1:{port}"
+            ))
+        };
+        let req = AcquireLockReq {
+            working_copy_path: "/repo".to_string(),
+        };
+
+        let mut crashed = connect().await.unwrap();
+        let mut stream = crashed
+            .acquire_lock(req.clone())
+            .await
+            .unwrap()
+            .into_inner();
+        stream.message().await.unwrap().unwrap();
+        let mut client = connect().await.unwrap();
+        let waiting = Duration::from_secs(5);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), client.acquire_lock(req.clone()))
+                .await
+                .is_err()
+        );
+
+        drop((crashed, stream));
+        let mut stream = tokio::time::timeout(waiting, client.acquire_lock(req))
+            .await
+            .unwrap()
+            .unwrap()
+            .into_inner();
+        stream.message().await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn workspaces_share_the_repo() {
+        let (svc, _cache) = service(Default::default()).await;
+        let add_workspace = |repo_path: &str, working_copy_path: &str| {
+            svc.add_workspace(Request::new(AddWorkspaceReq {
+                repo_path: repo_path.to_string(),
+                working_copy_path: working_copy_path.to_string(),
+                workspace_id: "second".to_string(),
+            }))
+        };
+        assert_matches!(
+            add_workspace("/repo", "/second").await,
+            Err(status) if status.code() == Code::NotFound
+        );
+        let repo_port = svc
+            .initialize(Request::new(InitializeReq {
+                path: "/repo".to_string(),
+                remote: "localhost".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .nfs_port;
+        let second_port = add_workspace("/repo", "/second")
+            .await
+            .unwrap()
+            .into_inner()
+            .nfs_port;
+        assert_ne!(repo_port, second_port);
+        for path in ["/repo", "/second"] {
+            assert_matches!(
+                add_workspace("/repo", path).await,
+                Err(status) if status.code() == Code::AlreadyExists
+            );
+            assert_matches!(
+                svc.initialize(Request::new(InitializeReq {
+                    path: path.to_string(),
+                    remote: "localhost".to_string(),
+                }))
+                .await,
+                Err(status) if status.code() == Code::AlreadyExists
+            );
+        }
+
+        // Each workspace has its own working copy.
+        let file_id = svc
+            .write_file(Request::new(File {
+                data: b"contents".to_vec(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .file_id;
+        let tree = Tree {
+            entries: vec![tree::Entry {
+                name: "file".to_string(),
+                value: Some(TreeValue {
+                    value: Some(tree_value::Value::File(tree_value::File {
+                        id: file_id,
+                        executable: false,
+                    })),
+                }),
+            }],
+        };
+        let tree_id = svc
+            .write_tree(Request::new(tree))
+            .await
+            .unwrap()
+            .into_inner()
+            .tree_id;
+        let empty_tree_id: Vec<u8> = svc.store.get_empty_tree_id().into();
+        let check_out = |lease_id: u64| {
+            svc.check_out(Request::new(CheckOutReq {
+                working_copy_path: "/second".to_string(),
+                old_tree_id: empty_tree_id.clone(),
+                new_tree_id: tree_id.clone(),
+                lease_id,
+            }))
+        };
+        // Changes need the lease that holds the lock on the working copy itself.
+        let repo_lease = svc.leases.acquire("/repo").await;
+        assert_matches!(
+            check_out(repo_lease.id()).await,
+            Err(status) if status.code() == Code::PermissionDenied
+        );
+        let lease = svc.leases.acquire("/second").await;
+        check_out(lease.id()).await.unwrap();
+        let tree_id_of = |path: &str| {
+            svc.get_tree_state(Request::new(GetTreeStateReq {
+                working_copy_path: path.to_string(),
+            }))
+        };
+        assert_eq!(
+            tree_id_of("/repo").await.unwrap().into_inner().tree_id,
+            empty_tree_id
+        );
+        assert_eq!(
+            tree_id_of("/second").await.unwrap().into_inner().tree_id,
+            tree_id
+        );
+
+        svc.set_checkout_state(Request::new(SetCheckoutStateReq {
+            working_copy_path: "/second".to_string(),
+            checkout_state: Some(CheckoutState {
+                op_id: vec![1],
+                workspace_id: b"renamed".to_vec(),
+            }),
+            lease_id: lease.id(),
+        }))
+        .await
+        .unwrap();
+        let status = svc
+            .daemon_status(Request::new(DaemonStatusReq {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(status.data.len(), 1);
+        assert_eq!(status.data[0].nfs_port, repo_port);
+        assert_eq!(
+            status.data[0].workspaces,
+            [
+                daemon_status_reply::Workspace {
+                    workspace_id: String::new(),
+                    path: "/repo".to_string(),
+                    nfs_port: repo_port,
+                },
+                daemon_status_reply::Workspace {
+                    workspace_id: "renamed".to_string(),
+                    path: "/second".to_string(),
+                    nfs_port: second_port,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn working_copies_are_served_over_nfs() {
+        let (svc, _cache) = service(Default::default()).await;
+        let nfs_port = svc
+            .initialize(Request::new(InitializeReq {
+                path: "/repo".to_string(),
+                remote: "localhost".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .nfs_port;
+        assert!((30000..40000).contains(&nfs_port));
+        tokio::net::TcpStream::connect(("127.0.0.1", nfs_port as u16))
+            .await
+            .unwrap();
+
+        let status = svc
+            .daemon_status(Request::new(DaemonStatusReq {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(status.data[0].nfs_port, nfs_port);
+    }
+
+    #[tokio::test]
+    async fn unmounting_forgets_working_copies() {
+        use nfsserve::vfs::NFSFileSystem;
+
+        let (svc, _cache) = service(Default::default()).await;
+        let initialize = || {
+            svc.initialize(Request::new(InitializeReq {
+                path: "/repo".to_string(),
+                remote: "localhost".to_string(),
+            }))
+        };
+        let unmount = |path: &str, force: bool| {
+            svc.unmount(Request::new(UnmountReq {
+                working_copy_path: path.to_string(),
+                force,
+            }))
+        };
+        let status = || svc.daemon_status(Request::new(DaemonStatusReq {}));
+        let nfs_port = initialize().await.unwrap().into_inner().nfs_port;
+        svc.add_workspace(Request::new(AddWorkspaceReq {
+            repo_path: "/repo".to_string(),
+            working_copy_path: "/second".to_string(),
+            workspace_id: "second".to_string(),
+        }))
+        .await
+        .unwrap();
+
+        let remounted = svc
+            .remount(Request::new(RemountReq {
+                working_copy_path: "/repo".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(remounted.nfs_port, nfs_port);
+
+        assert!(svc.working_copy_dir("/repo").exists());
+        unmount("/repo", false).await.unwrap();
+        assert!(!svc.working_copy_dir("/repo").exists());
+        assert!(svc.working_copy_dir("/second").exists());
+        let data = status().await.unwrap().into_inner().data;
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0].nfs_port, 0);
+        assert_eq!(data[0].workspaces.len(), 1);
+        assert_eq!(data[0].workspaces[0].path, "/second");
+        assert!(
+            tokio::net::TcpStream::connect(("127.0.0.1", nfs_port as u16))
+                .await
+                .is_err()
+        );
+        assert_matches!(
+            unmount("/repo", false).await,
+            Err(status) if status.code() == Code::NotFound
+        );
+
+        // Changes that were not snapshotted are only dropped when forced.
+        let second = svc.workspace("/second").await.unwrap();
+        second
+            .vfs
+            .create(
+                second.vfs.root_dir(),
+                &b"notes".as_slice().into(),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        assert_matches!(
+            unmount("/second", false).await,
+            Err(status) if status.code() == Code::FailedPrecondition
+        );
+        assert_eq!(status().await.unwrap().into_inner().data.len(), 1);
+
+        // The repo goes away with its last workspace, and can be set up again.
+        unmount("/second", true).await.unwrap();
+        assert!(status().await.unwrap().into_inner().data.is_empty());
+        initialize().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn remotes_are_resolved_at_initialize() {
+        let (mut svc, _cache) = service(Default::default()).await;
+        svc.remotes = Remotes {
+            default_port: 23000,
+            default_addr: Some("thelastyak.com:23000".to_string()),
+        };
+        let initialize = |path: &str, remote: &str| {
+            svc.initialize(Request::new(InitializeReq {
+                path: path.to_string(),
+                remote: remote.to_string(),
+            }))
+        };
+        for invalid in ["", "bwb@.hidden", "thelastyak.com/a/b", "a b/repo"] {
+            assert_matches!(
+                initialize("/repo", invalid).await,
+                Err(status) if status.code() == Code::InvalidArgument
+            );
+        }
+        initialize("/repo", "bwb@thelastyak.com/repo")
+            .await
+            .unwrap();
+        initialize("/other", "elsewhere/other").await.unwrap();
+        initialize("/default", "repo").await.unwrap();
+        initialize("/local", "localhost").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn repos_from_different_remotes_keep_their_objects_apart() {
+        let (svc, _cache) = service(Default::default()).await;
+        for (path, remote) in [
+            ("/a", "thelastyak.com/a"),
+            ("/b", "thelastyak.com/b"),
+            ("/also-a", "bwb@thelastyak.com/a"),
+        ] {
+            svc.initialize(Request::new(InitializeReq {
+                path: path.to_string(),
+                remote: remote.to_string(),
+            }))
+            .await
+            .unwrap();
+        }
+        fn for_repo<T>(path: Option<&str>, message: T) -> Request<T> {
+            let mut request = Request::new(message);
+            if let Some(path) = path {
+                request.metadata_mut().insert_bin(
+                    proto::REPO_PATH_METADATA,
+                    MetadataValue::from_bytes(path.as_bytes()),
+                );
+            }
+            request
+        }
+        let file_id = svc
+            .write_file(for_repo(
+                Some("/a"),
+                File {
+                    data: b"hello".to_vec(),
+                },
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        let read = |path| svc.read_file(for_repo(path, file_id.clone()));
+        read(Some("/a")).await.unwrap();
+        // Repos from the same remote repo share a store.
+        read(Some("/also-a")).await.unwrap();
+        assert_matches!(
+            read(Some("/b")).await,
+            Err(status) if status.code() == Code::NotFound
+        );
+        // Without the repo the request could go to the wrong store.
+        assert_matches!(
+            read(None).await,
+            Err(status) if status.code() == Code::InvalidArgument
+        );
+        assert_matches!(
+            read(Some("/nowhere")).await,
+            Err(status) if status.code() == Code::NotFound
+        );
+
+        // Working copies read from their repo's store.
+        let tree_id = svc
+            .write_tree(for_repo(
+                Some("/a"),
+                Tree {
+                    entries: vec![tree::Entry {
+                        name: "file".to_string(),
+                        value: Some(TreeValue {
+                            value: Some(tree_value::Value::File(tree_value::File {
+                                id: file_id.file_id,
+                                executable: false,
+                            })),
+                        }),
+                    }],
+                },
+            ))
+            .await
+            .unwrap()
+            .into_inner()
+            .tree_id;
+        let empty_tree_id: Vec<u8> = svc.store.get_empty_tree_id().into();
+        for (path, code) in [("/also-a", Code::Ok), ("/b", Code::NotFound)] {
+            let lease = svc.leases.acquire(path).await;
+            let result = svc
+                .check_out(Request::new(CheckOutReq {
+                    working_copy_path: path.to_string(),
+                    old_tree_id: empty_tree_id.clone(),
+                    new_tree_id: tree_id.clone(),
+                    lease_id: lease.id(),
+                }))
+                .await;
+            assert_eq!(
+                result.map_or_else(|status| status.code(), |_| Code::Ok),
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn sessions_are_restored_after_a_restart() {
+        let objects = Arc::new(InMemoryObjectStore::default());
+        let cache = tempfile::tempdir().unwrap();
+        async fn get_tree_state(
+            svc: &JujutsuService,
+            path: &str,
+        ) -> Result<GetTreeStateReply, Status> {
+            svc.get_tree_state(Request::new(GetTreeStateReq {
+                working_copy_path: path.to_string(),
+            }))
+            .await
+            .map(Response::into_inner)
+        }
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let (ports, tree_state) = rt.block_on(async {
+            let svc = service_in(objects.clone(), cache.path()).await;
+            let repo_port = svc
+                .initialize(Request::new(InitializeReq {
+                    path: "/repo".to_string(),
+                    remote: "localhost".to_string(),
+                }))
+                .await
+                .unwrap()
+                .into_inner()
+                .nfs_port;
+            let second_port = svc
+                .add_workspace(Request::new(AddWorkspaceReq {
+                    repo_path: "/repo".to_string(),
+                    working_copy_path: "/second".to_string(),
+                    workspace_id: "second".to_string(),
+                }))
+                .await
+                .unwrap()
+                .into_inner()
+                .nfs_port;
+            svc.initialize(Request::new(InitializeReq {
+                path: "/broken".to_string(),
+                remote: "localhost".to_string(),
+            }))
+            .await
+            .unwrap();
+            let lease = svc.leases.acquire("/repo").await;
+            svc.set_sparse_patterns(Request::new(SetSparsePatternsReq {
+                working_copy_path: "/repo".to_string(),
+                sparse_patterns: vec!["src".to_string()],
+                lease_id: lease.id(),
+            }))
+            .await
+            .unwrap();
+            std::fs::remove_file(svc.working_copy_dir("/broken").join("state.toml")).unwrap();
+            (
+                (repo_port, second_port),
+                get_tree_state(&svc, "/repo").await.unwrap(),
+            )
+        });
+        // Stops the NFS servers.
+        drop(rt);
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let svc = service_in(objects, cache.path()).await;
+            let status = svc
+                .daemon_status(Request::new(DaemonStatusReq {}))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(status.data.len(), 1);
+            assert_eq!(status.data[0].path, "/repo");
+            assert_eq!(status.data[0].remote, "localhost");
+            let workspace_ports: Vec<_> = status.data[0]
+                .workspaces
+                .iter()
+                .map(|workspace| (workspace.path.as_str(), workspace.nfs_port))
+                .collect();
+            assert_eq!(workspace_ports, [("/repo", ports.0), ("/second", ports.1)]);
+            for port in [ports.0, ports.1] {
+                tokio::net::TcpStream::connect(("127.0.0.1", port as u16))
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(get_tree_state(&svc, "/repo").await.unwrap(), tree_state);
+
+            // Working copies that can't be restored are reported, and left out.
+            assert_eq!(status.unrestored.len(), 1);
+            assert_eq!(status.unrestored[0].path, "/broken");
+            assert_matches!(
+                get_tree_state(&svc, "/broken").await,
+                Err(status) if status.code() == Code::NotFound
+            );
+        });
+    }
 }
@@ -1,99 +1,202 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{io, sync::Arc};
 
-use parking_lot::Mutex;
+use prost::Message;
 
-use crate::ty::*;
+use crate::{
+    object_store::{ObjectKind, ObjectStore, SyncStatus},
+    ty::*,
+};
 
 /// Stores mount-agnostic information like Trees or Commits. Unaware of filesystem information.
+///
+/// Encodes objects and hands them to an [`ObjectStore`], which decides where they live.
 #[derive(Clone, Debug)]
 pub struct Store {
-    // Commits
-    pub commits: Arc<Mutex<HashMap<Id, Commit>>>,
+    objects: Arc<dyn ObjectStore>,
 
-    /// File contents                                             
-    pub files: Arc<Mutex<HashMap<Id, File>>>,
-
-    /// Symlinks                                                  
-    pub symlinks: Arc<Mutex<HashMap<Id, Symlink>>>,
-
-    /// Trees
-    pub trees: Arc<Mutex<HashMap<Id, Tree>>>,
-
-    /// Empty sha identity                                        
+    /// Empty sha identity
     pub empty_tree_id: Id,
 }
 
 impl Store {
-    pub fn new() -> Self {
-        let commits = Arc::new(Mutex::new(HashMap::new()));
-        let files = Arc::new(Mutex::new(HashMap::new()));
-        let symlinks = Arc::new(Mutex::new(HashMap::new()));
-
-        let (empty_tree_id, trees) = {
-            let mut trees = HashMap::new();
-            let tree = Tree::default();
-            let empty_tree_id: Id = tree.get_hash();
-            trees.insert(empty_tree_id.clone(), tree);
-            (empty_tree_id, Arc::new(Mutex::new(trees)))
-        };
-
-        Store {
-            commits,
-            trees,
-            files,
-            symlinks,
+    pub async fn new(objects: Arc<dyn ObjectStore>) -> io::Result<Self> {
+        let tree = Tree::default();
+        let empty_tree_id: Id = tree.get_hash();
+        let store = Store {
+            objects,
             empty_tree_id,
-        }
+        };
+        store.write_tree(tree).await?;
+        Ok(store)
     }
 
     pub fn get_empty_tree_id(&self) -> Id {
-        self.empty_tree_id.clone()
+        self.empty_tree_id
+    }
+
+    pub async fn sync_status(&self) -> SyncStatus {
+        self.objects.sync_status().await
     }
 
-    pub fn get_tree(&self, id: Id) -> Option<Tree> {
-        let tree_store = self.trees.lock();
-        tree_store.get(&id).cloned()
+    pub async fn get_tree(&self, id: Id) -> io::Result<Option<Tree>> {
+        let Some(data) = self.objects.get(ObjectKind::Tree, id).await? else {
+            return Ok(None);
+        };
+        let proto = decode::<proto::jj_interface::Tree>(&data)?;
+        Ok(Some(proto.try_into().map_err(invalid_data)?))
     }
 
     #[tracing::instrument]
-    pub async fn write_tree(&self, tree: Tree) -> Id {
-        let mut tree_store = self.trees.lock();
+    pub async fn write_tree(&self, tree: Tree) -> io::Result<Id> {
         let hash = tree.get_hash();
-        tree_store.insert(hash, tree);
-        hash
+        self.objects
+            .put(ObjectKind::Tree, hash, tree.as_proto().encode_to_vec())
+            .await?;
+        Ok(hash)
     }
 
-    pub fn get_file(&self, id: Id) -> Option<File> {
-        let file_store = self.files.lock();
-        file_store.get(&id).cloned()
+    pub async fn get_file(&self, id: Id) -> io::Result<Option<File>> {
+        let Some(data) = self.objects.get(ObjectKind::File, id).await? else {
+            return Ok(None);
+        };
+        Ok(Some(decode::<proto::jj_interface::File>(&data)?.into()))
     }
 
     #[tracing::instrument]
-    pub async fn write_commit(&self, commit: Commit) -> Id {
-        let mut commit_store = self.commits.lock();
-        let hash = commit.get_hash();
-        commit_store.insert(hash, commit);
-        hash
+    pub async fn write_file(&self, file: File) -> io::Result<Id> {
+        let hash = file.get_hash();
+        self.objects
+            .put(ObjectKind::File, hash, file.as_proto().encode_to_vec())
+            .await?;
+        Ok(hash)
+    }
+
+    pub async fn get_commit(&self, id: Id) -> io::Result<Option<Commit>> {
+        let Some(data) = self.objects.get(ObjectKind::Commit, id).await? else {
+            return Ok(None);
+        };
+        let proto = decode::<proto::jj_interface::Commit>(&data)?;
+        Ok(Some(proto.try_into().map_err(invalid_data)?))
     }
 
     #[tracing::instrument]
-    pub async fn write_file(&self, file: File) -> Id {
-        let mut file_store = self.files.lock();
-        let hash = file.get_hash();
-        file_store.insert(hash, file);
-        hash
+    pub async fn write_commit(&self, commit: Commit) -> io::Result<Id> {
+        let hash = commit.get_hash();
+        self.objects
+            .put(ObjectKind::Commit, hash, commit.as_proto().encode_to_vec())
+            .await?;
+        Ok(hash)
     }
 
-    pub fn get_symlink(&self, id: Id) -> Option<Symlink> {
-        let symlink_store = self.symlinks.lock();
-        symlink_store.get(&id).cloned()
+    pub async fn get_symlink(&self, id: Id) -> io::Result<Option<Symlink>> {
+        let Some(data) = self.objects.get(ObjectKind::Symlink, id).await? else {
+            return Ok(None);
+        };
+        Ok(Some(decode::<proto::jj_interface::Symlink>(&data)?.into()))
     }
 
     #[tracing::instrument]
-    pub async fn write_symlink(&self, symlink: Symlink) -> Id {
-        let mut symlink_store = self.symlinks.lock();
+    pub async fn write_symlink(&self, symlink: Symlink) -> io::Result<Id> {
         let hash = symlink.get_hash();
-        symlink_store.insert(hash, symlink);
-        hash
+        self.objects
+            .put(
+                ObjectKind::Symlink,
+                hash,
+                symlink.as_proto().encode_to_vec(),
+            )
+            .await?;
+        Ok(hash)
+    }
+
+    pub async fn get_conflict(&self, id: Id) -> io::Result<Option<Conflict>> {
+        let Some(data) = self.objects.get(ObjectKind::Conflict, id).await? else {
+            return Ok(None);
+        };
+        let proto = decode::<proto::jj_interface::Conflict>(&data)?;
+        Ok(Some(proto.try_into().map_err(invalid_data)?))
+    }
+
+    #[tracing::instrument]
+    pub async fn write_conflict(&self, conflict: Conflict) -> io::Result<Id> {
+        let hash = conflict.get_hash();
+        self.objects
+            .put(
+                ObjectKind::Conflict,
+                hash,
+                conflict.as_proto().encode_to_vec(),
+            )
+            .await?;
+        Ok(hash)
+    }
+}
+
+fn decode<M: Message + Default>(data: &[u8]) -> io::Result<M> {
+    M::decode(data).map_err(invalid_data)
+}
+
+/// Stored objects that don't decode are corrupt.
+fn invalid_data(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object_store::DiskObjectStore;
+
+    #[tokio::test]
+    async fn objects_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = || async {
+            let objects = DiskObjectStore::new(dir.path()).await.unwrap();
+            Store::new(Arc::new(objects)).await.unwrap()
+        };
+
+        let store = open().await;
+        let file_id = store
+            .write_file(File {
+                content: b"hello".to_vec(),
+            })
+            .await
+            .unwrap();
+        let symlink_id = store
+            .write_symlink(Symlink {
+                target: "hello".to_string(),
+            })
+            .await
+            .unwrap();
+        let tree_id = store
+            .write_tree(Tree {
+                entries: vec![TreeEntryMapping {
+                    name: "hello".to_string(),
+                    entry: TreeEntry::File {
+                        id: file_id,
+                        executable: false,
+                    },
+                }],
+            })
+            .await
+            .unwrap();
+        drop(store);
+
+        let store = open().await;
+        assert_eq!(
+            store.get_file(file_id).await.unwrap().unwrap().content,
+            b"hello"
+        );
+        assert_eq!(
+            store.get_symlink(symlink_id).await.unwrap().unwrap().target,
+            "hello"
+        );
+        assert_eq!(
+            store.get_tree(tree_id).await.unwrap().unwrap().get_hash(),
+            tree_id
+        );
+        assert!(store
+            .get_tree(store.get_empty_tree_id())
+            .await
+            .unwrap()
+            .is_some());
+        assert!(store.get_file(Id([1; 32])).await.unwrap().is_none());
     }
 }
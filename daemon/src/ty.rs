@@ -1,3 +1,5 @@
+use std::fmt;
+
 use jj_lib_proc_macros::ContentHash;
 
 use crate::hash::blake3;
@@ -14,39 +16,123 @@ impl jj_lib::content_hash::ContentHash for Id {
     }
 }
 
+/// Lowercase hex encoding of arbitrary bytes.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Decodes the output of [`hex`].
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|chunk| u8::from_str_radix(std::str::from_utf8(chunk).ok()?, 16).ok())
+        .collect()
+}
+
+/// A proto message that can't be turned into one of our types.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProtoError {
+    InvalidIdLength(usize),
+    MissingField(&'static str),
+}
+
+impl fmt::Display for ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtoError::InvalidIdLength(len) => {
+                write!(f, "id is {len} bytes long, expected {}", Id::LENGTH)
+            }
+            ProtoError::MissingField(field) => write!(f, "missing field `{field}`"),
+        }
+    }
+}
+
+impl std::error::Error for ProtoError {}
+
+impl Id {
+    pub const LENGTH: usize = 32;
+
+    /// Lowercase hex encoding, used to name objects on disk.
+    pub fn hex(&self) -> String {
+        hex(&self.0)
+    }
+
+    pub fn from_hex(hex: &str) -> Option<Id> {
+        Id::try_from(from_hex(hex)?.as_slice()).ok()
+    }
+}
+
+/// Ids are written as hex in configuration and state files.
+impl serde::Serialize for Id {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.hex())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Id {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        Id::from_hex(&hex).ok_or_else(|| serde::de::Error::custom(format!("invalid id {hex:?}")))
+    }
+}
+
 impl Into<Vec<u8>> for Id {
     fn into(self) -> Vec<u8> {
         self.0.to_vec()
     }
 }
 
-impl From<proto::jj_interface::FileId> for Id {
-    fn from(proto: proto::jj_interface::FileId) -> Self {
-        proto.file_id.into()
+impl TryFrom<proto::jj_interface::FileId> for Id {
+    type Error = ProtoError;
+
+    fn try_from(proto: proto::jj_interface::FileId) -> Result<Self, Self::Error> {
+        proto.file_id.as_slice().try_into()
+    }
+}
+
+impl TryFrom<proto::jj_interface::CommitId> for Id {
+    type Error = ProtoError;
+
+    fn try_from(proto: proto::jj_interface::CommitId) -> Result<Self, Self::Error> {
+        proto.commit_id.as_slice().try_into()
     }
 }
 
-impl From<proto::jj_interface::CommitId> for Id {
-    fn from(proto: proto::jj_interface::CommitId) -> Self {
-        proto.commit_id.into()
+impl TryFrom<proto::jj_interface::SymlinkId> for Id {
+    type Error = ProtoError;
+
+    fn try_from(proto: proto::jj_interface::SymlinkId) -> Result<Self, Self::Error> {
+        proto.symlink_id.as_slice().try_into()
     }
 }
 
-impl From<proto::jj_interface::SymlinkId> for Id {
-    fn from(proto: proto::jj_interface::SymlinkId) -> Self {
-        proto.symlink_id.into()
+impl TryFrom<proto::jj_interface::ConflictId> for Id {
+    type Error = ProtoError;
+
+    fn try_from(proto: proto::jj_interface::ConflictId) -> Result<Self, Self::Error> {
+        proto.conflict_id.as_slice().try_into()
     }
 }
 
-impl From<proto::jj_interface::TreeId> for Id {
-    fn from(proto: proto::jj_interface::TreeId) -> Self {
-        proto.tree_id.into()
+impl TryFrom<proto::jj_interface::TreeId> for Id {
+    type Error = ProtoError;
+
+    fn try_from(proto: proto::jj_interface::TreeId) -> Result<Self, Self::Error> {
+        proto.tree_id.as_slice().try_into()
     }
 }
 
-impl From<Vec<u8>> for Id {
-    fn from(proto: Vec<u8>) -> Self {
-        Id(proto.as_slice().try_into().expect("should fit in Id"))
+impl TryFrom<&[u8]> for Id {
+    type Error = ProtoError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        bytes
+            .try_into()
+            .map(Id)
+            .map_err(|_| ProtoError::InvalidIdLength(bytes.len()))
     }
 }
 
@@ -109,13 +195,18 @@ impl From<proto::jj_interface::commit::Timestamp> for CommitTimestamp {
     }
 }
 
-impl From<proto::jj_interface::commit::Signature> for CommitSignature {
-    fn from(proto: proto::jj_interface::commit::Signature) -> Self {
+impl TryFrom<proto::jj_interface::commit::Signature> for CommitSignature {
+    type Error = ProtoError;
+
+    fn try_from(proto: proto::jj_interface::commit::Signature) -> Result<Self, Self::Error> {
         let mut sig = CommitSignature::default();
         sig.name = proto.name;
         sig.email = proto.email;
-        sig.timestamp = proto.timestamp.unwrap().into();
-        sig
+        sig.timestamp = proto
+            .timestamp
+            .ok_or(ProtoError::MissingField("timestamp"))?
+            .into();
+        Ok(sig)
     }
 }
 
@@ -126,7 +217,7 @@ pub struct CommitSignature {
     timestamp: CommitTimestamp,
 }
 
-#[derive(Clone, Debug, Default, ContentHash)]
+#[derive(Clone, Debug, Default)]
 pub struct Commit {
     pub parents: Vec<Vec<u8>>,
     pub predecessors: Vec<Vec<u8>>,
@@ -136,6 +227,26 @@ pub struct Commit {
     pub description: String,
     pub author: Option<CommitSignature>,
     pub committer: Option<CommitSignature>,
+    /// Signature over the encoded commit with this field unset
+    pub secure_sig: Option<Vec<u8>>,
+}
+
+// Written out rather than derived so that `secure_sig` is only hashed when it is set, which keeps
+// the ids of unsigned commits the same as before commits could be signed.
+impl jj_lib::content_hash::ContentHash for Commit {
+    fn hash(&self, state: &mut impl digest::Update) {
+        self.parents.hash(state);
+        self.predecessors.hash(state);
+        self.root_tree.hash(state);
+        self.uses_tree_conflict_format.hash(state);
+        self.change_id.hash(state);
+        self.description.hash(state);
+        self.author.hash(state);
+        self.committer.hash(state);
+        if let Some(sig) = &self.secure_sig {
+            sig.hash(state);
+        }
+    }
 }
 
 impl Commit {
@@ -153,12 +264,15 @@ impl Commit {
         proto.description = self.description.clone();
         proto.author = self.author.clone().map(|a| a.as_proto());
         proto.committer = self.committer.clone().map(|a| a.as_proto());
+        proto.secure_sig = self.secure_sig.clone();
         proto
     }
 }
 
-impl From<proto::jj_interface::Commit> for Commit {
-    fn from(proto: proto::jj_interface::Commit) -> Self {
+impl TryFrom<proto::jj_interface::Commit> for Commit {
+    type Error = ProtoError;
+
+    fn try_from(proto: proto::jj_interface::Commit) -> Result<Self, Self::Error> {
         let mut commit = Commit::default();
         commit.parents = proto.parents;
         commit.predecessors = proto.predecessors;
@@ -166,9 +280,10 @@ impl From<proto::jj_interface::Commit> for Commit {
         commit.uses_tree_conflict_format = proto.uses_tree_conflict_format.clone();
         commit.change_id = proto.change_id.clone();
         commit.description = proto.description.clone();
-        commit.author = proto.author.map(Into::into);
-        commit.committer = proto.committer.map(Into::into);
-        commit
+        commit.author = proto.author.map(TryInto::try_into).transpose()?;
+        commit.committer = proto.committer.map(TryInto::try_into).transpose()?;
+        commit.secure_sig = proto.secure_sig;
+        Ok(commit)
     }
 }
 
@@ -197,12 +312,12 @@ impl From<proto::jj_interface::File> for File {
     }
 }
 
-#[derive(Clone, Debug, Default, ContentHash)]
+#[derive(Clone, Debug, Default, Eq, PartialEq, ContentHash)]
 pub struct Tree {
     pub entries: Vec<TreeEntryMapping>,
 }
 
-#[derive(Clone, Debug, ContentHash)]
+#[derive(Clone, Debug, Eq, PartialEq, ContentHash)]
 pub struct TreeEntryMapping {
     pub name: String,
     pub entry: TreeEntry,
@@ -225,22 +340,24 @@ impl Tree {
     }
 }
 
-impl From<proto::jj_interface::Tree> for Tree {
-    fn from(proto: proto::jj_interface::Tree) -> Self {
+impl TryFrom<proto::jj_interface::Tree> for Tree {
+    type Error = ProtoError;
+
+    fn try_from(proto: proto::jj_interface::Tree) -> Result<Self, Self::Error> {
         let mut tree = Tree::default();
         for proto_entry in proto.entries {
-            let proto_val = proto_entry.value.unwrap();
-            let entry = proto_val.into();
+            let proto_val = proto_entry.value.ok_or(ProtoError::MissingField("value"))?;
+            let entry = proto_val.try_into()?;
             tree.entries.push(TreeEntryMapping {
                 name: proto_entry.name,
                 entry,
             });
         }
-        tree
+        Ok(tree)
     }
 }
 
-#[derive(Clone, Debug, ContentHash)]
+#[derive(Clone, Debug, Eq, PartialEq, ContentHash)]
 pub enum TreeEntry {
     File { id: Id, executable: bool },
     TreeId(Id),
@@ -248,19 +365,22 @@ pub enum TreeEntry {
     ConflictId(Id),
 }
 
-impl From<proto::jj_interface::TreeValue> for TreeEntry {
-    fn from(proto: proto::jj_interface::TreeValue) -> Self {
-        let value: proto::jj_interface::tree_value::Value = proto.value.unwrap();
+impl TryFrom<proto::jj_interface::TreeValue> for TreeEntry {
+    type Error = ProtoError;
+
+    fn try_from(proto: proto::jj_interface::TreeValue) -> Result<Self, Self::Error> {
+        let value: proto::jj_interface::tree_value::Value =
+            proto.value.ok_or(ProtoError::MissingField("value"))?;
         use proto::jj_interface::tree_value::Value::*;
-        match value {
-            TreeId(id) => TreeEntry::TreeId(id.into()),
-            SymlinkId(id) => TreeEntry::SymlinkId(id.into()),
-            ConflictId(id) => TreeEntry::ConflictId(id.into()),
+        Ok(match value {
+            TreeId(id) => TreeEntry::TreeId(id.as_slice().try_into()?),
+            SymlinkId(id) => TreeEntry::SymlinkId(id.as_slice().try_into()?),
+            ConflictId(id) => TreeEntry::ConflictId(id.as_slice().try_into()?),
             File(file) => TreeEntry::File {
-                id: file.id.try_into().unwrap(),
+                id: file.id.as_slice().try_into()?,
                 executable: file.executable,
             },
-        }
+        })
     }
 }
 
@@ -274,8 +394,190 @@ impl TreeEntry {
                 proto_entry.executable = *executable;
                 proto::jj_interface::tree_value::Value::File(proto_entry)
             }
-            _ => todo!(),
+            TreeEntry::TreeId(id) => proto::jj_interface::tree_value::Value::TreeId(id.0.to_vec()),
+            TreeEntry::SymlinkId(id) => {
+                proto::jj_interface::tree_value::Value::SymlinkId(id.0.to_vec())
+            }
+            TreeEntry::ConflictId(id) => {
+                proto::jj_interface::tree_value::Value::ConflictId(id.0.to_vec())
+            }
         });
         proto
     }
 }
+
+/// A legacy path-level conflict. Each term is the value one side had at that path.
+#[derive(Clone, Debug, Default, ContentHash)]
+pub struct Conflict {
+    pub removes: Vec<TreeEntry>,
+    pub adds: Vec<TreeEntry>,
+}
+
+impl Conflict {
+    pub fn get_hash(&self) -> Id {
+        Id(*blake3(self).as_bytes())
+    }
+
+    pub fn as_proto(&self) -> proto::jj_interface::Conflict {
+        let term = |entry: &TreeEntry| proto::jj_interface::conflict::Term {
+            content: Some(entry.as_proto()),
+        };
+        proto::jj_interface::Conflict {
+            removes: self.removes.iter().map(term).collect(),
+            adds: self.adds.iter().map(term).collect(),
+        }
+    }
+}
+
+impl TryFrom<proto::jj_interface::Conflict> for Conflict {
+    type Error = ProtoError;
+
+    fn try_from(proto: proto::jj_interface::Conflict) -> Result<Self, Self::Error> {
+        let terms = |terms: Vec<proto::jj_interface::conflict::Term>| {
+            terms
+                .into_iter()
+                .map(|term| {
+                    term.content
+                        .ok_or(ProtoError::MissingField("content"))?
+                        .try_into()
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Conflict {
+            removes: terms(proto.removes)?,
+            adds: terms(proto.adds)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use prost::Message;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    const SEEDS: u64 = 200;
+
+    fn random_id(rng: &mut StdRng) -> Id {
+        Id(rng.gen())
+    }
+
+    /// Builds a random tree, writing every subtree it refers to into `trees`.
+    fn random_tree(rng: &mut StdRng, depth: usize, trees: &mut HashMap<Id, Tree>) -> Tree {
+        let mut tree = Tree::default();
+        for i in 0..rng.gen_range(0..6) {
+            let entry = match rng.gen_range(0..4) {
+                0 => TreeEntry::File {
+                    id: random_id(rng),
+                    executable: rng.gen(),
+                },
+                1 => TreeEntry::SymlinkId(random_id(rng)),
+                2 => TreeEntry::ConflictId(random_id(rng)),
+                _ if depth == 0 => TreeEntry::File {
+                    id: random_id(rng),
+                    executable: false,
+                },
+                _ => {
+                    let subtree = random_tree(rng, depth - 1, trees);
+                    let id = subtree.get_hash();
+                    trees.insert(id, subtree);
+                    TreeEntry::TreeId(id)
+                }
+            };
+            tree.entries.push(TreeEntryMapping {
+                name: format!("entry-{i}"),
+                entry,
+            });
+        }
+        tree
+    }
+
+    fn round_trip(tree: &Tree) -> Tree {
+        let bytes = tree.as_proto().encode_to_vec();
+        proto::jj_interface::Tree::decode(bytes.as_slice())
+            .unwrap()
+            .try_into()
+            .unwrap()
+    }
+
+    #[test]
+    fn every_tree_entry_round_trips() {
+        let id = Id([1; 32]);
+        for entry in [
+            TreeEntry::File {
+                id,
+                executable: true,
+            },
+            TreeEntry::TreeId(id),
+            TreeEntry::SymlinkId(id),
+            TreeEntry::ConflictId(id),
+        ] {
+            assert_eq!(TreeEntry::try_from(entry.as_proto()), Ok(entry));
+        }
+    }
+
+    #[test]
+    fn trees_round_trip() {
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut trees = HashMap::new();
+            let root = random_tree(&mut rng, 3, &mut trees);
+            trees.insert(root.get_hash(), root);
+
+            for (id, tree) in &trees {
+                let decoded = round_trip(tree);
+                assert_eq!(&decoded, tree, "seed {seed}");
+                assert_eq!(decoded.get_hash(), *id, "seed {seed}");
+            }
+        }
+    }
+
+    #[test]
+    fn nested_tree_hashes_follow_their_subtrees() {
+        for seed in 0..SEEDS {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut trees = HashMap::new();
+            let root = random_tree(&mut rng, 3, &mut trees);
+
+            // Walking the round-tripped tree reaches exactly the subtrees that were written.
+            let mut pending = vec![round_trip(&root)];
+            let mut reached = HashSet::new();
+            while let Some(tree) = pending.pop() {
+                for mapping in &tree.entries {
+                    if let TreeEntry::TreeId(id) = &mapping.entry {
+                        let subtree = trees.get(id).expect("subtree should exist");
+                        let decoded = round_trip(subtree);
+                        assert_eq!(decoded.get_hash(), *id, "seed {seed}");
+                        pending.push(decoded);
+                        reached.insert(*id);
+                    }
+                }
+            }
+            assert_eq!(reached, trees.keys().copied().collect(), "seed {seed}");
+
+            // Changing anything below the root changes the root's hash.
+            let Some((index, id)) = root
+                .entries
+                .iter()
+                .enumerate()
+                .find_map(|(index, mapping)| match mapping.entry {
+                    TreeEntry::TreeId(id) => Some((index, id)),
+                    _ => None,
+                })
+            else {
+                continue;
+            };
+            let mut changed_subtree = trees[&id].clone();
+            changed_subtree.entries.push(TreeEntryMapping {
+                name: "added".to_string(),
+                entry: TreeEntry::SymlinkId(random_id(&mut rng)),
+            });
+            let mut changed_root = root.clone();
+            changed_root.entries[index].entry = TreeEntry::TreeId(changed_subtree.get_hash());
+            assert_ne!(changed_root.get_hash(), root.get_hash(), "seed {seed}");
+        }
+    }
+}
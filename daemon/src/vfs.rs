@@ -1,114 +1,2717 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    io::{self, SeekFrom},
+    ops::Bound,
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use async_trait::async_trait;
+use durable::write_durably;
 use nfsserve::{
-    nfs::{fattr3, fileid3, filename3, nfspath3, nfsstat3, sattr3},
-    vfs::{NFSFileSystem, ReadDirResult, VFSCapabilities},
+    nfs::{
+        fattr3, fileid3, filename3, ftype3, nfspath3, nfsstat3, nfstime3, sattr3, set_mode3,
+        set_size3,
+    },
+    vfs::{DirEntry, NFSFileSystem, ReadDirResult, VFSCapabilities},
 };
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::warn;
 
-#[derive(Debug)]
+use crate::{
+    store::Store,
+    ty::{File, Id, Symlink, Tree, TreeEntry, TreeEntryMapping},
+};
+
+const ROOT_ID: fileid3 = 1;
+
+const MANIFEST: &str = "manifest.toml";
+const MANIFEST_TMP: &str = "manifest.toml.tmp";
+const JOURNAL: &str = "journal";
+/// Once the journal is this large, and larger than the manifest, it is folded into the manifest.
+const JOURNAL_COMPACT_BYTES: u64 = 1 << 20;
+const DATA: &str = "data";
+
+/// The repo's own metadata lives in the mount, but never makes it into trees.
+const REPO_DIR: &str = ".jj";
+
+/// How many bytes of decompressed files from the store are kept around, so that reading a file
+/// in chunks only decompresses it once.
+const CONTENTS_CACHE_BYTES: usize = 64 << 20;
+
+/// A view of a tree in the [`Store`] with local changes on top, served over NFS.
+///
+/// Nothing from the store is materialized on disk: every request walks the tree from the root.
+/// Changes made through the mount are recorded in an overlay keyed by path and persisted in
+/// its own directory, so the daemon knows exactly which paths changed since checkout.
+///
+/// File ids are handed out per path the first time a path is looked up, and stay stable for
+/// as long as the filesystem lives. Renames keep the ids of everything they move. Paths whose
+/// contents change outside of the mount, e.g. on checkout, get a new modification time instead.
+#[derive(Clone, Debug)]
 pub struct VirtualFileSystem {
-    rootdir: fileid3,
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    store: Store,
+    /// What the mount shows where the overlay has no changes
+    root_tree: Mutex<Id>,
+    inodes: Mutex<Inodes>,
+    overlay: tokio::sync::Mutex<Overlay>,
+    /// Only paths in these directories are shown, the empty path stands for everything
+    sparse_patterns: Mutex<Vec<String>>,
+    /// Decompressed sizes of the files in the store, which the store doesn't record
+    sizes: Mutex<HashMap<Id, u64>>,
+    contents: Mutex<ContentsCache>,
+    /// Modification time of the paths that haven't changed since the filesystem started
+    started: nfstime3,
+    uid: u32,
+    gid: u32,
+}
+
+/// Maps file ids to the paths they were handed out for. The root has the empty path.
+#[derive(Debug)]
+struct Inodes {
+    ids: HashMap<String, fileid3>,
+    paths: HashMap<fileid3, String>,
+    /// When the contents of a file id last changed outside of the overlay
+    mtimes: HashMap<fileid3, nfstime3>,
+    next_id: fileid3,
+}
+
+impl Default for Inodes {
+    fn default() -> Self {
+        Inodes {
+            ids: HashMap::from([(String::new(), ROOT_ID)]),
+            paths: HashMap::from([(ROOT_ID, String::new())]),
+            mtimes: HashMap::new(),
+            next_id: ROOT_ID + 1,
+        }
+    }
+}
+
+impl Inodes {
+    fn id(&mut self, path: &str) -> fileid3 {
+        if let Some(id) = self.ids.get(path) {
+            return *id;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.ids.insert(path.to_string(), id);
+        self.paths.insert(id, path.to_string());
+        id
+    }
+
+    fn path(&self, id: fileid3) -> Result<String, nfsstat3> {
+        self.paths.get(&id).cloned().ok_or(nfsstat3::NFS3ERR_STALE)
+    }
+
+    /// Records that `path` changed at `time`. Paths without an id aren't known to any client, so
+    /// they are left alone.
+    fn set_mtime(&mut self, path: &str, time: nfstime3) {
+        if let Some(id) = self.ids.get(path) {
+            self.mtimes.insert(*id, time);
+        }
+    }
+
+    /// Records that `path` changed at `time`, along with the directories containing it.
+    fn touch(&mut self, mut path: &str, time: nfstime3) {
+        loop {
+            self.set_mtime(path, time);
+            if path.is_empty() {
+                break;
+            }
+            path = parent(path);
+        }
+    }
+
+    /// Moves the ids of `from` and everything below it to `to`, dropping whatever `to` had.
+    fn rename(&mut self, from: &str, to: &str) {
+        let replaced: Vec<_> = self
+            .ids
+            .keys()
+            .filter(|path| is_at_or_below(path, to))
+            .cloned()
+            .collect();
+        for path in replaced {
+            if let Some(id) = self.ids.remove(&path) {
+                self.paths.remove(&id);
+                self.mtimes.remove(&id);
+            }
+        }
+        let moved: Vec<_> = self
+            .ids
+            .iter()
+            .filter(|(path, _)| is_at_or_below(path, from))
+            .map(|(path, id)| (path.clone(), *id))
+            .collect();
+        for (path, id) in moved {
+            let new_path = format!("{to}{}", &path[from.len()..]);
+            self.ids.remove(&path);
+            self.ids.insert(new_path.clone(), id);
+            self.paths.insert(id, new_path);
+        }
+    }
+}
+
+/// Files from the store that were read recently, decompressed.
+#[derive(Debug, Default)]
+struct ContentsCache {
+    files: HashMap<Id, Arc<Vec<u8>>>,
+    /// Oldest first
+    order: VecDeque<Id>,
+    bytes: usize,
+}
+
+impl ContentsCache {
+    fn get(&self, id: Id) -> Option<Arc<Vec<u8>>> {
+        self.files.get(&id).cloned()
+    }
+
+    /// Drops the oldest files to stay within [`CONTENTS_CACHE_BYTES`], but always keeps the one
+    /// just inserted, however large.
+    fn insert(&mut self, id: Id, contents: Arc<Vec<u8>>) {
+        if self.files.contains_key(&id) {
+            return;
+        }
+        self.bytes += contents.len();
+        self.files.insert(id, contents);
+        self.order.push_back(id);
+        while self.bytes > CONTENTS_CACHE_BYTES && self.order.len() > 1 {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            if let Some(old) = self.files.remove(&oldest) {
+                self.bytes -= old.len();
+            }
+        }
+    }
+}
+
+/// A change to a path in the mount.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OverlayEntry {
+    /// Contents live in a data file of the overlay
+    File {
+        data: String,
+        executable: bool,
+    },
+    Symlink {
+        target: String,
+    },
+    /// A directory created in the mount, it starts out empty
+    Dir,
+    /// A file from the store moved here
+    StoreFile {
+        id: String,
+        executable: bool,
+    },
+    /// A directory from the store moved here, changes below it have their own entries
+    Tree {
+        id: String,
+    },
+    /// A conflict from the store moved here
+    Conflict {
+        id: String,
+    },
+    /// Hides whatever the tree had at this path
+    Removed,
 }
 
-impl Default for VirtualFileSystem {
-    fn default() -> VirtualFileSystem {
-        VirtualFileSystem { rootdir: 1 }
+impl OverlayEntry {
+    /// What the entry shows at its path, nothing for removed paths.
+    fn node(&self) -> io::Result<Option<Node>> {
+        let parse = |hex: &str| {
+            Id::from_hex(hex).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid id in overlay: {hex}"),
+                )
+            })
+        };
+        Ok(Some(match self {
+            OverlayEntry::File { data, executable } => Node::LocalFile {
+                data: data.clone(),
+                executable: *executable,
+            },
+            OverlayEntry::Symlink { target } => Node::LocalSymlink(target.clone()),
+            OverlayEntry::Dir => Node::Dir,
+            OverlayEntry::StoreFile { id, executable } => Node::File {
+                id: parse(id)?,
+                executable: *executable,
+            },
+            OverlayEntry::Tree { id } => Node::Tree(parse(id)?),
+            OverlayEntry::Conflict { id } => Node::Conflict(parse(id)?),
+            OverlayEntry::Removed => return Ok(None),
+        }))
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Manifest {
+    next_data: u64,
+    /// The tree the entries apply to, saved along with them so that the two always match
+    root_tree: Option<Id>,
+    entries: BTreeMap<String, OverlayEntry>,
+}
+
+/// A change to the manifest, as recorded in the journal.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+enum Change {
+    Insert { path: String, entry: OverlayEntry },
+    Remove { path: String },
+    NextData { next_data: u64 },
+    RootTree { id: Id },
+}
+
+/// The changes saved at once, which are replayed all or not at all.
+#[derive(Debug, Deserialize, Serialize)]
+struct Batch {
+    changes: Vec<Change>,
+}
+
+impl Manifest {
+    fn apply(&mut self, change: Change) {
+        match change {
+            Change::Insert { path, entry } => {
+                self.entries.insert(path, entry);
+            }
+            Change::Remove { path } => {
+                self.entries.remove(&path);
+            }
+            Change::NextData { next_data } => self.next_data = next_data,
+            Change::RootTree { id } => self.root_tree = Some(id),
+        }
+    }
+}
+
+/// Local changes and the tree they apply to, persisted as a manifest of entries plus a data file
+/// per written file.
+///
+/// Saving appends the changes since the last save to a journal, so it costs the same however
+/// large the overlay is. The journal is folded into the manifest on [`sync`](Self::sync), when
+/// it grows larger than the manifest, and when the overlay is opened.
+#[derive(Debug)]
+struct Overlay {
+    dir: PathBuf,
+    manifest: Manifest,
+    /// Changes made since the last save
+    pending: Vec<Change>,
+    journal: tokio::fs::File,
+    journal_len: u64,
+    manifest_len: u64,
+}
+
+impl Overlay {
+    async fn open(dir: PathBuf) -> io::Result<Self> {
+        tokio::fs::create_dir_all(dir.join(DATA)).await?;
+        let mut manifest = match tokio::fs::read_to_string(dir.join(MANIFEST)).await {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Manifest::default(),
+            Err(err) => return Err(err),
+        };
+        let journal = match tokio::fs::read(dir.join(JOURNAL)).await {
+            Ok(journal) => journal,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+        let replay = !journal.is_empty();
+        for batch in read_batches(&journal) {
+            for change in batch.changes {
+                manifest.apply(change);
+            }
+        }
+        let mut overlay = Overlay {
+            journal: tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(JOURNAL))
+                .await?,
+            dir,
+            manifest,
+            pending: vec![],
+            journal_len: 0,
+            manifest_len: 0,
+        };
+        // Make sure the journal itself outlives a crash.
+        tokio::fs::File::open(&overlay.dir)
+            .await?
+            .sync_all()
+            .await?;
+        // A batch torn by a crash ends the journal, nothing may be appended after it.
+        if replay {
+            overlay.compact().await?;
+        }
+        Ok(overlay)
+    }
+
+    /// Makes the changes since the last save durable.
+    async fn save(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let batch = Batch {
+            changes: std::mem::take(&mut self.pending),
+        };
+        let body = toml::to_string(&batch)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let mut record = Vec::with_capacity(BATCH_HEADER_LEN + body.len());
+        record.extend_from_slice(&(body.len() as u64).to_le_bytes());
+        record.extend_from_slice(blake3::hash(body.as_bytes()).as_bytes());
+        record.extend_from_slice(body.as_bytes());
+        self.journal.write_all(&record).await?;
+        self.journal.flush().await?;
+        self.journal.sync_data().await?;
+        self.journal_len += record.len() as u64;
+        if self.journal_len >= JOURNAL_COMPACT_BYTES && self.journal_len > self.manifest_len {
+            self.compact().await?;
+        }
+        Ok(())
+    }
+
+    /// Writes the whole manifest and empties the journal.
+    async fn compact(&mut self) -> io::Result<()> {
+        let contents = toml::to_string(&self.manifest)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        write_durably(
+            &self.dir.join(MANIFEST_TMP),
+            &self.dir.join(MANIFEST),
+            contents.as_bytes(),
+        )
+        .await?;
+        // Replaying the journal on top of the new manifest changes nothing, so a crash before
+        // it is emptied is harmless.
+        self.journal.set_len(0).await?;
+        self.journal.sync_data().await?;
+        self.pending.clear();
+        self.journal_len = 0;
+        self.manifest_len = contents.len() as u64;
+        Ok(())
+    }
+
+    /// Makes what was written to local files durable, along with the manifest.
+    async fn sync(&mut self) -> io::Result<()> {
+        for entry in self.manifest.entries.values() {
+            if let OverlayEntry::File { data, .. } = entry {
+                tokio::fs::File::open(self.data_path(data))
+                    .await?
+                    .sync_all()
+                    .await?;
+            }
+        }
+        self.compact().await
+    }
+
+    fn get(&self, path: &str) -> Option<&OverlayEntry> {
+        self.manifest.entries.get(path)
+    }
+
+    /// Entries below `dir`, which are next to each other in the manifest.
+    fn below(&self, dir: &str) -> std::collections::btree_map::Range<'_, String, OverlayEntry> {
+        if dir.is_empty() {
+            return self.manifest.entries.range::<str, _>(..);
+        }
+        let start = format!("{dir}/");
+        let end = past_below(dir);
+        self.manifest.entries.range::<str, _>((
+            Bound::Included(start.as_str()),
+            Bound::Excluded(end.as_str()),
+        ))
+    }
+
+    /// Entries directly inside `dir`, by name. Entries further down are skipped over rather
+    /// than visited one by one.
+    fn children<'a>(&'a self, dir: &str) -> Vec<(&'a str, &'a OverlayEntry)> {
+        let prefix = if dir.is_empty() { 0 } else { dir.len() + 1 };
+        let end = (!dir.is_empty()).then(|| past_below(dir));
+        let end = end.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+        let mut children = vec![];
+        let mut entries = self.below(dir);
+        while let Some((path, entry)) = entries.next() {
+            match path[prefix..].split_once('/') {
+                None => children.push((&path[prefix..], entry)),
+                Some((name, _)) => {
+                    let past = past_below(&path[..prefix + name.len()]);
+                    entries = self
+                        .manifest
+                        .entries
+                        .range::<str, _>((Bound::Included(past.as_str()), end));
+                }
+            }
+        }
+        children
+    }
+
+    /// Entries outside the repo's `.jj` directory. The ones inside it never leave the overlay,
+    /// so they are skipped over rather than visited.
+    fn worktree_entries(&self) -> impl Iterator<Item = (&String, &OverlayEntry)> {
+        let entries = &self.manifest.entries;
+        let repo_dir = format!("{REPO_DIR}/");
+        let past_repo_dir = past_below(REPO_DIR);
+        entries
+            .range::<str, _>((Bound::Unbounded, Bound::Excluded(REPO_DIR)))
+            .chain(entries.range::<str, _>((
+                Bound::Excluded(REPO_DIR),
+                Bound::Excluded(repo_dir.as_str()),
+            )))
+            .chain(
+                entries
+                    .range::<str, _>((Bound::Included(past_repo_dir.as_str()), Bound::Unbounded)),
+            )
+    }
+
+    /// Whether the overlay changes `path`, directly or by changing a directory containing it.
+    fn covers(&self, path: &str) -> bool {
+        let mut path = path;
+        loop {
+            if self.manifest.entries.contains_key(path) {
+                return true;
+            }
+            if path.is_empty() {
+                return false;
+            }
+            path = parent(path);
+        }
+    }
+
+    fn data_path(&self, data: &str) -> PathBuf {
+        self.dir.join(DATA).join(data)
+    }
+
+    /// Creates an empty data file.
+    async fn new_data(&mut self) -> io::Result<String> {
+        let data = self.manifest.next_data.to_string();
+        self.manifest.next_data += 1;
+        self.pending.push(Change::NextData {
+            next_data: self.manifest.next_data,
+        });
+        tokio::fs::File::create(self.data_path(&data)).await?;
+        Ok(data)
+    }
+
+    fn insert(&mut self, path: &str, entry: OverlayEntry) -> Option<OverlayEntry> {
+        self.pending.push(Change::Insert {
+            path: path.to_string(),
+            entry: entry.clone(),
+        });
+        self.manifest.entries.insert(path.to_string(), entry)
+    }
+
+    fn remove(&mut self, path: &str) -> Option<OverlayEntry> {
+        let old = self.manifest.entries.remove(path)?;
+        self.pending.push(Change::Remove {
+            path: path.to_string(),
+        });
+        Some(old)
+    }
+
+    fn set_root_tree(&mut self, id: Id) {
+        self.manifest.root_tree = Some(id);
+        self.pending.push(Change::RootTree { id });
+    }
+
+    /// Takes the entries below `path` out of the overlay, keyed relative to `path`.
+    fn take_below(&mut self, path: &str) -> Vec<(String, OverlayEntry)> {
+        let below: Vec<_> = self.below(path).map(|(entry, _)| entry.clone()).collect();
+        below
+            .into_iter()
+            .map(|entry| {
+                let value = self.remove(&entry).unwrap();
+                (entry[path.len() + 1..].to_string(), value)
+            })
+            .collect()
+    }
+
+    /// Deletes the data files of entries that are no longer in the saved overlay.
+    async fn discard(&self, entries: impl IntoIterator<Item = OverlayEntry>) -> io::Result<()> {
+        for entry in entries {
+            if let OverlayEntry::File { data, .. } = entry {
+                tokio::fs::remove_file(self.data_path(&data)).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Each batch in the journal is preceded by its length and hash.
+const BATCH_HEADER_LEN: usize = 8 + blake3::OUT_LEN;
+
+/// The batches in `journal` up to the first one that wasn't written completely.
+fn read_batches(mut journal: &[u8]) -> Vec<Batch> {
+    let mut batches = vec![];
+    while journal.len() >= BATCH_HEADER_LEN {
+        let (len, rest) = journal.split_at(8);
+        let (hash, rest) = rest.split_at(blake3::OUT_LEN);
+        let len = u64::from_le_bytes(len.try_into().unwrap());
+        let Some(body) = usize::try_from(len).ok().and_then(|len| rest.get(..len)) else {
+            break;
+        };
+        if blake3::hash(body).as_bytes() != hash {
+            break;
+        }
+        let Some(batch) = std::str::from_utf8(body)
+            .ok()
+            .and_then(|body| toml::from_str(body).ok())
+        else {
+            break;
+        };
+        batches.push(batch);
+        journal = &rest[body.len()..];
+    }
+    batches
+}
+
+/// Files a snapshot would start tracking.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct NewFiles {
+    /// Sizes of the files by path
+    pub files: BTreeMap<String, u64>,
+    /// Contents of the `.gitignore` files in the directories leading to the files, by directory
+    pub gitignores: BTreeMap<String, Vec<u8>>,
+}
+
+/// How many files a checkout changed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CheckoutStats {
+    pub updated_files: u32,
+    pub added_files: u32,
+    pub removed_files: u32,
+    /// Files the overlay has changes to, which keep showing the changes
+    pub skipped_files: u32,
+}
+
+/// What a path in the mount points at.
+#[derive(Clone, Debug)]
+enum Node {
+    Tree(Id),
+    Dir,
+    File { id: Id, executable: bool },
+    LocalFile { data: String, executable: bool },
+    Symlink(Id),
+    LocalSymlink(String),
+    Conflict(Id),
+}
+
+impl Node {
+    fn is_dir(&self) -> bool {
+        matches!(self, Node::Tree(_) | Node::Dir)
+    }
+}
+
+impl From<&TreeEntry> for Node {
+    fn from(entry: &TreeEntry) -> Self {
+        match entry {
+            TreeEntry::File { id, executable } => Node::File {
+                id: *id,
+                executable: *executable,
+            },
+            TreeEntry::TreeId(id) => Node::Tree(*id),
+            TreeEntry::SymlinkId(id) => Node::Symlink(*id),
+            TreeEntry::ConflictId(id) => Node::Conflict(*id),
+        }
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+/// The first path that sorts after all the paths below `dir`, as `0` comes right after `/`.
+fn past_below(dir: &str) -> String {
+    format!("{dir}0")
+}
+
+fn is_at_or_below(path: &str, dir: &str) -> bool {
+    path.strip_prefix(dir)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Whether `path` is in one of the directories `patterns` name.
+fn in_sparse_patterns(patterns: &[String], path: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| pattern.is_empty() || is_at_or_below(path, pattern))
+}
+
+/// Whether `path` is a directory leading to one of the directories `patterns` name.
+fn leads_to_sparse_patterns(patterns: &[String], path: &str) -> bool {
+    path.is_empty()
+        || patterns
+            .iter()
+            .any(|pattern| pattern != path && is_at_or_below(pattern, path))
+}
+
+fn nfs_time(time: SystemTime) -> nfstime3 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    nfstime3 {
+        seconds: since_epoch.as_secs() as u32,
+        nseconds: since_epoch.subsec_nanos(),
+    }
+}
+
+fn io_error(err: io::Error) -> nfsstat3 {
+    warn!("Error while serving NFS: {err}");
+    nfsstat3::NFS3ERR_IO
+}
+
+/// Reads up to `count` bytes at `offset`, and whether they reach the end of the file.
+async fn read_range(
+    mut file: tokio::fs::File,
+    offset: u64,
+    count: u32,
+) -> io::Result<(Vec<u8>, bool)> {
+    let len = file.metadata().await?.len();
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut data = vec![];
+    file.take(count.into()).read_to_end(&mut data).await?;
+    let eof = offset.saturating_add(data.len() as u64) >= len;
+    Ok((data, eof))
+}
+
+fn executable(mode: u32) -> bool {
+    mode & 0o111 != 0
+}
+
+impl VirtualFileSystem {
+    /// Serves `root_tree` with the changes recorded in `overlay_dir`, which is created if needed.
+    /// If the overlay has recorded a tree, its changes apply to that one instead.
+    pub async fn new(
+        store: Store,
+        root_tree: Id,
+        overlay_dir: impl Into<PathBuf>,
+    ) -> io::Result<Self> {
+        let mut overlay = Overlay::open(overlay_dir.into()).await?;
+        let root_tree = *overlay.manifest.root_tree.get_or_insert(root_tree);
+        // Everything in the mount belongs to whoever runs the daemon.
+        // SAFETY: getuid and getgid can't fail and have no preconditions.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        Ok(VirtualFileSystem {
+            inner: Arc::new(Inner {
+                store,
+                root_tree: Mutex::new(root_tree),
+                inodes: Mutex::default(),
+                overlay: tokio::sync::Mutex::new(overlay),
+                sparse_patterns: Mutex::new(vec![String::new()]),
+                sizes: Mutex::default(),
+                contents: Mutex::default(),
+                started: nfs_time(SystemTime::now()),
+                uid,
+                gid,
+            }),
+        })
+    }
+
+    /// The tree the overlay applies to.
+    pub fn root_tree(&self) -> Id {
+        *self.inner.root_tree.lock()
+    }
+
+    /// Shows only the paths in the directories `patterns` name, plus the repo's own metadata.
+    ///
+    /// Nothing is written anywhere, the paths outside the patterns are only hidden from the mount
+    /// and snapshots keep them as they are. Returns how many files of the tree appeared or
+    /// disappeared.
+    pub async fn set_sparse_patterns(&self, patterns: Vec<String>) -> io::Result<CheckoutStats> {
+        let _overlay = self.inner.overlay.lock().await;
+        let old_patterns = self.inner.sparse_patterns.lock().clone();
+        let mut stats = CheckoutStats::default();
+        let now = nfs_time(SystemTime::now());
+        let mut pending = vec![(String::new(), self.root_tree())];
+        while let Some((dir, tree_id)) = pending.pop() {
+            self.inner.inodes.lock().set_mtime(&dir, now);
+            for mapping in self.store_tree(tree_id).await?.entries {
+                let path = join(&dir, &mapping.name);
+                let was_shown = in_sparse_patterns(&old_patterns, &path);
+                let is_shown = in_sparse_patterns(&patterns, &path);
+                match mapping.entry {
+                    TreeEntry::TreeId(id) => {
+                        // Only directories that are shown differently can change what's shown.
+                        let unchanged = (was_shown && is_shown)
+                            || !(was_shown
+                                || is_shown
+                                || leads_to_sparse_patterns(&old_patterns, &path)
+                                || leads_to_sparse_patterns(&patterns, &path));
+                        if !unchanged {
+                            pending.push((path, id));
+                        }
+                    }
+                    _ => match (was_shown, is_shown) {
+                        (false, true) => stats.added_files += 1,
+                        (true, false) => stats.removed_files += 1,
+                        _ => {}
+                    },
+                }
+            }
+        }
+        *self.inner.sparse_patterns.lock() = patterns;
+        Ok(stats)
+    }
+
+    /// Whether the mount shows `path`.
+    fn visible(&self, path: &str) -> bool {
+        let patterns = self.inner.sparse_patterns.lock();
+        is_at_or_below(path, REPO_DIR)
+            || in_sparse_patterns(&patterns, path)
+            || leads_to_sparse_patterns(&patterns, path)
+    }
+
+    /// The files in the overlay that the tree it applies to doesn't have, along with the
+    /// `.gitignore` files that decide whether they should be tracked.
+    pub async fn new_files(&self) -> io::Result<NewFiles> {
+        let overlay = self.inner.overlay.lock().await;
+        let mut new_files = NewFiles::default();
+        for (path, entry) in overlay.worktree_entries() {
+            let size = match entry.node()? {
+                Some(Node::LocalFile { data, .. }) => {
+                    tokio::fs::metadata(overlay.data_path(&data)).await?.len()
+                }
+                Some(Node::File { id, .. }) => self.file_size(id).await.map_err(|status| {
+                    io::Error::other(format!("Could not read {path:?}: {status:?}"))
+                })?,
+                Some(Node::LocalSymlink(target)) => target.len() as u64,
+                _ => continue,
+            };
+            if !self.tracked(path).await? {
+                new_files.files.insert(path.clone(), size);
+            }
+        }
+
+        let mut dirs = BTreeSet::new();
+        for path in new_files.files.keys() {
+            let mut dir = path.as_str();
+            while !dir.is_empty() {
+                dir = parent(dir);
+                dirs.insert(dir);
+            }
+        }
+        for dir in dirs {
+            let contents = match self.resolve(&overlay, &join(dir, ".gitignore")).await {
+                Ok(Node::File { id, .. }) => self
+                    .file_contents(id)
+                    .await
+                    .map_err(|status| {
+                        io::Error::other(format!(
+                            "Could not read .gitignore in {dir:?}: {status:?}"
+                        ))
+                    })?
+                    .to_vec(),
+                Ok(Node::LocalFile { data, .. }) => {
+                    tokio::fs::read(overlay.data_path(&data)).await?
+                }
+                _ => continue,
+            };
+            new_files.gitignores.insert(dir.to_string(), contents);
+        }
+        Ok(new_files)
+    }
+
+    /// Whether the tree the overlay applies to has something other than a directory at `path`.
+    async fn tracked(&self, path: &str) -> io::Result<bool> {
+        let mut tree_id = self.root_tree();
+        let mut names = path.split('/').peekable();
+        while let Some(name) = names.next() {
+            let tree = self.store_tree(tree_id).await?;
+            let Some(mapping) = tree.entries.into_iter().find(|m| m.name == name) else {
+                return Ok(false);
+            };
+            match (mapping.entry, names.peek()) {
+                (TreeEntry::TreeId(id), Some(_)) => tree_id = id,
+                (TreeEntry::TreeId(_), None) | (_, Some(_)) => return Ok(false),
+                (_, None) => return Ok(true),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Writes what the mount shows to the store and makes it the tree the overlay applies to.
+    ///
+    /// Only directories with changes below them are rewritten, so this takes time proportional
+    /// to the number of changes rather than to the size of the tree. Changes to the repo's own
+    /// `.jj` directory stay in the overlay, as do the new files in `untracked`, e.g. because
+    /// they are ignored. Files the tree already has are snapshotted either way.
+    pub async fn snapshot(&self, untracked: &BTreeSet<String>) -> io::Result<Id> {
+        let mut overlay = self.inner.overlay.lock().await;
+        let mut new_untracked = BTreeSet::new();
+        for path in untracked {
+            let node = overlay.get(path).map(OverlayEntry::node).transpose()?;
+            if matches!(node.flatten(), Some(node) if !node.is_dir()) && !self.tracked(path).await?
+            {
+                new_untracked.insert(path.clone());
+            }
+        }
+        let untracked = new_untracked;
+        let mut dirty = BTreeSet::from([String::new()]);
+        for (path, _) in overlay.worktree_entries() {
+            let mut dir = path.as_str();
+            while !dir.is_empty() {
+                dir = parent(dir);
+                dirty.insert(dir.to_string());
+            }
+        }
+        // Children are written before the directories containing them.
+        let mut dirty: Vec<_> = dirty.into_iter().collect();
+        dirty.sort_by_key(|dir| Reverse(dir.split('/').filter(|n| !n.is_empty()).count()));
+
+        let mut written: HashMap<String, Option<Id>> = HashMap::new();
+        // Trees don't record empty directories, the overlay keeps them around instead.
+        let mut empty_dirs = vec![];
+        for dir in dirty {
+            let node = match self.resolve(&overlay, &dir).await {
+                Ok(node) if node.is_dir() => node,
+                _ => continue,
+            };
+            let entries = self.list(&overlay, &dir, &node).await.map_err(|status| {
+                io::Error::other(format!("Could not list {dir:?}: {status:?}"))
+            })?;
+            let mut tree = Tree::default();
+            for (name, node) in entries {
+                let path = join(&dir, &name);
+                if path == REPO_DIR || untracked.contains(&path) {
+                    continue;
+                }
+                let entry = match written.remove(&path) {
+                    Some(Some(id)) => TreeEntry::TreeId(id),
+                    Some(None) => {
+                        empty_dirs.push(path);
+                        continue;
+                    }
+                    None => match node {
+                        Node::Tree(id) => TreeEntry::TreeId(id),
+                        Node::Dir => {
+                            empty_dirs.push(path);
+                            continue;
+                        }
+                        Node::File { id, executable } => TreeEntry::File { id, executable },
+                        Node::LocalFile { data, executable } => {
+                            let data_path = overlay.data_path(&data);
+                            // The file keeps the time it was last written at.
+                            let modified = tokio::fs::metadata(&data_path).await?.modified()?;
+                            self.inner
+                                .inodes
+                                .lock()
+                                .set_mtime(&path, nfs_time(modified));
+                            let contents = tokio::fs::read(data_path).await?;
+                            // File contents are stored compressed, like the client does.
+                            let content = zstd::encode_all(contents.as_slice(), 0)?;
+                            let id = self.inner.store.write_file(File { content }).await?;
+                            TreeEntry::File { id, executable }
+                        }
+                        Node::Symlink(id) => TreeEntry::SymlinkId(id),
+                        Node::LocalSymlink(target) => TreeEntry::SymlinkId(
+                            self.inner.store.write_symlink(Symlink { target }).await?,
+                        ),
+                        Node::Conflict(id) => TreeEntry::ConflictId(id),
+                    },
+                };
+                tree.entries.push(TreeEntryMapping { name, entry });
+            }
+            let id = if tree.entries.is_empty() && !dir.is_empty() {
+                None
+            } else {
+                Some(self.inner.store.write_tree(tree).await?)
+            };
+            written.insert(dir, id);
+        }
+        let root_tree = written
+            .remove("")
+            .flatten()
+            .unwrap_or_else(|| self.inner.store.get_empty_tree_id());
+
+        // Everything but the repo's metadata, untracked files and empty directories is in the new
+        // tree now. The new tree is saved along with the entries that are left, and data files
+        // are only deleted once nothing refers to them anymore.
+        let snapshotted: Vec<_> = overlay
+            .worktree_entries()
+            .map(|(path, _)| path.clone())
+            .filter(|path| !untracked.contains(path))
+            .collect();
+        let snapshotted: Vec<_> = snapshotted
+            .into_iter()
+            .filter_map(|path| overlay.remove(&path))
+            .collect();
+        for path in empty_dirs {
+            overlay.insert(&path, OverlayEntry::Dir);
+        }
+        overlay.set_root_tree(root_tree);
+        overlay.save().await?;
+        *self.inner.root_tree.lock() = root_tree;
+        overlay.discard(snapshotted).await?;
+        Ok(root_tree)
+    }
+
+    /// Makes `tree_id` the tree the overlay applies to, if it still applies to `old_tree_id`.
+    /// Returns nothing otherwise, e.g. because of a concurrent checkout.
+    ///
+    /// Changes in the overlay carry over to the new tree, so files they cover are skipped
+    /// rather than updated. Only the parts of the trees that differ are read.
+    pub async fn check_out(
+        &self,
+        old_tree_id: Id,
+        tree_id: Id,
+    ) -> io::Result<Option<CheckoutStats>> {
+        let mut overlay = self.inner.overlay.lock().await;
+        if self.root_tree() != old_tree_id {
+            return Ok(None);
+        }
+        let mut files = BTreeMap::<String, (bool, bool)>::new();
+        for (path, old, new) in self.diff(self.root_tree(), tree_id).await? {
+            for path in self.files(path.clone(), old).await? {
+                files.entry(path).or_default().0 = true;
+            }
+            for path in self.files(path, new).await? {
+                files.entry(path).or_default().1 = true;
+            }
+        }
+
+        let mut stats = CheckoutStats::default();
+        let now = nfs_time(SystemTime::now());
+        for (path, (old, new)) in files {
+            if overlay.covers(&path) {
+                stats.skipped_files += 1;
+                continue;
+            }
+            let count = match (old, new) {
+                (true, true) => &mut stats.updated_files,
+                (false, _) => &mut stats.added_files,
+                (_, false) => &mut stats.removed_files,
+            };
+            *count += 1;
+            // Clients only notice new contents under the same file id by their time.
+            self.inner.inodes.lock().touch(&path, now);
+        }
+        overlay.set_root_tree(tree_id);
+        overlay.save().await?;
+        *self.inner.root_tree.lock() = tree_id;
+        Ok(Some(stats))
+    }
+
+    /// Makes `tree_id` the tree the overlay applies to, without changing what the mount shows.
+    ///
+    /// Wherever the new tree differs, the overlay takes over what the old tree had there, so
+    /// the next snapshot records the difference as changes.
+    pub async fn reset(&self, tree_id: Id) -> io::Result<()> {
+        let mut overlay = self.inner.overlay.lock().await;
+        for (path, old, _) in self.diff(self.root_tree(), tree_id).await? {
+            if overlay.covers(&path) {
+                continue;
+            }
+            let entry = match old {
+                Some(old) => self.entry_for((&old).into()).await.map_err(|status| {
+                    io::Error::other(format!("Could not keep {path:?}: {status:?}"))
+                })?,
+                None => OverlayEntry::Removed,
+            };
+            overlay.insert(&path, entry);
+        }
+        overlay.set_root_tree(tree_id);
+        overlay.save().await?;
+        *self.inner.root_tree.lock() = tree_id;
+        Ok(())
+    }
+
+    /// Like [`reset`](Self::reset), for when the tree the overlay applies to may be gone from
+    /// the store. Without it, only the changes in the overlay carry over.
+    pub async fn recover(&self, tree_id: Id) -> io::Result<()> {
+        match self.reset(tree_id).await {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let mut overlay = self.inner.overlay.lock().await;
+                overlay.set_root_tree(tree_id);
+                overlay.save().await?;
+                *self.inner.root_tree.lock() = tree_id;
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Whether the mount shows anything outside the repo's metadata that the tree doesn't have,
+    /// e.g. changes that were not snapshotted or untracked files.
+    pub async fn has_changes(&self) -> bool {
+        let overlay = self.inner.overlay.lock().await;
+        let has_changes = overlay.worktree_entries().next().is_some();
+        has_changes
+    }
+
+    /// Waits for writes in progress and makes the overlay durable, e.g. before the working copy
+    /// stops being served.
+    pub async fn flush(&self) -> io::Result<()> {
+        let mut overlay = self.inner.overlay.lock().await;
+        overlay.sync().await
+    }
+
+    /// The paths at which two trees differ. Directories only one side has, or that are
+    /// something else on the other side, are reported as a whole rather than descended into.
+    async fn diff(
+        &self,
+        old: Id,
+        new: Id,
+    ) -> io::Result<Vec<(String, Option<TreeEntry>, Option<TreeEntry>)>> {
+        let mut changes = vec![];
+        let mut pending = vec![(String::new(), old, new)];
+        while let Some((dir, old, new)) = pending.pop() {
+            if old == new {
+                continue;
+            }
+            let mut entries = BTreeMap::<String, (Option<TreeEntry>, Option<TreeEntry>)>::new();
+            for mapping in self.store_tree(old).await?.entries {
+                entries.entry(mapping.name).or_default().0 = Some(mapping.entry);
+            }
+            for mapping in self.store_tree(new).await?.entries {
+                entries.entry(mapping.name).or_default().1 = Some(mapping.entry);
+            }
+            for (name, entries) in entries {
+                let path = join(&dir, &name);
+                match entries {
+                    (old, new) if old == new => {}
+                    (Some(TreeEntry::TreeId(old)), Some(TreeEntry::TreeId(new))) => {
+                        pending.push((path, old, new))
+                    }
+                    (old, new) => changes.push((path, old, new)),
+                }
+            }
+        }
+        Ok(changes)
+    }
+
+    /// The files at or below `path`, which has `entry` in some tree.
+    async fn files(&self, path: String, entry: Option<TreeEntry>) -> io::Result<Vec<String>> {
+        let mut files = vec![];
+        let mut pending: Vec<_> = entry.map(|entry| (path, entry)).into_iter().collect();
+        while let Some((path, entry)) = pending.pop() {
+            match entry {
+                TreeEntry::TreeId(id) => {
+                    for mapping in self.store_tree(id).await?.entries {
+                        pending.push((join(&path, &mapping.name), mapping.entry));
+                    }
+                }
+                _ => files.push(path),
+            }
+        }
+        Ok(files)
+    }
+
+    async fn store_tree(&self, id: Id) -> io::Result<Tree> {
+        self.inner.store.get_tree(id).await?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Tree {} not found", id.hex()),
+            )
+        })
+    }
+
+    fn path(&self, id: fileid3) -> Result<String, nfsstat3> {
+        self.inner.inodes.lock().path(id)
+    }
+
+    fn id(&self, path: &str) -> fileid3 {
+        self.inner.inodes.lock().id(path)
+    }
+
+    async fn tree(&self, id: Id) -> Result<Tree, nfsstat3> {
+        self.inner
+            .store
+            .get_tree(id)
+            .await
+            .map_err(io_error)?
+            .ok_or(nfsstat3::NFS3ERR_IO)
+    }
+
+    async fn store_file(&self, id: Id) -> Result<File, nfsstat3> {
+        self.inner
+            .store
+            .get_file(id)
+            .await
+            .map_err(io_error)?
+            .ok_or(nfsstat3::NFS3ERR_IO)
+    }
+
+    /// File contents are stored compressed by the client.
+    async fn file_contents(&self, id: Id) -> Result<Arc<Vec<u8>>, nfsstat3> {
+        if let Some(contents) = self.inner.contents.lock().get(id) {
+            return Ok(contents);
+        }
+        let file = self.store_file(id).await?;
+        let contents = Arc::new(zstd::decode_all(file.content.as_slice()).map_err(io_error)?);
+        self.inner.sizes.lock().insert(id, contents.len() as u64);
+        self.inner.contents.lock().insert(id, contents.clone());
+        Ok(contents)
+    }
+
+    /// The size of a file's contents, decompressing them once without keeping them.
+    async fn file_size(&self, id: Id) -> Result<u64, nfsstat3> {
+        if let Some(size) = self.inner.sizes.lock().get(&id) {
+            return Ok(*size);
+        }
+        let file = self.store_file(id).await?;
+        let mut decoder = zstd::Decoder::new(file.content.as_slice()).map_err(io_error)?;
+        let size = io::copy(&mut decoder, &mut io::sink()).map_err(io_error)?;
+        self.inner.sizes.lock().insert(id, size);
+        Ok(size)
+    }
+
+    async fn symlink_target(&self, id: Id) -> Result<String, nfsstat3> {
+        let symlink = self
+            .inner
+            .store
+            .get_symlink(id)
+            .await
+            .map_err(io_error)?
+            .ok_or(nfsstat3::NFS3ERR_IO)?;
+        Ok(symlink.target)
+    }
+
+    /// Finds what `path` points at, looking at the overlay before the tree.
+    async fn resolve(&self, overlay: &Overlay, path: &str) -> Result<Node, nfsstat3> {
+        if let Some(entry) = overlay.get(path) {
+            return entry
+                .node()
+                .map_err(io_error)?
+                .ok_or(nfsstat3::NFS3ERR_NOENT);
+        }
+        // The closest ancestor with an overlay entry decides where the rest of the path comes
+        // from.
+        let mut start = (Node::Tree(self.root_tree()), path);
+        let mut dir = parent(path);
+        while !dir.is_empty() {
+            if let Some(entry) = overlay.get(dir) {
+                match entry.node().map_err(io_error)? {
+                    Some(node @ Node::Tree(_)) => start = (node, &path[dir.len() + 1..]),
+                    // Nothing below a new directory, a removed path or a file is in the tree.
+                    _ => return Err(nfsstat3::NFS3ERR_NOENT),
+                }
+                break;
+            }
+            dir = parent(dir);
+        }
+
+        let (mut node, rest) = start;
+        for name in rest.split('/').filter(|name| !name.is_empty()) {
+            let Node::Tree(tree_id) = node else {
+                return Err(nfsstat3::NFS3ERR_NOTDIR);
+            };
+            let tree = self.tree(tree_id).await?;
+            let entry = tree
+                .entries
+                .iter()
+                .find(|mapping| mapping.name == name)
+                .ok_or(nfsstat3::NFS3ERR_NOENT)?;
+            node = (&entry.entry).into();
+        }
+        Ok(node)
+    }
+
+    /// The contents of directory `dir`, with the overlay applied.
+    async fn list(
+        &self,
+        overlay: &Overlay,
+        dir: &str,
+        node: &Node,
+    ) -> Result<BTreeMap<String, Node>, nfsstat3> {
+        let mut entries = BTreeMap::new();
+        match node {
+            Node::Tree(tree_id) => {
+                for mapping in self.tree(*tree_id).await?.entries {
+                    entries.insert(mapping.name, (&mapping.entry).into());
+                }
+            }
+            Node::Dir => {}
+            _ => return Err(nfsstat3::NFS3ERR_NOTDIR),
+        }
+        for (name, entry) in overlay.children(dir) {
+            match entry.node().map_err(io_error)? {
+                Some(node) => entries.insert(name.to_string(), node),
+                None => entries.remove(name),
+            };
+        }
+        Ok(entries)
+    }
+
+    /// The path of `name` in directory `dirid`.
+    async fn child_path(
+        &self,
+        overlay: &Overlay,
+        dirid: fileid3,
+        name: &filename3,
+    ) -> Result<String, nfsstat3> {
+        let dir = self.path(dirid)?;
+        if !self.resolve(overlay, &dir).await?.is_dir() {
+            return Err(nfsstat3::NFS3ERR_NOTDIR);
+        }
+        let name = std::str::from_utf8(name).map_err(|_| nfsstat3::NFS3ERR_INVAL)?;
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(nfsstat3::NFS3ERR_INVAL);
+        }
+        let path = join(&dir, name);
+        // Changes outside the sparse patterns would be hidden right away.
+        if !self.visible(&path) {
+            return Err(nfsstat3::NFS3ERR_ACCES);
+        }
+        Ok(path)
+    }
+
+    /// Puts `entry` at `path`, dropping whatever the overlay had there and below.
+    async fn replace(
+        &self,
+        overlay: &mut Overlay,
+        path: &str,
+        entry: OverlayEntry,
+    ) -> io::Result<()> {
+        let below = overlay.take_below(path);
+        let old = overlay.insert(path, entry);
+        overlay.save().await?;
+        overlay
+            .discard(below.into_iter().map(|(_, entry)| entry).chain(old))
+            .await
+    }
+
+    /// Makes sure the file at `path` has its own data file, copying the contents from the store.
+    async fn materialize(
+        &self,
+        overlay: &mut Overlay,
+        path: &str,
+        node: Node,
+    ) -> Result<(String, bool), nfsstat3> {
+        let (contents, executable) = match node {
+            Node::LocalFile { data, executable } => return Ok((data, executable)),
+            Node::File { id, executable } => (self.file_contents(id).await?, executable),
+            // Conflicts are resolved through jj, writing to one would silently drop it.
+            Node::Conflict(_) => return Err(nfsstat3::NFS3ERR_ACCES),
+            Node::Tree(_) | Node::Dir => return Err(nfsstat3::NFS3ERR_ISDIR),
+            Node::Symlink(_) | Node::LocalSymlink(_) => return Err(nfsstat3::NFS3ERR_INVAL),
+        };
+        let data = overlay.new_data().await.map_err(io_error)?;
+        tokio::fs::write(overlay.data_path(&data), contents.as_slice())
+            .await
+            .map_err(io_error)?;
+        let entry = OverlayEntry::File {
+            data: data.clone(),
+            executable,
+        };
+        self.replace(overlay, path, entry).await.map_err(io_error)?;
+        Ok((data, executable))
+    }
+
+    async fn create_file(
+        &self,
+        overlay: &mut Overlay,
+        path: &str,
+        executable: bool,
+    ) -> Result<Node, nfsstat3> {
+        let data = overlay.new_data().await.map_err(io_error)?;
+        let entry = OverlayEntry::File {
+            data: data.clone(),
+            executable,
+        };
+        self.replace(overlay, path, entry).await.map_err(io_error)?;
+        Ok(Node::LocalFile { data, executable })
+    }
+
+    /// Removes `path` from the overlay, hiding what the tree has there if anything.
+    async fn remove_path(&self, overlay: &mut Overlay, path: &str) -> Result<(), nfsstat3> {
+        let below = overlay.take_below(path);
+        let old = overlay.remove(path);
+        if self.resolve(overlay, path).await.is_ok() {
+            overlay.insert(path, OverlayEntry::Removed);
+        }
+        overlay.save().await.map_err(io_error)?;
+        overlay
+            .discard(below.into_iter().map(|(_, entry)| entry).chain(old))
+            .await
+            .map_err(io_error)
+    }
+
+    /// The overlay entry that recreates `node` somewhere else.
+    async fn entry_for(&self, node: Node) -> Result<OverlayEntry, nfsstat3> {
+        Ok(match node {
+            Node::Tree(id) => OverlayEntry::Tree { id: id.hex() },
+            Node::Dir => OverlayEntry::Dir,
+            Node::File { id, executable } => OverlayEntry::StoreFile {
+                id: id.hex(),
+                executable,
+            },
+            Node::LocalFile { data, executable } => OverlayEntry::File { data, executable },
+            Node::Symlink(id) => OverlayEntry::Symlink {
+                target: self.symlink_target(id).await?,
+            },
+            Node::LocalSymlink(target) => OverlayEntry::Symlink { target },
+            Node::Conflict(id) => OverlayEntry::Conflict { id: id.hex() },
+        })
+    }
+
+    async fn attr(
+        &self,
+        overlay: &Overlay,
+        fileid: fileid3,
+        node: &Node,
+    ) -> Result<fattr3, nfsstat3> {
+        let mut mtime = self
+            .inner
+            .inodes
+            .lock()
+            .mtimes
+            .get(&fileid)
+            .copied()
+            .unwrap_or(self.inner.started);
+        let (ftype, mode, nlink, size) = match node {
+            Node::Tree(_) | Node::Dir => (ftype3::NF3DIR, 0o755, 2, 0),
+            Node::File { id, executable } => {
+                let size = self.file_size(*id).await?;
+                let mode = if *executable { 0o755 } else { 0o644 };
+                (ftype3::NF3REG, mode, 1, size)
+            }
+            Node::LocalFile { data, executable } => {
+                let metadata = tokio::fs::metadata(overlay.data_path(data))
+                    .await
+                    .map_err(io_error)?;
+                if let Ok(modified) = metadata.modified() {
+                    mtime = nfs_time(modified);
+                }
+                let mode = if *executable { 0o755 } else { 0o644 };
+                (ftype3::NF3REG, mode, 1, metadata.len())
+            }
+            Node::Symlink(id) => {
+                let size = self.symlink_target(*id).await?.len() as u64;
+                (ftype3::NF3LNK, 0o777, 1, size)
+            }
+            Node::LocalSymlink(target) => (ftype3::NF3LNK, 0o777, 1, target.len() as u64),
+            // Conflicts aren't materialized, they show up as empty read-only files.
+            Node::Conflict(_) => (ftype3::NF3REG, 0o444, 1, 0),
+        };
+        Ok(fattr3 {
+            ftype,
+            mode,
+            nlink,
+            uid: self.inner.uid,
+            gid: self.inner.gid,
+            size,
+            used: size,
+            rdev: Default::default(),
+            fsid: 0,
+            fileid,
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+        })
     }
 }
 
 #[async_trait]
 impl NFSFileSystem for VirtualFileSystem {
     fn root_dir(&self) -> fileid3 {
-        self.rootdir
+        ROOT_ID
     }
 
     fn capabilities(&self) -> VFSCapabilities {
         VFSCapabilities::ReadWrite
     }
 
-    async fn write(&self, _id: fileid3, _offset: u64, _data: &[u8]) -> Result<fattr3, nfsstat3> {
-        Err(nfsstat3::NFS3ERR_NOTSUPP)
+    async fn write(&self, id: fileid3, offset: u64, data: &[u8]) -> Result<fattr3, nfsstat3> {
+        let mut overlay = self.inner.overlay.lock().await;
+        let path = self.path(id)?;
+        let node = self.resolve(&overlay, &path).await?;
+        let (data_name, executable) = self.materialize(&mut overlay, &path, node).await?;
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(overlay.data_path(&data_name))
+            .await
+            .map_err(io_error)?;
+        file.seek(SeekFrom::Start(offset)).await.map_err(io_error)?;
+        file.write_all(data).await.map_err(io_error)?;
+        file.flush().await.map_err(io_error)?;
+
+        let node = Node::LocalFile {
+            data: data_name,
+            executable,
+        };
+        self.attr(&overlay, id, &node).await
     }
 
     async fn create(
         &self,
-        _dirid: fileid3,
-        _filename: &filename3,
-        _attr: sattr3,
+        dirid: fileid3,
+        filename: &filename3,
+        attr: sattr3,
     ) -> Result<(fileid3, fattr3), nfsstat3> {
-        Err(nfsstat3::NFS3ERR_NOTSUPP)
+        let mut overlay = self.inner.overlay.lock().await;
+        let path = self.child_path(&overlay, dirid, filename).await?;
+        if let Ok(node) = self.resolve(&overlay, &path).await {
+            if node.is_dir() {
+                return Err(nfsstat3::NFS3ERR_EXIST);
+            }
+        }
+        let executable = matches!(attr.mode, set_mode3::mode(mode) if executable(mode));
+        let node = self.create_file(&mut overlay, &path, executable).await?;
+        if let (set_size3::size(size), Node::LocalFile { data, .. }) = (attr.size, &node) {
+            let file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .open(overlay.data_path(data))
+                .await
+                .map_err(io_error)?;
+            file.set_len(size).await.map_err(io_error)?;
+        }
+        let id = self.id(&path);
+        Ok((id, self.attr(&overlay, id, &node).await?))
     }
 
     async fn create_exclusive(
         &self,
-        _dirid: fileid3,
-        _filename: &filename3,
+        dirid: fileid3,
+        filename: &filename3,
     ) -> Result<fileid3, nfsstat3> {
-        Err(nfsstat3::NFS3ERR_NOTSUPP)
+        let mut overlay = self.inner.overlay.lock().await;
+        let path = self.child_path(&overlay, dirid, filename).await?;
+        if self.resolve(&overlay, &path).await.is_ok() {
+            return Err(nfsstat3::NFS3ERR_EXIST);
+        }
+        self.create_file(&mut overlay, &path, false).await?;
+        Ok(self.id(&path))
     }
 
-    async fn lookup(&self, _dirid: fileid3, _filename: &filename3) -> Result<fileid3, nfsstat3> {
-        Err(nfsstat3::NFS3ERR_NOTSUPP)
+    async fn lookup(&self, dirid: fileid3, filename: &filename3) -> Result<fileid3, nfsstat3> {
+        let overlay = self.inner.overlay.lock().await;
+        let dir = self.path(dirid)?;
+        if !self.resolve(&overlay, &dir).await?.is_dir() {
+            return Err(nfsstat3::NFS3ERR_NOTDIR);
+        }
+        let name = std::str::from_utf8(filename).map_err(|_| nfsstat3::NFS3ERR_NOENT)?;
+        let path = match name {
+            "." => return Ok(dirid),
+            ".." => parent(&dir).to_string(),
+            _ => join(&dir, name),
+        };
+        if !self.visible(&path) {
+            return Err(nfsstat3::NFS3ERR_NOENT);
+        }
+        self.resolve(&overlay, &path).await?;
+        Ok(self.id(&path))
     }
 
-    async fn getattr(&self, _id: fileid3) -> Result<fattr3, nfsstat3> {
-        Err(nfsstat3::NFS3ERR_NOTSUPP)
+    async fn getattr(&self, id: fileid3) -> Result<fattr3, nfsstat3> {
+        let overlay = self.inner.overlay.lock().await;
+        let path = self.path(id)?;
+        let node = self.resolve(&overlay, &path).await?;
+        self.attr(&overlay, id, &node).await
     }
 
-    async fn setattr(&self, _id: fileid3, _setattr: sattr3) -> Result<fattr3, nfsstat3> {
-        Err(nfsstat3::NFS3ERR_NOTSUPP)
+    async fn setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        let mut overlay = self.inner.overlay.lock().await;
+        let path = self.path(id)?;
+        let mut node = self.resolve(&overlay, &path).await?;
+        if node.is_dir() {
+            return self.attr(&overlay, id, &node).await;
+        }
+
+        if let set_size3::size(size) = setattr.size {
+            let (data, executable) = self.materialize(&mut overlay, &path, node).await?;
+            let file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .open(overlay.data_path(&data))
+                .await
+                .map_err(io_error)?;
+            file.set_len(size).await.map_err(io_error)?;
+            node = Node::LocalFile { data, executable };
+        }
+        if let set_mode3::mode(mode) = setattr.mode {
+            let is_executable = match &node {
+                Node::File { executable, .. } | Node::LocalFile { executable, .. } => *executable,
+                _ => executable(mode),
+            };
+            if is_executable != executable(mode) {
+                let (data, _) = self.materialize(&mut overlay, &path, node).await?;
+                let entry = OverlayEntry::File {
+                    data: data.clone(),
+                    executable: executable(mode),
+                };
+                overlay.insert(&path, entry);
+                overlay.save().await.map_err(io_error)?;
+                node = Node::LocalFile {
+                    data,
+                    executable: executable(mode),
+                };
+            }
+        }
+        self.attr(&overlay, id, &node).await
     }
 
     async fn read(
         &self,
-        _id: fileid3,
-        _offset: u64,
-        _count: u32,
+        id: fileid3,
+        offset: u64,
+        count: u32,
     ) -> Result<(Vec<u8>, bool), nfsstat3> {
-        Err(nfsstat3::NFS3ERR_NOTSUPP)
+        // Only finding the file needs the overlay, reading it happens without blocking others.
+        let overlay = self.inner.overlay.lock().await;
+        let path = self.path(id)?;
+        let id = match self.resolve(&overlay, &path).await? {
+            Node::File { id, .. } => id,
+            Node::LocalFile { data, .. } => {
+                // An open data file stays readable even if the path is removed meanwhile.
+                let file = tokio::fs::File::open(overlay.data_path(&data))
+                    .await
+                    .map_err(io_error)?;
+                drop(overlay);
+                return read_range(file, offset, count).await.map_err(io_error);
+            }
+            Node::Conflict(_) => return Ok((vec![], true)),
+            Node::Tree(_) | Node::Dir => return Err(nfsstat3::NFS3ERR_ISDIR),
+            Node::Symlink(_) | Node::LocalSymlink(_) => return Err(nfsstat3::NFS3ERR_INVAL),
+        };
+        drop(overlay);
+        let contents = self.file_contents(id).await?;
+        let start = (offset as usize).min(contents.len());
+        let end = start.saturating_add(count as usize).min(contents.len());
+        Ok((contents[start..end].to_vec(), end == contents.len()))
     }
 
     async fn readdir(
         &self,
-        _dirid: fileid3,
-        _start_after: fileid3,
-        _max_entries: usize,
+        dirid: fileid3,
+        start_after: fileid3,
+        max_entries: usize,
     ) -> Result<ReadDirResult, nfsstat3> {
-        Err(nfsstat3::NFS3ERR_NOTSUPP)
+        let overlay = self.inner.overlay.lock().await;
+        let dir = self.path(dirid)?;
+        let node = self.resolve(&overlay, &dir).await?;
+        let mut listing = self.list(&overlay, &dir, &node).await?;
+        listing.retain(|name, _| self.visible(&join(&dir, name)));
+        let mut entries = listing.iter().peekable();
+        if start_after != 0 {
+            let after = self.path(start_after)?;
+            entries
+                .by_ref()
+                .find(|(name, _)| join(&dir, name) == after)
+                .ok_or(nfsstat3::NFS3ERR_BAD_COOKIE)?;
+        }
+
+        let mut result = ReadDirResult::default();
+        while result.entries.len() < max_entries {
+            let Some((name, node)) = entries.next() else {
+                break;
+            };
+            let fileid = self.id(&join(&dir, name));
+            result.entries.push(DirEntry {
+                fileid,
+                name: name.as_bytes().into(),
+                attr: self.attr(&overlay, fileid, node).await?,
+            });
+        }
+        result.end = entries.peek().is_none();
+        Ok(result)
     }
 
-    async fn remove(&self, _dirid: fileid3, _filename: &filename3) -> Result<(), nfsstat3> {
-        Err(nfsstat3::NFS3ERR_NOTSUPP)
+    async fn remove(&self, dirid: fileid3, filename: &filename3) -> Result<(), nfsstat3> {
+        let mut overlay = self.inner.overlay.lock().await;
+        let path = self.child_path(&overlay, dirid, filename).await?;
+        let node = self.resolve(&overlay, &path).await?;
+        if node.is_dir() && !self.list(&overlay, &path, &node).await?.is_empty() {
+            return Err(nfsstat3::NFS3ERR_NOTEMPTY);
+        }
+        self.remove_path(&mut overlay, &path).await
     }
 
     async fn rename(
         &self,
-        _from_dirid: fileid3,
-        _from_filename: &filename3,
-        _to_dirid: fileid3,
-        _to_filename: &filename3,
+        from_dirid: fileid3,
+        from_filename: &filename3,
+        to_dirid: fileid3,
+        to_filename: &filename3,
     ) -> Result<(), nfsstat3> {
-        Err(nfsstat3::NFS3ERR_NOTSUPP)
+        let mut overlay = self.inner.overlay.lock().await;
+        let from = self.child_path(&overlay, from_dirid, from_filename).await?;
+        let to = self.child_path(&overlay, to_dirid, to_filename).await?;
+        if from == to {
+            return Ok(());
+        }
+        if is_at_or_below(&to, &from) {
+            return Err(nfsstat3::NFS3ERR_INVAL);
+        }
+        let node = self.resolve(&overlay, &from).await?;
+        if let Ok(existing) = self.resolve(&overlay, &to).await {
+            match (node.is_dir(), existing.is_dir()) {
+                (true, true) => {
+                    if !self.list(&overlay, &to, &existing).await?.is_empty() {
+                        return Err(nfsstat3::NFS3ERR_NOTEMPTY);
+                    }
+                }
+                (false, true) => return Err(nfsstat3::NFS3ERR_ISDIR),
+                (true, false) => return Err(nfsstat3::NFS3ERR_NOTDIR),
+                (false, false) => {}
+            }
+        }
+
+        // Changes below `from` move along with it. Its data files now belong to `to`, so they
+        // are taken out of the overlay without being discarded.
+        let entry = self.entry_for(node).await?;
+        let moved = overlay.take_below(&from);
+        overlay.remove(&from);
+        if self.resolve(&overlay, &from).await.is_ok() {
+            overlay.insert(&from, OverlayEntry::Removed);
+        }
+
+        let replaced = overlay.take_below(&to);
+        let old = overlay.insert(&to, entry);
+        for (rel, entry) in moved {
+            overlay.insert(&join(&to, &rel), entry);
+        }
+        overlay.save().await.map_err(io_error)?;
+        overlay
+            .discard(replaced.into_iter().map(|(_, entry)| entry).chain(old))
+            .await
+            .map_err(io_error)?;
+
+        self.inner.inodes.lock().rename(&from, &to);
+        Ok(())
     }
 
     async fn mkdir(
         &self,
-        _dirid: fileid3,
-        _dirname: &filename3,
+        dirid: fileid3,
+        dirname: &filename3,
     ) -> Result<(fileid3, fattr3), nfsstat3> {
-        Err(nfsstat3::NFS3ERR_NOTSUPP)
+        let mut overlay = self.inner.overlay.lock().await;
+        let path = self.child_path(&overlay, dirid, dirname).await?;
+        if self.resolve(&overlay, &path).await.is_ok() {
+            return Err(nfsstat3::NFS3ERR_EXIST);
+        }
+        self.replace(&mut overlay, &path, OverlayEntry::Dir)
+            .await
+            .map_err(io_error)?;
+        let id = self.id(&path);
+        Ok((id, self.attr(&overlay, id, &Node::Dir).await?))
     }
 
     async fn symlink(
         &self,
-        _dirid: fileid3,
-        _linkname: &filename3,
-        _symlink: &nfspath3,
+        dirid: fileid3,
+        linkname: &filename3,
+        symlink: &nfspath3,
         _attr: &sattr3,
     ) -> Result<(fileid3, fattr3), nfsstat3> {
-        Err(nfsstat3::NFS3ERR_NOTSUPP)
+        let mut overlay = self.inner.overlay.lock().await;
+        let path = self.child_path(&overlay, dirid, linkname).await?;
+        if self.resolve(&overlay, &path).await.is_ok() {
+            return Err(nfsstat3::NFS3ERR_EXIST);
+        }
+        let target = String::from_utf8(symlink.0.clone()).map_err(|_| nfsstat3::NFS3ERR_INVAL)?;
+        let entry = OverlayEntry::Symlink {
+            target: target.clone(),
+        };
+        self.replace(&mut overlay, &path, entry)
+            .await
+            .map_err(io_error)?;
+        let id = self.id(&path);
+        let attr = self.attr(&overlay, id, &Node::LocalSymlink(target)).await?;
+        Ok((id, attr))
+    }
+
+    async fn readlink(&self, id: fileid3) -> Result<nfspath3, nfsstat3> {
+        let overlay = self.inner.overlay.lock().await;
+        let path = self.path(id)?;
+        let target = match self.resolve(&overlay, &path).await? {
+            Node::Symlink(symlink_id) => self.symlink_target(symlink_id).await?,
+            Node::LocalSymlink(target) => target,
+            _ => return Err(nfsstat3::NFS3ERR_INVAL),
+        };
+        Ok(target.into_bytes().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use nfsserve::{
+        nfs::{diropargs3, nfs_fh3, post_op_attr, wcc_data},
+        tcp::{NFSTcp, NFSTcpListener},
+        xdr::XDR,
+    };
+
+    use super::*;
+    use crate::{
+        object_store::InMemoryObjectStore,
+        ty::{Conflict, File, Symlink, TreeEntryMapping},
+    };
+
+    async fn write_file(store: &Store, contents: &[u8]) -> Id {
+        let content = zstd::encode_all(contents, 0).unwrap();
+        store.write_file(File { content }).await.unwrap()
     }
 
-    async fn readlink(&self, _id: fileid3) -> Result<nfspath3, nfsstat3> {
-        Err(nfsstat3::NFS3ERR_NOTSUPP)
+    /// A tree with `README`, `bin/run` (executable), `bin/link -> run` and an empty `empty/`,
+    /// served with an overlay in a fresh temporary directory.
+    async fn example() -> (VirtualFileSystem, tempfile::TempDir) {
+        let store = Store::new(Arc::new(InMemoryObjectStore::default()))
+            .await
+            .unwrap();
+        let readme = write_file(&store, b"hello world").await;
+        let run = write_file(&store, b"#!/bin/sh").await;
+        let link = store
+            .write_symlink(Symlink {
+                target: "run".to_string(),
+            })
+            .await
+            .unwrap();
+        let bin = store
+            .write_tree(Tree {
+                entries: vec![
+                    TreeEntryMapping {
+                        name: "link".to_string(),
+                        entry: TreeEntry::SymlinkId(link),
+                    },
+                    TreeEntryMapping {
+                        name: "run".to_string(),
+                        entry: TreeEntry::File {
+                            id: run,
+                            executable: true,
+                        },
+                    },
+                ],
+            })
+            .await
+            .unwrap();
+        let root = store
+            .write_tree(Tree {
+                entries: vec![
+                    TreeEntryMapping {
+                        name: "README".to_string(),
+                        entry: TreeEntry::File {
+                            id: readme,
+                            executable: false,
+                        },
+                    },
+                    TreeEntryMapping {
+                        name: "bin".to_string(),
+                        entry: TreeEntry::TreeId(bin),
+                    },
+                    TreeEntryMapping {
+                        name: "empty".to_string(),
+                        entry: TreeEntry::TreeId(store.get_empty_tree_id()),
+                    },
+                ],
+            })
+            .await
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let fs = VirtualFileSystem::new(store, root, dir.path())
+            .await
+            .unwrap();
+        (fs, dir)
+    }
+
+    async fn read_all(fs: &VirtualFileSystem, id: fileid3) -> Vec<u8> {
+        fs.read(id, 0, u32::MAX).await.unwrap().0
+    }
+
+    async fn names(fs: &VirtualFileSystem, dir: fileid3) -> Vec<String> {
+        let result = fs.readdir(dir, 0, 100).await.unwrap();
+        result
+            .entries
+            .iter()
+            .map(|entry| String::from_utf8(entry.name.0.clone()).unwrap())
+            .collect()
+    }
+
+    fn name(name: &str) -> filename3 {
+        name.as_bytes().into()
+    }
+
+    #[tokio::test]
+    async fn lookup_and_read() {
+        let (fs, _dir) = example().await;
+        let root = fs.root_dir();
+
+        let readme = fs.lookup(root, &name("README")).await.unwrap();
+        // Ids are stable per path.
+        assert_eq!(fs.lookup(root, &name("README")).await.unwrap(), readme);
+        let attr = fs.getattr(readme).await.unwrap();
+        assert!(matches!(attr.ftype, ftype3::NF3REG));
+        assert_eq!(attr.mode, 0o644);
+        assert_eq!(attr.size, 11);
+        assert_eq!(
+            fs.read(readme, 0, 1024).await.unwrap(),
+            (b"hello world".to_vec(), true)
+        );
+        assert_eq!(
+            fs.read(readme, 6, 3).await.unwrap(),
+            (b"wor".to_vec(), false)
+        );
+
+        let bin = fs.lookup(root, &name("bin")).await.unwrap();
+        assert!(matches!(
+            fs.getattr(bin).await.unwrap().ftype,
+            ftype3::NF3DIR
+        ));
+        assert_eq!(fs.lookup(bin, &name("..")).await.unwrap(), root);
+        assert_eq!(fs.lookup(bin, &name(".")).await.unwrap(), bin);
+
+        let run = fs.lookup(bin, &name("run")).await.unwrap();
+        assert_eq!(fs.getattr(run).await.unwrap().mode, 0o755);
+
+        let link = fs.lookup(bin, &name("link")).await.unwrap();
+        assert!(matches!(
+            fs.getattr(link).await.unwrap().ftype,
+            ftype3::NF3LNK
+        ));
+        assert_eq!(fs.readlink(link).await.unwrap().0, b"run");
+    }
+
+    #[tokio::test]
+    async fn lookup_errors() {
+        let (fs, _dir) = example().await;
+        let root = fs.root_dir();
+        let readme = fs.lookup(root, &name("README")).await.unwrap();
+
+        assert!(matches!(
+            fs.lookup(root, &name("missing")).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+        assert!(matches!(
+            fs.lookup(readme, &name("child")).await,
+            Err(nfsstat3::NFS3ERR_NOTDIR)
+        ));
+        assert!(matches!(
+            fs.getattr(1000).await,
+            Err(nfsstat3::NFS3ERR_STALE)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_in_chunks() {
+        let (fs, _dir) = example().await;
+        let root = fs.root_dir();
+        let readme = fs.lookup(root, &name("README")).await.unwrap();
+        let new = fs.create_exclusive(root, &name("new")).await.unwrap();
+        fs.write(new, 0, b"hello world").await.unwrap();
+
+        for id in [readme, new] {
+            let mut contents = vec![];
+            loop {
+                let (chunk, eof) = fs.read(id, contents.len() as u64, 4).await.unwrap();
+                contents.extend(chunk);
+                if eof {
+                    break;
+                }
+            }
+            assert_eq!(contents, b"hello world");
+            assert_eq!(fs.read(id, 100, 4).await.unwrap(), (vec![], true));
+        }
+        // The file from the store was only decompressed once.
+        let readme_id = match fs.resolve(&*fs.inner.overlay.lock().await, "README").await {
+            Ok(Node::File { id, .. }) => id,
+            node => panic!("unexpected node {node:?}"),
+        };
+        assert!(fs.inner.contents.lock().get(readme_id).is_some());
+    }
+
+    #[test]
+    fn contents_cache_keeps_the_latest_files() {
+        let mut cache = ContentsCache::default();
+        let big = Arc::new(vec![0; CONTENTS_CACHE_BYTES / 2 + 1]);
+        cache.insert(Id([1; 32]), big.clone());
+        cache.insert(Id([2; 32]), big.clone());
+        assert!(cache.get(Id([1; 32])).is_none());
+        assert!(cache.get(Id([2; 32])).is_some());
+
+        // Files larger than the cache still get cached on their own.
+        cache.insert(Id([3; 32]), Arc::new(vec![0; CONTENTS_CACHE_BYTES + 1]));
+        assert!(cache.get(Id([2; 32])).is_none());
+        assert!(cache.get(Id([3; 32])).is_some());
+        assert_eq!(cache.bytes, CONTENTS_CACHE_BYTES + 1);
+    }
+
+    #[tokio::test]
+    async fn readdir_pages() {
+        let (fs, _dir) = example().await;
+        let root = fs.root_dir();
+
+        let first = fs.readdir(root, 0, 2).await.unwrap();
+        let names: Vec<_> = first.entries.iter().map(|e| e.name.0.clone()).collect();
+        assert_eq!(names, [b"README".to_vec(), b"bin".to_vec()]);
+        assert!(!first.end);
+
+        let rest = fs.readdir(root, first.entries[1].fileid, 10).await.unwrap();
+        assert_eq!(rest.entries.len(), 1);
+        assert_eq!(rest.entries[0].name.0, b"empty");
+        assert!(matches!(rest.entries[0].attr.ftype, ftype3::NF3DIR));
+        assert!(rest.end);
+
+        // Entries get the same ids as lookups.
+        assert_eq!(
+            fs.lookup(root, &name("empty")).await.unwrap(),
+            rest.entries[0].fileid
+        );
+        let empty = fs.readdir(rest.entries[0].fileid, 0, 10).await.unwrap();
+        assert!(empty.entries.is_empty());
+        assert!(empty.end);
+    }
+
+    /// Makes an NFSv3 call without credentials and returns the results of the reply, after
+    /// checking that the server accepted the call.
+    async fn call(
+        conn: &mut tokio::net::TcpStream,
+        xid: u32,
+        procedure: u32,
+        args: &[u8],
+    ) -> io::Cursor<Vec<u8>> {
+        let mut message = vec![];
+        // xid, CALL, RPC version 2, NFS version 3, then AUTH_NONE credentials and verifier.
+        for word in [xid, 0, 2, 100003, 3, procedure, 0, 0, 0, 0] {
+            encode(&mut message, &word);
+        }
+        message.extend_from_slice(args);
+        // The whole call goes in a single record fragment, marked as the last one.
+        let marker = 0x8000_0000 | u32::try_from(message.len()).unwrap();
+        conn.write_all(&marker.to_be_bytes()).await.unwrap();
+        conn.write_all(&message).await.unwrap();
+
+        let mut marker = [0; 4];
+        conn.read_exact(&mut marker).await.unwrap();
+        let mut reply = vec![0; (u32::from_be_bytes(marker) & 0x7fff_ffff) as usize];
+        conn.read_exact(&mut reply).await.unwrap();
+        let mut reply = io::Cursor::new(reply);
+        // xid, REPLY, MSG_ACCEPTED, an AUTH_NONE verifier and SUCCESS.
+        let header: Vec<u32> = (0..6).map(|_| decode(&mut reply)).collect();
+        assert_eq!(header, [xid, 1, 0, 0, 0, 0]);
+        reply
+    }
+
+    fn encode(buf: &mut Vec<u8>, value: &impl XDR) {
+        XDR::serialize(value, buf).unwrap();
+    }
+
+    fn decode<T: XDR + Default>(reply: &mut io::Cursor<Vec<u8>>) -> T {
+        let mut value = T::default();
+        XDR::deserialize(&mut value, reply).unwrap();
+        value
+    }
+
+    #[tokio::test]
+    async fn serves_over_tcp() {
+        const NFS3_OK: u32 = 0;
+        let (fs, _dir) = example().await;
+        let listener = NFSTcpListener::bind("127.0.0.1:0", fs.clone())
+            .await
+            .unwrap();
+        let port = listener.get_listen_port();
+        tokio::spawn(async move { listener.handle_forever().await });
+        let mut conn = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .unwrap();
+
+        // NULL
+        let reply = call(&mut conn, 1, 0, &[]).await;
+        assert_eq!(reply.position(), reply.get_ref().len() as u64);
+
+        // LOOKUP
+        let mut args = vec![];
+        let lookup = diropargs3 {
+            dir: fs.id_to_fh(fs.root_dir()),
+            name: name("README"),
+        };
+        encode(&mut args, &lookup);
+        let mut reply = call(&mut conn, 2, 3, &args).await;
+        assert_eq!(decode::<u32>(&mut reply), NFS3_OK);
+        let readme: nfs_fh3 = decode(&mut reply);
+        assert_eq!(
+            fs.fh_to_id(&readme).unwrap(),
+            fs.lookup(fs.root_dir(), &name("README")).await.unwrap()
+        );
+
+        // READ
+        let mut args = vec![];
+        encode(&mut args, &readme);
+        encode(&mut args, &0u64);
+        encode(&mut args, &1024u32);
+        let mut reply = call(&mut conn, 3, 6, &args).await;
+        assert_eq!(decode::<u32>(&mut reply), NFS3_OK);
+        let _: post_op_attr = decode(&mut reply);
+        assert_eq!(decode::<u32>(&mut reply), 11);
+        assert!(decode::<bool>(&mut reply));
+        assert_eq!(decode::<Vec<u8>>(&mut reply), b"hello world");
+
+        // WRITE
+        let mut args = vec![];
+        encode(&mut args, &readme);
+        encode(&mut args, &6u64);
+        encode(&mut args, &5u32);
+        encode(&mut args, &2u32); // FILE_SYNC
+        encode(&mut args, &b"there".to_vec());
+        let mut reply = call(&mut conn, 4, 7, &args).await;
+        assert_eq!(decode::<u32>(&mut reply), NFS3_OK);
+        let _: wcc_data = decode(&mut reply);
+        assert_eq!(decode::<u32>(&mut reply), 5);
+
+        // The write went to the overlay.
+        let overlay = fs.inner.overlay.lock().await;
+        let dirty: Vec<_> = overlay.manifest.entries.keys().cloned().collect();
+        assert_eq!(dirty, ["README"]);
+        drop(overlay);
+        let readme = fs.fh_to_id(&readme).unwrap();
+        assert_eq!(read_all(&fs, readme).await, b"hello there");
+    }
+
+    #[tokio::test]
+    async fn writes_go_to_the_overlay() {
+        let (fs, dir) = example().await;
+        let root = fs.root_dir();
+        let readme = fs.lookup(root, &name("README")).await.unwrap();
+
+        let attr = fs.write(readme, 6, b"there").await.unwrap();
+        assert_eq!(attr.size, 11);
+        assert_eq!(read_all(&fs, readme).await, b"hello there");
+        // The file keeps its id once it's overlaid.
+        assert_eq!(fs.lookup(root, &name("README")).await.unwrap(), readme);
+
+        let (notes, _) = fs
+            .create(root, &name("notes"), sattr3::default())
+            .await
+            .unwrap();
+        fs.write(notes, 0, b"todo").await.unwrap();
+        assert!(matches!(
+            fs.create_exclusive(root, &name("notes")).await,
+            Err(nfsstat3::NFS3ERR_EXIST)
+        ));
+        let (src, attr) = fs.mkdir(root, &name("src")).await.unwrap();
+        assert!(matches!(attr.ftype, ftype3::NF3DIR));
+        let (latest, _) = fs
+            .symlink(
+                src,
+                &name("latest"),
+                &b"../notes".to_vec().into(),
+                &sattr3::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(fs.readlink(latest).await.unwrap().0, b"../notes");
+        assert_eq!(
+            names(&fs, root).await,
+            ["README", "bin", "empty", "notes", "src"]
+        );
+        assert_eq!(names(&fs, src).await, ["latest"]);
+
+        // Truncating and flipping the executable bit are changes too.
+        let bin = fs.lookup(root, &name("bin")).await.unwrap();
+        let run = fs.lookup(bin, &name("run")).await.unwrap();
+        let attr = fs
+            .setattr(
+                run,
+                sattr3 {
+                    mode: set_mode3::mode(0o644),
+                    size: set_size3::size(2),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!((attr.mode, attr.size), (0o644, 2));
+        assert_eq!(read_all(&fs, run).await, b"#!");
+
+        // The store still has the original tree.
+        let root_tree = fs.root_tree();
+        let reopened = VirtualFileSystem::new(
+            fs.inner.store.clone(),
+            root_tree,
+            tempfile::tempdir().unwrap().path(),
+        )
+        .await
+        .unwrap();
+        let readme = reopened.lookup(root, &name("README")).await.unwrap();
+        assert_eq!(read_all(&reopened, readme).await, b"hello world");
+
+        // The overlay survives a restart.
+        fs.flush().await.unwrap();
+        let reopened = VirtualFileSystem::new(fs.inner.store.clone(), root_tree, dir.path())
+            .await
+            .unwrap();
+        let readme = reopened.lookup(root, &name("README")).await.unwrap();
+        assert_eq!(read_all(&reopened, readme).await, b"hello there");
+        let notes = reopened.lookup(root, &name("notes")).await.unwrap();
+        assert_eq!(read_all(&reopened, notes).await, b"todo");
+        let src = reopened.lookup(root, &name("src")).await.unwrap();
+        let latest = reopened.lookup(src, &name("latest")).await.unwrap();
+        assert_eq!(reopened.readlink(latest).await.unwrap().0, b"../notes");
+    }
+
+    #[tokio::test]
+    async fn remove_and_rename() {
+        let (fs, _dir) = example().await;
+        let root = fs.root_dir();
+
+        assert!(matches!(
+            fs.remove(root, &name("bin")).await,
+            Err(nfsstat3::NFS3ERR_NOTEMPTY)
+        ));
+        fs.remove(root, &name("README")).await.unwrap();
+        assert!(matches!(
+            fs.lookup(root, &name("README")).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+        fs.remove(root, &name("empty")).await.unwrap();
+        assert_eq!(names(&fs, root).await, ["bin"]);
+
+        // Renaming a directory moves its contents and keeps everyone's ids.
+        let bin = fs.lookup(root, &name("bin")).await.unwrap();
+        let run = fs.lookup(bin, &name("run")).await.unwrap();
+        fs.write(run, 0, b"#!/bin/bash").await.unwrap();
+        fs.rename(root, &name("bin"), root, &name("tools"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            fs.lookup(root, &name("bin")).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+        assert_eq!(fs.lookup(root, &name("tools")).await.unwrap(), bin);
+        assert_eq!(fs.lookup(bin, &name("run")).await.unwrap(), run);
+        assert_eq!(read_all(&fs, run).await, b"#!/bin/bash");
+        assert_eq!(names(&fs, bin).await, ["link", "run"]);
+
+        // A file replaces a file, but never a directory.
+        let link = fs.lookup(bin, &name("link")).await.unwrap();
+        fs.rename(bin, &name("link"), root, &name("run"))
+            .await
+            .unwrap();
+        assert_eq!(fs.lookup(root, &name("run")).await.unwrap(), link);
+        assert_eq!(fs.readlink(link).await.unwrap().0, b"run");
+        fs.rename(bin, &name("run"), root, &name("run"))
+            .await
+            .unwrap();
+        assert_eq!(read_all(&fs, run).await, b"#!/bin/bash");
+        assert!(matches!(
+            fs.rename(root, &name("run"), root, &name("tools")).await,
+            Err(nfsstat3::NFS3ERR_ISDIR)
+        ));
+        assert!(matches!(
+            fs.rename(root, &name("tools"), bin, &name("inside")).await,
+            Err(nfsstat3::NFS3ERR_INVAL)
+        ));
+        assert_eq!(names(&fs, root).await, ["run", "tools"]);
+        assert!(names(&fs, bin).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn conflicts_are_read_only() {
+        let store = Store::new(Arc::new(InMemoryObjectStore::default()))
+            .await
+            .unwrap();
+        let file = write_file(&store, b"side").await;
+        let side = TreeEntry::File {
+            id: file,
+            executable: false,
+        };
+        let conflict = store
+            .write_conflict(Conflict {
+                removes: vec![side.clone()],
+                adds: vec![side.clone(), side],
+            })
+            .await
+            .unwrap();
+        let root = store
+            .write_tree(Tree {
+                entries: vec![TreeEntryMapping {
+                    name: "merged".to_string(),
+                    entry: TreeEntry::ConflictId(conflict),
+                }],
+            })
+            .await
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let fs = VirtualFileSystem::new(store, root, dir.path())
+            .await
+            .unwrap();
+        let root_dir = fs.root_dir();
+        let merged = fs.lookup(root_dir, &name("merged")).await.unwrap();
+
+        assert!(matches!(
+            fs.write(merged, 0, b"resolved").await,
+            Err(nfsstat3::NFS3ERR_ACCES)
+        ));
+        let truncate = sattr3 {
+            size: set_size3::size(0),
+            ..Default::default()
+        };
+        assert!(matches!(
+            fs.setattr(merged, truncate).await,
+            Err(nfsstat3::NFS3ERR_ACCES)
+        ));
+        assert_eq!(fs.snapshot(&BTreeSet::new()).await.unwrap(), root);
+
+        // Conflicts can be moved around, and removing one is a way of resolving it.
+        fs.rename(root_dir, &name("merged"), root_dir, &name("moved"))
+            .await
+            .unwrap();
+        let tree_id = fs.snapshot(&BTreeSet::new()).await.unwrap();
+        let tree = fs.inner.store.get_tree(tree_id).await.unwrap().unwrap();
+        assert_eq!(tree.entries.len(), 1);
+        assert_eq!(tree.entries[0].name, "moved");
+        assert!(matches!(tree.entries[0].entry, TreeEntry::ConflictId(id) if id == conflict));
+        fs.remove(root_dir, &name("moved")).await.unwrap();
+        assert!(names(&fs, root_dir).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn snapshot_writes_the_dirty_paths() {
+        let (fs, dir) = example().await;
+        let root = fs.root_dir();
+        let store = fs.inner.store.clone();
+        let old_root_id = fs.root_tree();
+        let old_root = store.get_tree(old_root_id).await.unwrap().unwrap();
+
+        let readme = fs.lookup(root, &name("README")).await.unwrap();
+        fs.write(readme, 0, b"HELLO").await.unwrap();
+        let bin = fs.lookup(root, &name("bin")).await.unwrap();
+        fs.remove(bin, &name("link")).await.unwrap();
+        let (src, _) = fs.mkdir(root, &name("src")).await.unwrap();
+        let (main, _) = fs
+            .create(src, &name("main.rs"), sattr3::default())
+            .await
+            .unwrap();
+        fs.write(main, 0, b"fn main() {}").await.unwrap();
+        fs.mkdir(root, &name("new-and-empty")).await.unwrap();
+        let (repo, _) = fs.mkdir(root, &name(".jj")).await.unwrap();
+        fs.create(repo, &name("config"), sattr3::default())
+            .await
+            .unwrap();
+
+        let tree_id = fs.snapshot(&BTreeSet::new()).await.unwrap();
+        assert_eq!(fs.root_tree(), tree_id);
+        let tree = store.get_tree(tree_id).await.unwrap().unwrap();
+        let entries: Vec<_> = tree.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(entries, ["README", "bin", "empty", "src"]);
+        // Untouched directories keep their trees.
+        assert_eq!(tree.entries[2], old_root.entries[2]);
+
+        let TreeEntry::File { id, .. } = tree.entries[0].entry else {
+            panic!("README should be a file");
+        };
+        let contents = fs.file_contents(id).await.unwrap();
+        assert_eq!(*contents, b"HELLO world");
+        let TreeEntry::TreeId(bin_id) = tree.entries[1].entry else {
+            panic!("bin should be a tree");
+        };
+        let bin_tree = store.get_tree(bin_id).await.unwrap().unwrap();
+        assert_eq!(bin_tree.entries.len(), 1);
+        assert_eq!(bin_tree.entries[0].name, "run");
+
+        // Only the repo's metadata and the empty directory are left in the overlay, and the
+        // mount looks the same.
+        let overlay = fs.inner.overlay.lock().await;
+        let dirty: Vec<_> = overlay.manifest.entries.keys().cloned().collect();
+        assert_eq!(dirty, [".jj", ".jj/config", "new-and-empty"]);
+        drop(overlay);
+        assert_eq!(read_all(&fs, readme).await, b"HELLO world");
+        assert_eq!(read_all(&fs, main).await, b"fn main() {}");
+        assert_eq!(
+            names(&fs, root).await,
+            [".jj", "README", "bin", "empty", "new-and-empty", "src"]
+        );
+
+        // Nothing changed since.
+        assert_eq!(fs.snapshot(&BTreeSet::new()).await.unwrap(), tree_id);
+
+        // The new tree was saved along with what's left in the overlay, so it's picked up
+        // even if the caller never got to record it.
+        let reopened = VirtualFileSystem::new(store, old_root_id, dir.path())
+            .await
+            .unwrap();
+        assert_eq!(reopened.root_tree(), tree_id);
+        let readme = reopened.lookup(root, &name("README")).await.unwrap();
+        assert_eq!(read_all(&reopened, readme).await, b"HELLO world");
+    }
+
+    #[tokio::test]
+    async fn overlay_lookups_skip_unrelated_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut overlay = Overlay::open(dir.path().into()).await.unwrap();
+        for path in [
+            ".jj",
+            ".jj-old",
+            ".jj/repo",
+            ".jj/repo/store",
+            ".jjx",
+            "a",
+            "a-b",
+            "a/c",
+            "a/c/d",
+            "a/e",
+            "b",
+        ] {
+            overlay.insert(path, OverlayEntry::Dir);
+        }
+        let names = |dir| -> Vec<_> {
+            overlay
+                .children(dir)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect()
+        };
+        assert_eq!(names(""), [".jj", ".jj-old", ".jjx", "a", "a-b", "b"]);
+        assert_eq!(names("a"), ["c", "e"]);
+        assert_eq!(names(".jj"), ["repo"]);
+        assert!(names("b").is_empty());
+        let worktree: Vec<_> = overlay.worktree_entries().map(|(path, _)| path).collect();
+        assert_eq!(
+            worktree,
+            [".jj-old", ".jjx", "a", "a-b", "a/c", "a/c/d", "a/e", "b"]
+        );
+    }
+
+    #[tokio::test]
+    async fn saving_costs_the_same_however_large_the_overlay() {
+        let (fs, dir) = example().await;
+        let root = fs.root_dir();
+        let journal_len = || std::fs::metadata(dir.path().join(JOURNAL)).unwrap().len();
+
+        let before = journal_len();
+        fs.create(root, &name("small-0"), sattr3::default())
+            .await
+            .unwrap();
+        let small = journal_len() - before;
+
+        let mut overlay = fs.inner.overlay.lock().await;
+        for i in 0..1000 {
+            overlay.insert(&format!(".jj/file-{i:04}"), OverlayEntry::Dir);
+        }
+        overlay.save().await.unwrap();
+        drop(overlay);
+        let manifest = std::fs::read(dir.path().join(MANIFEST)).ok();
+        let before = journal_len();
+        fs.create(root, &name("small-1"), sattr3::default())
+            .await
+            .unwrap();
+        assert_eq!(journal_len() - before, small);
+        assert_eq!(std::fs::read(dir.path().join(MANIFEST)).ok(), manifest);
+
+        // The journal is replayed on restart, and folded into the manifest.
+        let reopened = VirtualFileSystem::new(fs.inner.store.clone(), fs.root_tree(), dir.path())
+            .await
+            .unwrap();
+        reopened.lookup(root, &name("small-1")).await.unwrap();
+        assert_eq!(
+            reopened.inner.overlay.lock().await.below(".jj").count(),
+            1000
+        );
+        assert_eq!(journal_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn torn_journal_batches_are_dropped() {
+        let (fs, dir) = example().await;
+        let root = fs.root_dir();
+        fs.create(root, &name("kept"), sattr3::default())
+            .await
+            .unwrap();
+        fs.create(root, &name("torn"), sattr3::default())
+            .await
+            .unwrap();
+        let journal = std::fs::read(dir.path().join(JOURNAL)).unwrap();
+        std::fs::write(dir.path().join(JOURNAL), &journal[..journal.len() - 1]).unwrap();
+
+        let reopened = VirtualFileSystem::new(fs.inner.store.clone(), fs.root_tree(), dir.path())
+            .await
+            .unwrap();
+        reopened.lookup(root, &name("kept")).await.unwrap();
+        assert!(matches!(
+            reopened.lookup(root, &name("torn")).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+        // Changes made after reopening aren't lost behind the torn batch.
+        reopened
+            .create(root, &name("later"), sattr3::default())
+            .await
+            .unwrap();
+        let reopened = VirtualFileSystem::new(fs.inner.store.clone(), fs.root_tree(), dir.path())
+            .await
+            .unwrap();
+        reopened.lookup(root, &name("later")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshot_leaves_untracked_files() {
+        let (fs, _dir) = example().await;
+        let root = fs.root_dir();
+        let old_root = fs.root_tree();
+        let readme = fs.lookup(root, &name("README")).await.unwrap();
+        fs.write(readme, 0, b"HELLO").await.unwrap();
+        let ignore = fs
+            .create_exclusive(root, &name(".gitignore"))
+            .await
+            .unwrap();
+        fs.write(ignore, 0, b"*.log").await.unwrap();
+        let (logs, _) = fs.mkdir(root, &name("logs")).await.unwrap();
+        let log = fs.create_exclusive(logs, &name("build.log")).await.unwrap();
+        fs.write(log, 0, b"ok").await.unwrap();
+
+        let new_files = fs.new_files().await.unwrap();
+        assert_eq!(
+            new_files,
+            NewFiles {
+                files: BTreeMap::from([
+                    (".gitignore".to_string(), 5),
+                    ("logs/build.log".to_string(), 2),
+                ]),
+                gitignores: BTreeMap::from([(String::new(), b"*.log".to_vec())]),
+            }
+        );
+
+        // Tracked files are snapshotted even if they are asked to be left untracked.
+        let untracked = BTreeSet::from(["README".to_string(), "logs/build.log".to_string()]);
+        let tree_id = fs.snapshot(&untracked).await.unwrap();
+        assert_ne!(tree_id, old_root);
+        let tree = fs.inner.store.get_tree(tree_id).await.unwrap().unwrap();
+        let entries: Vec<_> = tree.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(entries, [".gitignore", "README", "bin", "empty"]);
+
+        // The untracked file stays in the mount, and is new in the next snapshot too.
+        assert_eq!(read_all(&fs, log).await, b"ok");
+        let new_files = fs.new_files().await.unwrap();
+        assert_eq!(
+            new_files.files,
+            BTreeMap::from([("logs/build.log".to_string(), 2)])
+        );
+        let tree_id = fs.snapshot(&BTreeSet::new()).await.unwrap();
+        let tree = fs.inner.store.get_tree(tree_id).await.unwrap().unwrap();
+        let entries: Vec<_> = tree.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(entries, [".gitignore", "README", "bin", "empty", "logs"]);
+    }
+
+    #[tokio::test]
+    async fn check_out_keeps_the_overlay() {
+        let (fs, _dir) = example().await;
+        let root = fs.root_dir();
+        let store = fs.inner.store.clone();
+        let old_root = store.get_tree(fs.root_tree()).await.unwrap().unwrap();
+
+        // `README` changes in the new tree, `bin/link` goes away and `docs/guide` is added.
+        let readme = write_file(&store, b"new readme").await;
+        let guide = write_file(&store, b"read me first").await;
+        let TreeEntry::TreeId(bin) = old_root.entries[1].entry else {
+            panic!("bin should be a tree");
+        };
+        let mut bin = store.get_tree(bin).await.unwrap().unwrap();
+        bin.entries.retain(|mapping| mapping.name != "link");
+        let docs = Tree {
+            entries: vec![TreeEntryMapping {
+                name: "guide".to_string(),
+                entry: TreeEntry::File {
+                    id: guide,
+                    executable: false,
+                },
+            }],
+        };
+        let new_root = Tree {
+            entries: vec![
+                TreeEntryMapping {
+                    name: "README".to_string(),
+                    entry: TreeEntry::File {
+                        id: readme,
+                        executable: false,
+                    },
+                },
+                TreeEntryMapping {
+                    name: "bin".to_string(),
+                    entry: TreeEntry::TreeId(store.write_tree(bin).await.unwrap()),
+                },
+                TreeEntryMapping {
+                    name: "docs".to_string(),
+                    entry: TreeEntry::TreeId(store.write_tree(docs).await.unwrap()),
+                },
+                old_root.entries[2].clone(),
+            ],
+        };
+        let new_root = store.write_tree(new_root).await.unwrap();
+
+        let readme = fs.lookup(root, &name("README")).await.unwrap();
+        fs.write(readme, 0, b"local").await.unwrap();
+        let old_root_id = fs.root_tree();
+        // The overlay has to apply to the tree the checkout starts from.
+        assert_eq!(fs.check_out(new_root, new_root).await.unwrap(), None);
+        let stats = fs.check_out(old_root_id, new_root).await.unwrap();
+        assert_eq!(
+            stats,
+            Some(CheckoutStats {
+                updated_files: 0,
+                added_files: 1,
+                removed_files: 1,
+                skipped_files: 1,
+            })
+        );
+        assert_eq!(fs.root_tree(), new_root);
+        assert_eq!(read_all(&fs, readme).await, b"local world");
+        let docs = fs.lookup(root, &name("docs")).await.unwrap();
+        let guide = fs.lookup(docs, &name("guide")).await.unwrap();
+        assert_eq!(read_all(&fs, guide).await, b"read me first");
+        let bin = fs.lookup(root, &name("bin")).await.unwrap();
+        assert_eq!(names(&fs, bin).await, ["run"]);
+
+        // Going back only touches what differs.
+        let stats = fs.check_out(new_root, old_root_id).await.unwrap();
+        assert_eq!(
+            stats,
+            Some(CheckoutStats {
+                updated_files: 0,
+                added_files: 1,
+                removed_files: 1,
+                skipped_files: 1,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn times_change_with_the_contents() {
+        let (fs, _dir) = example().await;
+        let root = fs.root_dir();
+        let store = fs.inner.store.clone();
+        let mtime = |attr: fattr3| (attr.mtime.seconds, attr.mtime.nseconds);
+        let readme = fs.lookup(root, &name("README")).await.unwrap();
+        let bin = fs.lookup(root, &name("bin")).await.unwrap();
+        let started = mtime(fs.getattr(readme).await.unwrap());
+        assert_ne!(started, (0, 0));
+        assert_eq!(mtime(fs.getattr(bin).await.unwrap()), started);
+
+        let mut tree = store.get_tree(fs.root_tree()).await.unwrap().unwrap();
+        tree.entries[0].entry = TreeEntry::File {
+            id: write_file(&store, b"new readme").await,
+            executable: false,
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        fs.check_out(fs.root_tree(), store.write_tree(tree).await.unwrap())
+            .await
+            .unwrap();
+        let checked_out = mtime(fs.getattr(readme).await.unwrap());
+        assert!(checked_out > started);
+        assert_eq!(mtime(fs.getattr(root).await.unwrap()), checked_out);
+        assert_eq!(mtime(fs.getattr(bin).await.unwrap()), started);
+
+        // Snapshots keep the times files were written at.
+        let new = fs.create_exclusive(root, &name("new")).await.unwrap();
+        let written = mtime(fs.write(new, 0, b"new").await.unwrap());
+        fs.snapshot(&BTreeSet::new()).await.unwrap();
+        assert_eq!(mtime(fs.getattr(new).await.unwrap()), written);
+    }
+
+    #[tokio::test]
+    async fn reset_keeps_what_the_mount_shows() {
+        let (fs, _dir) = example().await;
+        let root = fs.root_dir();
+        let tree_id = fs.root_tree();
+        let empty_tree_id = fs.inner.store.get_empty_tree_id();
+
+        let readme = fs.lookup(root, &name("README")).await.unwrap();
+        fs.write(readme, 0, b"HELLO").await.unwrap();
+        fs.reset(empty_tree_id).await.unwrap();
+        assert_eq!(fs.root_tree(), empty_tree_id);
+        assert_eq!(names(&fs, root).await, ["README", "bin", "empty"]);
+        assert_eq!(read_all(&fs, readme).await, b"HELLO world");
+
+        // Everything the empty tree doesn't have shows up as changes.
+        let snapshot = fs.snapshot(&BTreeSet::new()).await.unwrap();
+        let mut expected = fs.inner.store.get_tree(tree_id).await.unwrap().unwrap();
+        let mut actual = fs.inner.store.get_tree(snapshot).await.unwrap().unwrap();
+        assert_ne!(actual.entries[0], expected.entries[0]);
+        actual.entries.remove(0);
+        expected.entries.remove(0);
+        assert_eq!(actual, expected);
+
+        // Resetting to what the mount shows leaves nothing to snapshot.
+        fs.reset(tree_id).await.unwrap();
+        fs.reset(snapshot).await.unwrap();
+        assert_eq!(fs.snapshot(&BTreeSet::new()).await.unwrap(), snapshot);
+    }
+
+    #[tokio::test]
+    async fn recover_from_a_missing_tree() {
+        let (fs, dir) = example().await;
+        let tree_id = fs.root_tree();
+        let missing = Id([9; 32]);
+        let fs = VirtualFileSystem::new(fs.inner.store.clone(), missing, dir.path())
+            .await
+            .unwrap();
+        assert!(fs.reset(tree_id).await.is_err());
+        assert_eq!(fs.root_tree(), missing);
+
+        fs.recover(tree_id).await.unwrap();
+        assert_eq!(fs.root_tree(), tree_id);
+        assert_eq!(names(&fs, fs.root_dir()).await, ["README", "bin", "empty"]);
+    }
+
+    #[tokio::test]
+    async fn sparse_patterns_hide_paths() {
+        let (fs, _dir) = example().await;
+        let root = fs.root_dir();
+        let tree_id = fs.root_tree();
+        fs.mkdir(root, &name(".jj")).await.unwrap();
+
+        let stats = fs
+            .set_sparse_patterns(vec!["bin".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            stats,
+            CheckoutStats {
+                removed_files: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(names(&fs, root).await, [".jj", "bin"]);
+        assert!(matches!(
+            fs.lookup(root, &name("README")).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+        let bin = fs.lookup(root, &name("bin")).await.unwrap();
+        assert_eq!(names(&fs, bin).await, ["link", "run"]);
+        assert!(matches!(
+            fs.create(root, &name("hidden"), sattr3::default()).await,
+            Err(nfsstat3::NFS3ERR_ACCES)
+        ));
+
+        // Hidden paths are still part of snapshots.
+        let run = fs.lookup(bin, &name("run")).await.unwrap();
+        fs.write(run, 0, b"#!").await.unwrap();
+        let snapshot = fs.snapshot(&BTreeSet::new()).await.unwrap();
+        let tree = fs.inner.store.get_tree(snapshot).await.unwrap().unwrap();
+        let old_tree = fs.inner.store.get_tree(tree_id).await.unwrap().unwrap();
+        assert_eq!(tree.entries[0], old_tree.entries[0]);
+
+        // Patterns inside a directory show the directories leading there.
+        let stats = fs
+            .set_sparse_patterns(vec!["bin/run".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(
+            stats,
+            CheckoutStats {
+                removed_files: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(names(&fs, bin).await, ["run"]);
+
+        let stats = fs.set_sparse_patterns(vec![String::new()]).await.unwrap();
+        assert_eq!(
+            stats,
+            CheckoutStats {
+                added_files: 2,
+                ..Default::default()
+            }
+        );
+        assert_eq!(names(&fs, root).await, [".jj", "README", "bin", "empty"]);
     }
 }
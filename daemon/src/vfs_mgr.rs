@@ -1,6 +1,15 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::PathBuf,
+};
+
+use anyhow::anyhow;
 use nfsserve::tcp::{NFSTcp, NFSTcpListener};
-use rand::Rng;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+use durable::write_durably;
 
 use crate::vfs::VirtualFileSystem;
 
@@ -8,31 +17,98 @@ pub struct VfsManager {
     config: VfsManagerConfig,
     tx: mpsc::UnboundedSender<VfsManagerMessage>,
     rx: mpsc::UnboundedReceiver<VfsManagerMessage>,
+    /// NFS port of each working copy, by path. Working copies that have not been served since a
+    /// restart keep their port, so nothing else takes it.
+    ports: BTreeMap<String, u16>,
+    /// The NFS server of each working copy that is served, by path
+    servers: HashMap<String, NfsServer>,
 }
 
 pub struct VfsManagerConfig {
-    pub min_nfs_port: usize,
-    pub max_nfs_port: usize,
+    pub min_nfs_port: u16,
+    /// Last port of the range, inclusive
+    pub max_nfs_port: u16,
+    /// Where the port of each working copy is kept across restarts
+    pub ports_path: PathBuf,
 }
 
 enum VfsManagerMessage {
-    Bind,
+    Bind {
+        /// Where the working copy is mounted
+        path: String,
+        fs: VirtualFileSystem,
+        reply: oneshot::Sender<io::Result<u16>>,
+    },
+    /// Restarts the NFS server of a working copy, on the same port if it is still free
+    Remount {
+        path: String,
+        fs: VirtualFileSystem,
+        reply: oneshot::Sender<io::Result<u16>>,
+    },
+    /// Stops the NFS server of a working copy and gives up its port
+    Unmount {
+        path: String,
+        reply: oneshot::Sender<io::Result<()>>,
+    },
 }
 
 /// Handle to the VFS Manager service
+#[derive(Clone)]
 pub struct VfsManagerHandle(mpsc::UnboundedSender<VfsManagerMessage>);
 
 impl VfsManagerHandle {
-    pub fn bind(&self) -> anyhow::Result<()> {
-        self.0.send(VfsManagerMessage::Bind)?;
+    /// Serves the working copy at `path` over its own NFS server, returning the port it listens
+    /// on.
+    pub async fn bind(&self, path: String, fs: VirtualFileSystem) -> anyhow::Result<u16> {
+        let (reply, port) = oneshot::channel();
+        self.0.send(VfsManagerMessage::Bind { path, fs, reply })?;
+        let port = port.await.map_err(|_| anyhow!("VFS manager stopped"))??;
+        Ok(port)
+    }
+
+    /// Serves the working copy at `path` again, returning the port it listens on now.
+    pub async fn remount(&self, path: String, fs: VirtualFileSystem) -> anyhow::Result<u16> {
+        let (reply, port) = oneshot::channel();
+        self.0
+            .send(VfsManagerMessage::Remount { path, fs, reply })?;
+        let port = port.await.map_err(|_| anyhow!("VFS manager stopped"))??;
+        Ok(port)
+    }
+
+    pub async fn unmount(&self, path: String) -> anyhow::Result<()> {
+        let (reply, done) = oneshot::channel();
+        self.0.send(VfsManagerMessage::Unmount { path, reply })?;
+        done.await.map_err(|_| anyhow!("VFS manager stopped"))??;
         Ok(())
     }
 }
 
 impl VfsManager {
-    pub fn new(config: VfsManagerConfig) -> Self {
+    /// Picks up the ports working copies were served on before the last restart.
+    pub async fn new(config: VfsManagerConfig) -> io::Result<Self> {
+        if config.min_nfs_port > config.max_nfs_port {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "NFS port range {}-{} is empty",
+                    config.min_nfs_port, config.max_nfs_port
+                ),
+            ));
+        }
+        let ports = match tokio::fs::read_to_string(&config.ports_path).await {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err),
+        };
         let (tx, rx) = mpsc::unbounded_channel();
-        VfsManager { config, tx, rx }
+        Ok(VfsManager {
+            config,
+            tx,
+            rx,
+            ports,
+            servers: HashMap::new(),
+        })
     }
 
     pub fn handle(&self) -> VfsManagerHandle {
@@ -42,21 +118,323 @@ impl VfsManager {
     pub async fn serve(&mut self) -> Result<(), std::io::Error> {
         while let Some(msg) = self.rx.recv().await {
             match msg {
-                VfsManagerMessage::Bind => {
-                    let port = rand::thread_rng()
-                        .gen_range(self.config.min_nfs_port..self.config.max_nfs_port);
-                    let _join_handle = tokio::spawn(async move {
-                        let listener = NFSTcpListener::bind(
-                            &format!("127.0.0.1:{port}"),
-                            VirtualFileSystem::default(),
-                        )
-                        .await
-                        .unwrap();
-                        listener.handle_forever().await
-                    });
+                VfsManagerMessage::Bind { path, fs, reply } => {
+                    let result = if self.servers.contains_key(&path) {
+                        Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!("{path} is already served"),
+                        ))
+                    } else {
+                        self.serve_working_copy(path, fs).await
+                    };
+                    let _ = reply.send(result);
+                }
+                VfsManagerMessage::Remount { path, fs, reply } => {
+                    self.stop(&path).await;
+                    let _ = reply.send(self.serve_working_copy(path, fs).await);
+                }
+                VfsManagerMessage::Unmount { path, reply } => {
+                    let result = if self.stop(&path).await {
+                        self.ports.remove(&path);
+                        self.save().await
+                    } else {
+                        Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("{path} is not served"),
+                        ))
+                    };
+                    let _ = reply.send(result);
                 }
             }
         }
         unreachable!();
     }
+
+    async fn serve_working_copy(&mut self, path: String, fs: VirtualFileSystem) -> io::Result<u16> {
+        let range = self.config.min_nfs_port..=self.config.max_nfs_port;
+        let previous = self
+            .ports
+            .get(&path)
+            .copied()
+            .filter(|port| range.contains(port));
+        // The port the working copy had before, or else the first port of the range that is
+        // neither taken by another working copy nor in use.
+        let candidates: Vec<u16> = previous
+            .into_iter()
+            .chain(range.filter(|port| !self.ports.values().any(|taken| taken == port)))
+            .collect();
+        let Some((server, port)) = NfsServer::start(fs, candidates).await? else {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!(
+                    "NFS port range {}-{} exhausted",
+                    self.config.min_nfs_port, self.config.max_nfs_port
+                ),
+            ));
+        };
+        if previous != Some(port) {
+            self.ports.insert(path.clone(), port);
+            if let Err(err) = self.save().await {
+                warn!("Could not record NFS port {port} of {path}: {err}");
+            }
+        }
+        info!("Serving {path} over NFS on port {port}");
+        self.servers.insert(path, server);
+        Ok(port)
+    }
+
+    /// Stops the NFS server of the working copy at `path`. Returns whether it was served.
+    async fn stop(&mut self, path: &str) -> bool {
+        let Some(server) = self.servers.remove(path) else {
+            return false;
+        };
+        server.stop().await;
+        info!("Stopped serving {path} over NFS");
+        true
+    }
+
+    async fn save(&self) -> io::Result<()> {
+        let contents = toml::to_string(&self.ports)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let tmp_path = self.config.ports_path.with_extension("toml.tmp");
+        write_durably(&tmp_path, &self.config.ports_path, contents.as_bytes()).await
+    }
+}
+
+/// An NFS server running on a runtime of its own. The server spawns a task for each connection
+/// it accepts, so shutting its runtime down is the only way to drop the connections along with
+/// the listener.
+struct NfsServer {
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl NfsServer {
+    /// Serves `fs` on the first of `ports` that is not in use, returning the server and its
+    /// port. Returns None if all of them are in use.
+    async fn start(fs: VirtualFileSystem, ports: Vec<u16>) -> io::Result<Option<(Self, u16)>> {
+        let (bound_tx, bound) = oneshot::channel();
+        let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+        let thread = std::thread::Builder::new()
+            .name("nfs-server".to_string())
+            .spawn(move || {
+                let rt = match tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                {
+                    Ok(rt) => rt,
+                    Err(err) => {
+                        let _ = bound_tx.send(Err(err));
+                        return;
+                    }
+                };
+                rt.block_on(async move {
+                    let listener = match Self::bind(fs, ports).await {
+                        Ok(Some(listener)) => listener,
+                        Ok(None) => {
+                            let _ = bound_tx.send(Ok(None));
+                            return;
+                        }
+                        Err(err) => {
+                            let _ = bound_tx.send(Err(err));
+                            return;
+                        }
+                    };
+                    let _ = bound_tx.send(Ok(Some(listener.get_listen_port())));
+                    tokio::select! {
+                        result = listener.handle_forever() => {
+                            if let Err(err) = result {
+                                warn!("NFS server stopped: {err}");
+                            }
+                        }
+                        // Also taken when the server is dropped.
+                        _ = shutdown_rx => {}
+                    }
+                });
+                // Dropping the runtime drops the connections.
+            })?;
+        let server = NfsServer {
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        };
+        let port = bound
+            .await
+            .map_err(|_| io::Error::other("The NFS server thread exited"))??;
+        Ok(port.map(|port| (server, port)))
+    }
+
+    async fn bind(
+        fs: VirtualFileSystem,
+        ports: Vec<u16>,
+    ) -> io::Result<Option<NFSTcpListener<VirtualFileSystem>>> {
+        for port in ports {
+            match NFSTcpListener::bind(&format!("127.0.0.1:{port}"), fs.clone()).await {
+                Ok(listener) => return Ok(Some(listener)),
+                Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+                    info!("NFS port {port} is in use");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Shuts the server down, waiting until its port and connections are closed.
+    async fn stop(mut self) {
+        self.shutdown.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = tokio::task::spawn_blocking(move || thread.join()).await;
+        }
+    }
+}
+
+impl Drop for NfsServer {
+    fn drop(&mut self) {
+        self.shutdown.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, sync::Arc, time::Duration};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{object_store::InMemoryObjectStore, store::Store};
+
+    /// Finds `n` consecutive ports that are free right now.
+    fn free_ports(n: u16) -> u16 {
+        loop {
+            let base = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            if base.checked_add(n).is_some()
+                && (base..base + n).all(|port| TcpListener::bind(("127.0.0.1", port)).is_ok())
+            {
+                return base;
+            }
+        }
+    }
+
+    async fn working_copy(dir: &tempfile::TempDir) -> VirtualFileSystem {
+        let store = Store::new(Arc::new(InMemoryObjectStore::default()))
+            .await
+            .unwrap();
+        let tree_id = store.get_empty_tree_id();
+        VirtualFileSystem::new(store, tree_id, dir.path().join("overlay"))
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn ports_are_allocated_and_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = free_ports(2);
+        let config = || VfsManagerConfig {
+            min_nfs_port: base,
+            max_nfs_port: base + 1,
+            ports_path: dir.path().join("nfs_ports.toml"),
+        };
+        let manager = |rt: &tokio::runtime::Runtime| {
+            rt.block_on(async {
+                let mut manager = VfsManager::new(config()).await.unwrap();
+                let handle = manager.handle();
+                tokio::spawn(async move { manager.serve().await });
+                handle
+            })
+        };
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let handle = manager(&rt);
+        rt.block_on(async {
+            let fs = working_copy(&dir).await;
+            // Ports that are in use are skipped.
+            let in_use = TcpListener::bind(("127.0.0.1", base)).unwrap();
+            assert_eq!(
+                handle.bind("/a".into(), fs.clone()).await.unwrap(),
+                base + 1
+            );
+            let err = handle.bind("/b".into(), fs.clone()).await.unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("NFS port range {base}-{} exhausted", base + 1)
+            );
+            drop(in_use);
+            assert_eq!(handle.bind("/b".into(), fs).await.unwrap(), base);
+        });
+        // Stops the NFS servers.
+        drop(rt);
+
+        // After a restart, working copies get their ports back, whatever order they come in.
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let handle = manager(&rt);
+        rt.block_on(async {
+            let fs = working_copy(&dir).await;
+            assert_eq!(handle.bind("/b".into(), fs.clone()).await.unwrap(), base);
+            assert!(handle.bind("/c".into(), fs.clone()).await.is_err());
+            assert_eq!(handle.bind("/a".into(), fs).await.unwrap(), base + 1);
+        });
+    }
+
+    #[tokio::test]
+    async fn working_copies_can_be_unmounted() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = free_ports(1);
+        let mut manager = VfsManager::new(VfsManagerConfig {
+            min_nfs_port: base,
+            max_nfs_port: base,
+            ports_path: dir.path().join("nfs_ports.toml"),
+        })
+        .await
+        .unwrap();
+        let handle = manager.handle();
+        tokio::spawn(async move { manager.serve().await });
+        let fs = working_copy(&dir).await;
+
+        assert_eq!(handle.bind("/a".into(), fs.clone()).await.unwrap(), base);
+        assert!(handle.bind("/a".into(), fs.clone()).await.is_err());
+        assert_eq!(handle.remount("/a".into(), fs.clone()).await.unwrap(), base);
+        let mut conn = tokio::net::TcpStream::connect(("127.0.0.1", base))
+            .await
+            .unwrap();
+        // Wait for the server to answer a NULL call, so it has taken the connection.
+        let call: Vec<u8> = [0x8000_0028, 1, 0, 2, 100003, 3, 0, 0, 0, 0, 0]
+            .iter()
+            .flat_map(|word: &u32| word.to_be_bytes())
+            .collect();
+        conn.write_all(&call).await.unwrap();
+        let mut reply = [0; 4];
+        conn.read_exact(&mut reply).await.unwrap();
+
+        // Unmounting stops the server, closes its connections and frees the port for other
+        // working copies.
+        handle.unmount("/a".into()).await.unwrap();
+        let mut rest = vec![];
+        tokio::time::timeout(Duration::from_secs(10), conn.read_to_end(&mut rest))
+            .await
+            .expect("the connection should be closed")
+            .ok();
+        assert!(tokio::net::TcpStream::connect(("127.0.0.1", base))
+            .await
+            .is_err());
+        assert!(handle.unmount("/a".into()).await.is_err());
+        assert_eq!(handle.bind("/b".into(), fs).await.unwrap(), base);
+    }
+
+    #[tokio::test]
+    async fn empty_port_ranges_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = VfsManagerConfig {
+            min_nfs_port: 2000,
+            max_nfs_port: 1999,
+            ports_path: dir.path().join("nfs_ports.toml"),
+        };
+        assert!(VfsManager::new(config).await.is_err());
+    }
 }
@@ -0,0 +1,141 @@
+use std::{io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use durable::write_durably;
+
+use crate::ty::Id;
+
+const STATE: &str = "state.toml";
+const STATE_TMP: &str = "state.toml.tmp";
+const SESSIONS: &str = "sessions.toml";
+const SESSIONS_TMP: &str = "sessions.toml.tmp";
+
+/// What the daemon records about a working copy, kept in `state.toml` in its directory.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct WorkingCopyState {
+    /// Hex encoded id of the operation the working copy was last updated at
+    pub op_id: String,
+    pub workspace_id: String,
+    /// The tree the working copy was last checked out or snapshotted at
+    pub tree_id: Id,
+    /// Directories shown in the working copy, the empty path stands for everything
+    #[serde(default = "everything")]
+    pub sparse_patterns: Vec<String>,
+}
+
+fn everything() -> Vec<String> {
+    vec![String::new()]
+}
+
+impl WorkingCopyState {
+    /// A working copy that has not been checked out by any operation yet.
+    pub fn new(tree_id: Id) -> Self {
+        WorkingCopyState {
+            op_id: String::new(),
+            workspace_id: String::new(),
+            tree_id,
+            sparse_patterns: everything(),
+        }
+    }
+
+    pub async fn load(dir: &Path) -> io::Result<Self> {
+        let contents = tokio::fs::read_to_string(dir.join(STATE)).await?;
+        toml::from_str(&contents).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    pub async fn save(&self, dir: &Path) -> io::Result<()> {
+        let contents =
+            toml::to_string(self).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        write_durably(&dir.join(STATE_TMP), &dir.join(STATE), contents.as_bytes()).await
+    }
+}
+
+/// The repos the daemon serves, kept in `sessions.toml` in its cache so they survive a restart.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SavedSessions {
+    pub sessions: Vec<SavedSession>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SavedSession {
+    pub remote: String,
+    /// Where the repo was initialized
+    pub path: String,
+    /// Paths of the working copies the repo is checked out in
+    pub workspaces: Vec<String>,
+}
+
+impl SavedSessions {
+    /// Nothing was saved before the daemon first served a repo.
+    pub async fn load(cache: &Path) -> io::Result<Self> {
+        match tokio::fs::read_to_string(cache.join(SESSIONS)).await {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(SavedSessions::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub async fn save(&self, cache: &Path) -> io::Result<()> {
+        let contents =
+            toml::to_string(self).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        write_durably(
+            &cache.join(SESSIONS_TMP),
+            &cache.join(SESSIONS),
+            contents.as_bytes(),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn state_is_saved_as_toml() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = WorkingCopyState {
+            op_id: "00ff".to_string(),
+            workspace_id: "default".to_string(),
+            tree_id: Id([7; 32]),
+            sparse_patterns: vec!["src".to_string()],
+        };
+        state.save(dir.path()).await.unwrap();
+
+        let contents = std::fs::read_to_string(dir.path().join(STATE)).unwrap();
+        assert!(contents.contains(&format!("tree_id = \"{}\"", Id([7; 32]).hex())));
+        assert_eq!(WorkingCopyState::load(dir.path()).await.unwrap(), state);
+        assert!(toml::from_str::<WorkingCopyState>(
+            "op_id = \"\"\nworkspace_id = \"\"\ntree_id = \"beef\""
+        )
+        .is_err());
+
+        // Working copies without patterns show everything.
+        let contents = format!(
+            "op_id = \"\"\nworkspace_id = \"\"\ntree_id = \"{}\"",
+            Id([7; 32]).hex()
+        );
+        let state = toml::from_str::<WorkingCopyState>(&contents).unwrap();
+        assert_eq!(state.sparse_patterns, [""]);
+    }
+
+    #[tokio::test]
+    async fn sessions_are_saved_as_toml() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            SavedSessions::load(dir.path()).await.unwrap(),
+            SavedSessions::default()
+        );
+        let sessions = SavedSessions {
+            sessions: vec![SavedSession {
+                remote: "localhost".to_string(),
+                path: "/repo".to_string(),
+                workspaces: vec!["/repo".to_string(), "/second".to_string()],
+            }],
+        };
+        sessions.save(dir.path()).await.unwrap();
+        assert_eq!(SavedSessions::load(dir.path()).await.unwrap(), sessions);
+    }
+}
@@ -0,0 +1,36 @@
+//! Crash-safe file writes shared by the daemon and the server.
+
+use std::{io, path::Path};
+
+use tokio::io::AsyncWriteExt;
+
+/// Writes `data` to `tmp_path`, syncs it and atomically moves it to `path`.
+pub async fn write_durably(tmp_path: &Path, path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = tokio::fs::File::create(tmp_path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    tokio::fs::rename(tmp_path, path).await?;
+    // Make the rename itself durable.
+    if let Some(parent) = path.parent() {
+        tokio::fs::File::open(parent).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replaces_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        let tmp_path = dir.path().join("file.tmp");
+        write_durably(&tmp_path, &path, b"old").await.unwrap();
+        write_durably(&tmp_path, &path, b"new").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert!(!tmp_path.exists());
+    }
+}
@@ -4,4 +4,12 @@ pub mod jj_interface {
     tonic::include_proto!("jj_interface");
 }
 
+pub mod remote {
+    tonic::include_proto!("remote");
+}
+
+/// Binary gRPC metadata on store requests with the path of the repo they are for, where it was
+/// initialized. The daemon keeps the objects of repos from different remotes apart.
+pub const REPO_PATH_METADATA: &str = "yak-repo-path-bin";
+
 pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("grpc_descriptor");
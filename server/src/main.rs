@@ -1,3 +1,79 @@
-fn main() {
-    println!("Hello, world!");
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use clap::Parser;
+use serde::Deserialize;
+use tonic::transport::Server as GrpcServer;
+use tracing::info;
+
+mod service;
+mod storage;
+
+/// JJ Remote Backend
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Configuration
+    #[arg(short, long)]
+    config: PathBuf,
+}
+
+#[derive(Deserialize, Debug)]
+struct Config {
+    /// Address daemons connect over
+    pub grpc_addr: String,
+    /// Where objects for every repository are stored
+    pub storage: PathBuf,
+}
+
+async fn run_with_config(config: Config) -> Result<(), anyhow::Error> {
+    info!("Starting server with configuration: {config:#?}");
+
+    let addr = config.grpc_addr.parse()?;
+
+    let storage = storage::Storage::new(&config.storage).await.map_err(|e| {
+        anyhow!(
+            "Could not open storage in {}: {}",
+            config.storage.display(),
+            e
+        )
+    })?;
+    let backend_svc = service::RemoteBackendService::new(storage);
+
+    let reflection_svc = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
+        .build()?;
+
+    info!("Serving remote backend gRPC interface");
+    GrpcServer::builder()
+        .add_service(reflection_svc)
+        .add_service(backend_svc)
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+
+    let contents = std::fs::read_to_string(&args.config)
+        .map_err(|e| anyhow!("Could not read {}: {}", args.config.display(), e))?;
+
+    let config: Config = toml::from_str(&contents)?;
+
+    tracing_log::LogTracer::init()?;
+
+    let subscriber = tracing_subscriber::fmt()
+        .compact()
+        .with_file(true)
+        .with_line_number(true)
+        .with_thread_ids(true)
+        .with_target(false)
+        .finish();
+
+    // use that subscriber to process traces emitted after this point
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    run_with_config(config).await
 }
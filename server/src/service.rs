@@ -0,0 +1,226 @@
+use proto::remote::*;
+use tonic::{Request, Response, Status};
+
+use crate::storage::{is_valid_repo_name, Storage};
+
+const ID_LENGTH: usize = 32;
+
+pub struct RemoteBackendService {
+    storage: Storage,
+}
+
+impl RemoteBackendService {
+    pub fn new(storage: Storage) -> remote_backend_server::RemoteBackendServer<Self> {
+        remote_backend_server::RemoteBackendServer::new(RemoteBackendService { storage })
+    }
+}
+
+fn storage_error(err: std::io::Error) -> Status {
+    Status::internal(format!("Storage error: {err}"))
+}
+
+fn validate_repo(repo: &str) -> Result<(), Status> {
+    if is_valid_repo_name(repo) {
+        Ok(())
+    } else {
+        Err(Status::invalid_argument(format!(
+            "Invalid repository name {repo:?}"
+        )))
+    }
+}
+
+fn validate_kind(kind: i32) -> Result<ObjectKind, Status> {
+    match ObjectKind::try_from(kind) {
+        Ok(ObjectKind::Unspecified) | Err(_) => Err(Status::invalid_argument(format!(
+            "Invalid object kind {kind}"
+        ))),
+        Ok(kind) => Ok(kind),
+    }
+}
+
+fn validate_id(id: &[u8]) -> Result<(), Status> {
+    if id.len() == ID_LENGTH {
+        Ok(())
+    } else {
+        Err(Status::invalid_argument(format!(
+            "Invalid object id length {} (expected {ID_LENGTH})",
+            id.len()
+        )))
+    }
+}
+
+#[tonic::async_trait]
+impl remote_backend_server::RemoteBackend for RemoteBackendService {
+    #[tracing::instrument(skip(self))]
+    async fn get_object(
+        &self,
+        request: Request<GetObjectReq>,
+    ) -> Result<Response<GetObjectReply>, Status> {
+        let req = request.into_inner();
+        validate_repo(&req.repo)?;
+        let kind = validate_kind(req.kind)?;
+        validate_id(&req.id)?;
+        match self
+            .storage
+            .get(&req.repo, kind, &req.id)
+            .await
+            .map_err(storage_error)?
+        {
+            Some(data) => Ok(Response::new(GetObjectReply { data })),
+            None => Err(Status::not_found(format!(
+                "{} {} not found in {}",
+                kind.as_str_name(),
+                req.id
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect::<String>(),
+                req.repo
+            ))),
+        }
+    }
+
+    #[tracing::instrument(skip(self, request))]
+    async fn put_object(
+        &self,
+        request: Request<PutObjectReq>,
+    ) -> Result<Response<PutObjectReply>, Status> {
+        let req = request.into_inner();
+        validate_repo(&req.repo)?;
+        let kind = validate_kind(req.kind)?;
+        validate_id(&req.id)?;
+        self.storage
+            .put(&req.repo, kind, &req.id, &req.data)
+            .await
+            .map_err(storage_error)?;
+        Ok(Response::new(PutObjectReply {}))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn has_object(
+        &self,
+        request: Request<HasObjectReq>,
+    ) -> Result<Response<HasObjectReply>, Status> {
+        let req = request.into_inner();
+        validate_repo(&req.repo)?;
+        let kind = validate_kind(req.kind)?;
+        validate_id(&req.id)?;
+        let present = self
+            .storage
+            .has(&req.repo, kind, &req.id)
+            .await
+            .map_err(storage_error)?;
+        Ok(Response::new(HasObjectReply { present }))
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn list_objects(
+        &self,
+        request: Request<ListObjectsReq>,
+    ) -> Result<Response<ListObjectsReply>, Status> {
+        let req = request.into_inner();
+        validate_repo(&req.repo)?;
+        let kind = validate_kind(req.kind)?;
+        let ids = self
+            .storage
+            .list(&req.repo, kind)
+            .await
+            .map_err(storage_error)?;
+        Ok(Response::new(ListObjectsReply { ids }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use proto::remote::remote_backend_server::RemoteBackend;
+    use tonic::Code;
+
+    use super::*;
+
+    fn put(repo: &str, id: Vec<u8>, data: &[u8]) -> PutObjectReq {
+        PutObjectReq {
+            repo: repo.to_string(),
+            kind: ObjectKind::Tree.into(),
+            id,
+            data: data.to_vec(),
+        }
+    }
+
+    fn get(repo: &str, id: Vec<u8>) -> GetObjectReq {
+        GetObjectReq {
+            repo: repo.to_string(),
+            kind: ObjectKind::Tree.into(),
+            id,
+        }
+    }
+
+    #[tokio::test]
+    async fn repos_are_partitioned() {
+        let dir = tempfile::tempdir().unwrap();
+        let svc = RemoteBackendService {
+            storage: Storage::new(dir.path()).await.unwrap(),
+        };
+        let id = vec![1; ID_LENGTH];
+
+        svc.put_object(Request::new(put("repo1", id.clone(), b"tree")))
+            .await
+            .unwrap();
+
+        let reply = svc
+            .get_object(Request::new(get("repo1", id.clone())))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(reply.data, b"tree");
+
+        assert_matches!(
+            svc.get_object(Request::new(get("repo2", id.clone()))).await,
+            Err(status) if status.code() == Code::NotFound
+        );
+
+        let listed = svc
+            .list_objects(Request::new(ListObjectsReq {
+                repo: "repo1".to_string(),
+                kind: ObjectKind::Tree.into(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(listed.ids, vec![id.clone()]);
+
+        let present = svc
+            .has_object(Request::new(HasObjectReq {
+                repo: "repo2".to_string(),
+                kind: ObjectKind::Tree.into(),
+                id,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!present.present);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let svc = RemoteBackendService {
+            storage: Storage::new(dir.path()).await.unwrap(),
+        };
+
+        assert_matches!(
+            svc.put_object(Request::new(put("../escape", vec![1; ID_LENGTH], b"")))
+                .await,
+            Err(status) if status.code() == Code::InvalidArgument
+        );
+        assert_matches!(
+            svc.put_object(Request::new(put("repo", vec![1; 3], b""))).await,
+            Err(status) if status.code() == Code::InvalidArgument
+        );
+        let mut req = get("repo", vec![1; ID_LENGTH]);
+        req.kind = ObjectKind::Unspecified.into();
+        assert_matches!(
+            svc.get_object(Request::new(req)).await,
+            Err(status) if status.code() == Code::InvalidArgument
+        );
+    }
+}
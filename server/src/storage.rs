@@ -0,0 +1,133 @@
+use std::{io, path::PathBuf};
+
+use durable::write_durably;
+use proto::remote::ObjectKind;
+
+/// Objects for every repository, stored on local disk:
+///
+/// ```text
+/// <root>/repos/<repo>/commits/<hex id>
+/// <root>/repos/<repo>/conflicts/<hex id>
+/// <root>/repos/<repo>/files/<hex id>
+/// <root>/repos/<repo>/symlinks/<hex id>
+/// <root>/repos/<repo>/trees/<hex id>
+/// <root>/tmp/
+/// ```
+///
+/// Objects are written to `tmp/` first and renamed into place once they are on disk, so a
+/// crash never leaves a partially written object behind.
+#[derive(Clone, Debug)]
+pub struct Storage {
+    root: PathBuf,
+}
+
+const REPOS: &str = "repos";
+const TMP: &str = "tmp";
+
+fn kind_dir(kind: ObjectKind) -> &'static str {
+    match kind {
+        ObjectKind::Commit => "commits",
+        ObjectKind::File => "files",
+        ObjectKind::Symlink => "symlinks",
+        ObjectKind::Tree => "trees",
+        ObjectKind::Conflict => "conflicts",
+        ObjectKind::Unspecified => unreachable!("object kind is validated by the service"),
+    }
+}
+
+fn hex(id: &[u8]) -> String {
+    id.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|chunk| u8::from_str_radix(std::str::from_utf8(chunk).ok()?, 16).ok())
+        .collect()
+}
+
+/// Repository names become directory names, so only allow a conservative character set.
+pub fn is_valid_repo_name(repo: &str) -> bool {
+    !repo.is_empty()
+        && !repo.starts_with('.')
+        && repo
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+impl Storage {
+    pub async fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        tokio::fs::create_dir_all(root.join(REPOS)).await?;
+        tokio::fs::create_dir_all(root.join(TMP)).await?;
+        Ok(Storage { root })
+    }
+
+    fn kind_path(&self, repo: &str, kind: ObjectKind) -> PathBuf {
+        self.root.join(REPOS).join(repo).join(kind_dir(kind))
+    }
+
+    fn object_path(&self, repo: &str, kind: ObjectKind, id: &[u8]) -> PathBuf {
+        self.kind_path(repo, kind).join(hex(id))
+    }
+
+    pub async fn get(
+        &self,
+        repo: &str,
+        kind: ObjectKind,
+        id: &[u8],
+    ) -> io::Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.object_path(repo, kind, id)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub async fn put(
+        &self,
+        repo: &str,
+        kind: ObjectKind,
+        id: &[u8],
+        data: &[u8],
+    ) -> io::Result<()> {
+        let path = self.object_path(repo, kind, id);
+        // Objects are content addressed, so an existing object is already what we would write.
+        if tokio::fs::try_exists(&path).await? {
+            return Ok(());
+        }
+        tokio::fs::create_dir_all(self.kind_path(repo, kind)).await?;
+
+        let tmp_path =
+            self.root
+                .join(TMP)
+                .join(format!("{}-{:016x}", hex(id), rand::random::<u64>()));
+        let result = write_durably(&tmp_path, &path, data).await;
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+        }
+        result
+    }
+
+    pub async fn has(&self, repo: &str, kind: ObjectKind, id: &[u8]) -> io::Result<bool> {
+        tokio::fs::try_exists(self.object_path(repo, kind, id)).await
+    }
+
+    pub async fn list(&self, repo: &str, kind: ObjectKind) -> io::Result<Vec<Vec<u8>>> {
+        let mut entries = match tokio::fs::read_dir(self.kind_path(repo, kind)).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let mut ids = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(id) = entry.file_name().to_str().and_then(from_hex) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+}